@@ -4,10 +4,7 @@ use std::env;
 
 fn main() {
     let release_profile = env::var("PROFILE")
-        .map(|x| match x.to_lowercase().as_ref() {
-            "release" => true,
-            _ => false
-        })
+        .map(|x| matches!(x.to_lowercase().as_ref(), "release"))
         .unwrap_or(false);
     if release_profile {
         let crate_dir = env::var("CARGO_MANIFEST_DIR").expect("missing CARGO_MANIFEST_DIR");
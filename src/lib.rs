@@ -1,10 +1,12 @@
 #![allow(unused)]
+#![allow(clippy::missing_safety_doc)]
 
 use std::collections::{HashMap, HashSet};
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::os::raw::c_char;
 use std::os::raw::c_int;
+use std::os::raw::c_void;
 use libc::size_t;
 use image::{
     DynamicImage,
@@ -32,7 +34,7 @@ use image::{
 /// 
 /// Operations on a failed image may either return NULL or propagate with the
 /// original error.
-pub struct WiImage(Result<DynamicImage, String>);
+pub struct WiImage(Result<DynamicImage, WiError>, ImageMetadata);
 
 
 /// A decoded grayscale image.
@@ -50,7 +52,7 @@ pub struct WiImage(Result<DynamicImage, String>);
 /// 
 /// Operations on a failed image may either return NULL or propagate with the
 /// original error.
-pub struct WiGrayImage(Result<image::GrayImage, String>);
+pub struct WiGrayImage(Result<image::GrayImage, WiError>);
 
 
 /// A decoded grayscale image.
@@ -71,1017 +73,7235 @@ pub struct WiGrayImage(Result<image::GrayImage, String>);
 /// 
 /// Operations on a failed image may either return NULL or propagate with the
 /// original error.
-pub struct WiGrayImageU32(Result<imageproc::definitions::Image<image::Luma<u32>>, String>);
+pub struct WiGrayImageU32(Result<imageproc::definitions::Image<image::Luma<u32>>, WiError>);
+
+
+/// A decoded grayscale image with floating point pixels.
+///
+/// Pixels are nominally in the range `0.0` to `1.0`, and are clamped to it when
+/// converted back to 8 or 16 bits. Used for processing that should not lose
+/// precision between steps, such as the float filters of imageproc.
+///
+/// This data type will either be valid, or invalid (with an error message).
+/// See the functions `wi_grayimg_f32_is_ok`, `wi_grayimg_f32_is_err` and
+/// `wi_grayimg_f32_get_err_msg` for further details.
+pub struct WiGrayImageF32(Result<imageproc::definitions::Image<image::Luma<f32>>, WiError>);
+
+
+/// An encoded byte buffer owned by the library, such as the output of `wi_img_encode`.
+///
+/// This data type will either be valid, or invalid (with an error message).
+/// See the functions `wi_bytes_is_ok`, `wi_bytes_is_err` and `wi_bytes_get_err_msg`
+/// for further details.
+///
+/// The contents are accessed via `wi_bytes_data` and `wi_bytes_len`, or copied
+/// into a caller-owned buffer with `wi_bytes_copy`. Must be released with
+/// `wi_bytes_free`.
+pub struct WiBytes(Result<Vec<u8>, WiError>);
+
+
+/// A decoded animation, such as an animated GIF or APNG.
+///
+/// Each frame is the full picture shown at that point of the animation (already
+/// composited with the previous frames), so it can be processed on its own.
+///
+/// This data type will either be valid, or invalid (with an error message).
+/// See the functions `wi_animation_is_ok`, `wi_animation_is_err` and
+/// `wi_animation_get_err_msg` for further details.
+pub struct WiAnimation(Result<Animation, WiError>);
+
+
+/// How a frame of an animation was disposed of in its source file, before
+/// drawing the next frame.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WiDisposal {
+    /// Not specified, or not read from a file.
+    Unspecified = 0,
+    /// The frame is left in place.
+    Keep = 1,
+    /// The area of the frame is cleared.
+    Background = 2,
+    /// The area of the frame is restored to what was there before.
+    Previous = 3,
+}
+
+
+/// Metadata carried alongside the pixels of a `WiImage`.
+///
+/// Filled in by the decoders from the container of the source file, and passed
+/// on by operations that derive one image from another.
+#[derive(Clone, Debug, Default)]
+struct ImageMetadata {
+    /// The raw EXIF block, as TIFF structured data (without the `Exif\0\0` header).
+    exif: Option<Vec<u8>>,
+    /// The XMP packet.
+    xmp: Option<String>,
+    /// The IPTC-IIM datasets.
+    iptc: Option<Vec<u8>>,
+    /// The ICC color profile.
+    icc: Option<Vec<u8>>,
+    /// The key/value view of the above (see `wi_img_metadata_count`), rebuilt
+    /// whenever they change so that the strings can be handed out.
+    entries: Vec<(CString, CString)>,
+    /// Whether 8-bit RGB(A) pixels are stored in BGR(A) order instead.
+    bgr: bool,
+}
+
+
+/// The color type of a `WiImage`, one for each pixel format it can hold.
+///
+/// The values are stable, and match the strings of `wi_img_color`
+/// (`"gray:8"`, `"graya:8"`, `"rgb:8"`, ...).
+///
+/// `Bgr8` and `Bgra8` are only produced by `wi_img_convert`. Their pixels are
+/// stored like `Rgb8` and `Rgba8`, with the blue channel first and red third.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WiColorType {
+    Unknown = 0,
+    L8 = 1,
+    La8 = 2,
+    Rgb8 = 3,
+    Rgba8 = 4,
+    L16 = 5,
+    La16 = 6,
+    Rgb16 = 7,
+    Rgba16 = 8,
+    Rgb32F = 9,
+    Rgba32F = 10,
+    Bgr8 = 11,
+    Bgra8 = 12,
+}
 
 
 
 ///////////////////////////////////////////////////////////////////////////////
-// IMAGE - BASICS - UTILS
+// ERRORS
 ///////////////////////////////////////////////////////////////////////////////
 
-#[no_mangle]
-pub unsafe extern fn wi_img_is_ok(ctx: *const WiImage) -> c_int {
-    if ctx.is_null() {
-        return 0;
-    }
-    match (&*ctx).0 {
-        Ok(_) => 1,
-        Err(_) => 0,
-    }
+/// The category of an error.
+///
+/// Every failed handle carries one (see e.g. `wi_img_get_err_code`), and
+/// functions that return scalars or NULL record theirs as the calling thread's
+/// last error (see `wi_last_error_code`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WiErrorCode {
+    /// No error.
+    Ok = 0,
+    /// A NULL pointer, an invalid UTF-8 string or an unknown option value was given.
+    InvalidArgument = 1,
+    /// The image format is unknown, or can't be decoded or encoded by this library.
+    UnsupportedFormat = 2,
+    /// The input data could not be decoded.
+    DecodeFailed = 3,
+    /// The image could not be encoded.
+    EncodeFailed = 4,
+    /// Reading or writing a file or stream failed.
+    Io = 5,
+    /// A coordinate or region lies outside of the image.
+    OutOfBounds = 6,
+    /// The dimensions of the given images don't match.
+    DimensionMismatch = 7,
+    /// A size or memory limit was exceeded.
+    LimitExceeded = 8,
+    /// Any other failure, including a panic caught at the library boundary.
+    Internal = 9,
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_img_is_err(ctx: *const WiImage) -> c_int {
-    if ctx.is_null() {
-        return 1;
-    }
-    match (&*ctx).0 {
-        Ok(_) => 0,
-        Err(_) => 1,
-    }
+/// An error code along with a human readable message.
+///
+/// The message is kept as a C string so that the pointers handed out by the
+/// `*_get_err_msg` functions stay valid for as long as the owning handle.
+#[derive(Clone, Debug)]
+struct WiError {
+    code: WiErrorCode,
+    message: CString,
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_img_get_err_msg(ctx: *const WiImage) -> *const c_char {
-    if ctx.is_null() {
-        return std::ptr::null();
-    }
-    match (&*ctx).0 {
-        Ok(_) => std::ptr::null(),
-        Err(ref x) => {
-            let msg = CString::new(x.clone()).expect("CString::new failed");
-            msg.as_ptr()
+impl WiError {
+    fn new<S: Into<String>>(code: WiErrorCode, message: S) -> Self {
+        let message = message.into().replace('\0', "");
+        WiError {
+            code,
+            message: CString::new(message).unwrap_or_default(),
         }
     }
+    /// Record this error as the calling thread's last error, and return `value`.
+    fn report<T>(&self, value: T) -> T {
+        LAST_ERROR.with(|last| {
+            *last.borrow_mut() = Some(self.clone());
+        });
+        value
+    }
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_is_ok(ctx: *const WiGrayImage) -> c_int {
-    if ctx.is_null() {
-        return 0;
-    }
-    match (&*ctx).0 {
-        Ok(_) => 1,
-        Err(_) => 0,
+impl From<image::ImageError> for WiError {
+    fn from(error: image::ImageError) -> Self {
+        let code = match error {
+            image::ImageError::Decoding(_) => WiErrorCode::DecodeFailed,
+            image::ImageError::Encoding(_) => WiErrorCode::EncodeFailed,
+            image::ImageError::Parameter(_) => WiErrorCode::InvalidArgument,
+            image::ImageError::Limits(_) => WiErrorCode::LimitExceeded,
+            image::ImageError::Unsupported(_) => WiErrorCode::UnsupportedFormat,
+            image::ImageError::IoError(_) => WiErrorCode::Io,
+        };
+        WiError::new(code, error.to_string())
     }
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_is_err(ctx: *const WiGrayImage) -> c_int {
-    if ctx.is_null() {
-        return 1;
-    }
-    match (&*ctx).0 {
-        Ok(_) => 0,
-        Err(_) => 1,
+impl From<tiff::TiffError> for WiError {
+    fn from(error: tiff::TiffError) -> Self {
+        use tiff::{TiffError, TiffFormatError};
+        let code = match error {
+            TiffError::FormatError(TiffFormatError::TiffSignatureNotFound) => WiErrorCode::UnsupportedFormat,
+            TiffError::UnsupportedError(_) => WiErrorCode::UnsupportedFormat,
+            TiffError::IoError(_) => WiErrorCode::Io,
+            TiffError::LimitsExceeded => WiErrorCode::LimitExceeded,
+            _ => WiErrorCode::DecodeFailed,
+        };
+        WiError::new(code, error.to_string())
     }
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_get_err_msg(ctx: *const WiGrayImage) -> *const c_char {
-    if ctx.is_null() {
-        return std::ptr::null();
+impl From<std::io::Error> for WiError {
+    fn from(error: std::io::Error) -> Self {
+        WiError::new(WiErrorCode::Io, error.to_string())
     }
-    match (&*ctx).0 {
-        Ok(_) => std::ptr::null(),
-        Err(ref x) => {
-            let msg = CString::new(x.clone()).expect("CString::new failed");
-            msg.as_ptr()
-        }
+}
+
+impl From<std::str::Utf8Error> for WiError {
+    fn from(error: std::str::Utf8Error) -> Self {
+        WiError::new(WiErrorCode::InvalidArgument, format!("invalid UTF-8 string: {}", error))
     }
 }
 
+/// Record a NULL argument error for the given parameter, and return `value`.
+fn null_argument<T>(name: &str, value: T) -> T {
+    WiError::new(WiErrorCode::InvalidArgument, format!("`{}` is NULL", name)).report(value)
+}
+
+/// Record an out of bounds error for the pixel at (`cx`, `cy`), and return -1.
+fn out_of_bounds(cx: u32, cy: u32) -> c_int {
+    WiError::new(WiErrorCode::OutOfBounds, format!("pixel ({}, {}) is out of bounds", cx, cy)).report(-1)
+}
+
+thread_local! {
+    static LAST_ERROR: std::cell::RefCell<Option<WiError>> = const { std::cell::RefCell::new(None) };
+}
+
+/// The code of the last error recorded on the calling thread, or `Ok` if there is none.
+///
+/// Errors are recorded by functions that return scalars or NULL, and are kept
+/// until the next error on the same thread or a call to `wi_clear_last_error`.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_is_ok(ctx: *const WiGrayImageU32) -> c_int {
-    if ctx.is_null() {
-        return 0;
-    }
-    match (&*ctx).0 {
-        Ok(_) => 1,
-        Err(_) => 0,
-    }
+pub unsafe extern "C" fn wi_last_error_code() -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        LAST_ERROR.with(|last| {
+            match *last.borrow() {
+                Some(ref x) => x.code,
+                None => WiErrorCode::Ok,
+            }
+        })
+    })
 }
 
+/// The message of the last error recorded on the calling thread, or NULL if there is none.
+///
+/// The string is owned by the library, and stays valid until the next error
+/// on the same thread or a call to `wi_clear_last_error`.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_is_err(ctx: *const WiGrayImageU32) -> c_int {
-    if ctx.is_null() {
-        return 1;
-    }
-    match (&*ctx).0 {
-        Ok(_) => 0,
-        Err(_) => 1,
-    }
+pub unsafe extern "C" fn wi_last_error_message() -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        LAST_ERROR.with(|last| {
+            match *last.borrow() {
+                Some(ref x) => x.message.as_ptr(),
+                None => std::ptr::null(),
+            }
+        })
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_get_err_msg(ctx: *const WiGrayImageU32) -> *const c_char {
-    if ctx.is_null() {
-        return std::ptr::null();
-    }
-    match (&*ctx).0 {
-        Ok(_) => std::ptr::null(),
-        Err(ref x) => {
-            let msg = CString::new(x.clone()).expect("CString::new failed");
-            msg.as_ptr()
-        }
-    }
+pub unsafe extern "C" fn wi_clear_last_error() {
+    catch_panic_or((), || {
+        LAST_ERROR.with(|last| {
+            *last.borrow_mut() = None;
+        });
+    })
 }
 
 
 
 ///////////////////////////////////////////////////////////////////////////////
-// BASICS - MEMORY
+// PANICS
 ///////////////////////////////////////////////////////////////////////////////
 
-#[no_mangle]
-pub unsafe extern fn wi_img_clone(ctx: *const WiImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.clone()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+/// Convert the payload of a caught panic into an `Internal` error, keeping its message.
+fn panic_error(payload: Box<dyn std::any::Any + Send>) -> WiError {
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|x| x.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| String::from("unknown panic"));
+    WiError::new(WiErrorCode::Internal, format!("panic: {}", message))
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_img_free(ctx: *mut WiImage) {
-    if !ctx.is_null() {
-        let value = Box::from_raw(ctx);
-        std::mem::drop(value);
-    }
+/// Handle types that a caught panic can be returned as.
+trait FromPanic {
+    fn from_panic(error: WiError) -> Self;
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_clone(ctx: *const WiGrayImage) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
+impl FromPanic for *mut WiImage {
+    fn from_panic(error: WiError) -> Self {
+        Box::into_raw(Box::new(WiImage(Err(error), ImageMetadata::default())))
     }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.clone()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_free(ctx: *mut WiGrayImage) {
-    if !ctx.is_null() {
-        let value = Box::from_raw(ctx);
-        std::mem::drop(value);
+impl FromPanic for *mut WiGrayImage {
+    fn from_panic(error: WiError) -> Self {
+        Box::into_raw(Box::new(WiGrayImage(Err(error))))
     }
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_clone(ctx: *const WiGrayImageU32) -> *mut WiGrayImageU32 {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
+impl FromPanic for *mut WiGrayImageU32 {
+    fn from_panic(error: WiError) -> Self {
+        Box::into_raw(Box::new(WiGrayImageU32(Err(error))))
     }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.clone()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImageU32(result));
-    Box::into_raw(result)
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_free(ctx: *mut WiGrayImageU32) {
-    if !ctx.is_null() {
-        let value = Box::from_raw(ctx);
-        std::mem::drop(value);
+impl FromPanic for *mut WiGrayImageF32 {
+    fn from_panic(error: WiError) -> Self {
+        Box::into_raw(Box::new(WiGrayImageF32(Err(error))))
     }
 }
 
-
-///////////////////////////////////////////////////////////////////////////////
-// BASICS - CONVERSION
-///////////////////////////////////////////////////////////////////////////////
-
-#[no_mangle]
-pub unsafe extern fn wi_img_to_luma(ctx: *const WiImage) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
+impl FromPanic for *mut WiBytes {
+    fn from_panic(error: WiError) -> Self {
+        Box::into_raw(Box::new(WiBytes(Err(error))))
     }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.to_luma()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
 }
 
-
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_to_img(ctx: *const WiGrayImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
+impl FromPanic for *mut WiAnimation {
+    fn from_panic(error: WiError) -> Self {
+        Box::into_raw(Box::new(WiAnimation(Err(error))))
     }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(::image::DynamicImage::ImageLuma8(x.clone())),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
 }
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_to_img_with_pretty_labels(ctx: *const WiGrayImageU32) -> *mut WiImage {
-    fn random_color_map(keys: HashSet<u32>) -> HashMap<u32, image::Rgb<u8>> {
-        use colourado::{Color, ColorPalette, PaletteType};
-        let palette = ColorPalette::new(keys.len() as u32, PaletteType::Random, false);
-        let mut output: HashMap<u32, image::Rgb<u8>> = HashMap::new();
-        for (key, ix) in keys.iter().zip(0 .. keys.len()) {
-            let key = key.clone();
-            if key == 0 {
-                output.insert(key, image::Rgb([0, 0, 0]));
-            } else {
-                fn convert(x: f32) -> u8 {
-                    (x * 255.0) as u8
-                }
-                let red = convert(palette.colors[ix].red);
-                let green = convert(palette.colors[ix].green);
-                let blue = convert(palette.colors[ix].blue);
+/// Run the body of an exported function that returns a handle, so that a
+/// panic becomes an error-state handle instead of unwinding into C.
+fn catch_panic<T: FromPanic, F: FnOnce() -> T>(body: F) -> T {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(body))
+        .unwrap_or_else(|x| T::from_panic(panic_error(x)))
+}
 
-                output.insert(key, image::Rgb([red, green, blue]));
-            }
-        }
-        output
+/// Run the body of any other exported function, so that a panic is recorded
+/// as the last error and `fallback` is returned instead of unwinding into C.
+fn catch_panic_or<T, F: FnOnce() -> T>(fallback: T, body: F) -> T {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)) {
+        Ok(x) => x,
+        Err(x) => panic_error(x).report(fallback),
     }
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let go = |width, height, components: &imageproc::definitions::Image<image::Luma<u32>>| {
-        let pixels = components
-            .pixels()
-            .map(|p| p[0]).map(|x| x)
-            .collect();
-        let debug_colors = random_color_map(pixels);
-        let new_image = image::ImageBuffer::from_fn(width, height, |x, y| {
-            let px_key = components.get_pixel(x, y).channels()[0];
-            let color = debug_colors.get(&px_key).expect("missing color entry");
-            color.clone()
-        });
-        image::DynamicImage::ImageRgb8(new_image)
-    };
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(go(x.width(), x.height(), x)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
 }
 
 
 
 ///////////////////////////////////////////////////////////////////////////////
-// IMAGE - METHODS
+// IMAGE - BASICS - UTILS
 ///////////////////////////////////////////////////////////////////////////////
 
 #[no_mangle]
-pub unsafe extern fn wi_img_open(path: *const c_char) -> *mut WiImage {
-    if path.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = CStr::from_ptr(path)
-        .to_str()
-        .map_err(|x| format!("{:?}", x))
-        .and_then(|path| {
-            ::image::open(path).map_err(|x| format!("{:?}", x))
-        });
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_img_is_ok(ctx: *const WiImage) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return 0;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 1,
+            Err(_) => 0,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_new_luma8_img(width: u32, height: u32) -> *mut WiImage {
-    let result = Ok(DynamicImage::new_luma8(width, height));
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_img_is_err(ctx: *const WiImage) -> c_int {
+    catch_panic_or(1, || {
+        if ctx.is_null() {
+            return 1;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    })
 }
 
+/// The error message of a failed handle, or NULL if it's valid.
+///
+/// The string is owned by the handle, and stays valid until it's released
+/// with `wi_img_free`.
 #[no_mangle]
-pub unsafe extern fn wi_new_rgb8_img(width: u32, height: u32) -> *mut WiImage {
-    let result = Ok(DynamicImage::new_rgb8(width, height));
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_img_get_err_msg(ctx: *const WiImage) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return std::ptr::null();
+        }
+        match (&*ctx).0 {
+            Ok(_) => std::ptr::null(),
+            Err(ref x) => x.message.as_ptr(),
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_new_rgba8_img(width: u32, height: u32) -> *mut WiImage {
-    let result = Ok(DynamicImage::new_rgba8(width, height));
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_img_get_err_code(ctx: *const WiImage) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return WiErrorCode::InvalidArgument;
+        }
+        match (&*ctx).0 {
+            Ok(_) => WiErrorCode::Ok,
+            Err(ref x) => x.code,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_crop(ctx: *const WiImage, cx: u32, cy: u32, width: u32, height: u32) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.clone().crop(cx, cy, width, height)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_is_ok(ctx: *const WiGrayImage) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return 0;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 1,
+            Err(_) => 0,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_color(ctx: *const WiImage) -> *const c_char {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            let value: String = match x.color() {
-                ::image::ColorType::Gray(bit_depth) => {
-                    format!("gray:{}", bit_depth)
-                }
-                ::image::ColorType::RGB(bit_depth) => {
-                    format!("rgb:{}", bit_depth)
-                }
-                ::image::ColorType::Palette(bit_depth) => {
-                    format!("palette:{}", bit_depth)
-                }
-                ::image::ColorType::GrayA(bit_depth) => {
-                    format!("graya:{}", bit_depth)
-                }
-                ::image::ColorType::RGBA(bit_depth) => {
-                    format!("rgba:{}", bit_depth)
-                }
-                ::image::ColorType::BGR(bit_depth) => {
-                    format!("bgr:{}", bit_depth)
-                }
-                ::image::ColorType::BGRA(bit_depth) => {
-                    format!("bgra:{}", bit_depth)
-                }
-            };
-            let value = CString::new(value).expect("CString::new failed");
-            value.as_ptr()
+pub unsafe extern "C" fn wi_grayimg_is_err(ctx: *const WiGrayImage) -> c_int {
+    catch_panic_or(1, || {
+        if ctx.is_null() {
+            return 1;
         }
-        Err(x) => std::ptr::null()
-    }
+        match (&*ctx).0 {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    })
 }
 
-
+/// The error message of a failed handle, or NULL if it's valid.
+///
+/// The string is owned by the handle, and stays valid until it's released
+/// with `wi_grayimg_free`.
 #[no_mangle]
-pub unsafe extern fn wi_img_grayscale(ctx: *const WiImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.grayscale()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_get_err_msg(ctx: *const WiGrayImage) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return std::ptr::null();
+        }
+        match (&*ctx).0 {
+            Ok(_) => std::ptr::null(),
+            Err(ref x) => x.message.as_ptr(),
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_invert(ctx: *const WiImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok({
-            let mut x = x.clone();
-            x.invert();
-            x
-        }),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_get_err_code(ctx: *const WiGrayImage) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return WiErrorCode::InvalidArgument;
+        }
+        match (&*ctx).0 {
+            Ok(_) => WiErrorCode::Ok,
+            Err(ref x) => x.code,
+        }
+    })
 }
 
-
 #[no_mangle]
-pub unsafe extern fn wi_img_resize(ctx: *const WiImage, width: u32, height: u32, format: *const c_char) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if format.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = CStr::from_ptr(format)
-        .to_str()
-        .ok()
-        .and_then(|format| {
-            match format {
-                "Nearest" => Some(image::FilterType::Nearest),
-                "Triangle" => Some(image::FilterType::Triangle),
-                "CatmullRom" => Some(image::FilterType::CatmullRom),
-                "Gaussian" => Some(image::FilterType::Gaussian),
-                "Lanczos3" => Some(image::FilterType::Lanczos3),
-                _ => None
-            }
-        })
-        .ok_or(String::from("invalid filter type"))
-        .and_then(|format| {
-            match &(&*ctx).0 {
-                Ok(ref x) => Ok(x.resize(width, height, format)),
-                Err(x) => Err(x.clone()),
-            }
-        });
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_u32_is_ok(ctx: *const WiGrayImageU32) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return 0;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 1,
+            Err(_) => 0,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_resize_exact(ctx: *const WiImage, width: u32, height: u32, format: *const c_char) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if format.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = CStr::from_ptr(format)
-        .to_str()
-        .ok()
-        .and_then(|format| {
-            match format.to_lowercase().as_str() {
-                "nearest" => Some(image::FilterType::Nearest),
-                "triangle" => Some(image::FilterType::Triangle),
-                "catmullrom" => Some(image::FilterType::CatmullRom),
-                "gaussian" => Some(image::FilterType::Gaussian),
-                "lanczos3" => Some(image::FilterType::Lanczos3),
-                _ => None
-            }
-        })
-        .ok_or(String::from("invalid filter type"))
-        .and_then(|format| {
-            match &(&*ctx).0 {
-                Ok(ref x) => Ok(x.resize_exact(width, height, format)),
-                Err(x) => Err(x.clone()),
-            }
-        });
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_u32_is_err(ctx: *const WiGrayImageU32) -> c_int {
+    catch_panic_or(1, || {
+        if ctx.is_null() {
+            return 1;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    })
 }
 
-
-
+/// The error message of a failed handle, or NULL if it's valid.
+///
+/// The string is owned by the handle, and stays valid until it's released
+/// with `wi_grayimg_u32_free`.
 #[no_mangle]
-pub unsafe extern fn wi_img_thumbnail(ctx: *const WiImage, width: u32, height: u32) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.thumbnail(width, height)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_u32_get_err_msg(ctx: *const WiGrayImageU32) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return std::ptr::null();
+        }
+        match (&*ctx).0 {
+            Ok(_) => std::ptr::null(),
+            Err(ref x) => x.message.as_ptr(),
+        }
+    })
 }
 
-
 #[no_mangle]
-pub unsafe extern fn wi_img_thumbnail_exact(ctx: *const WiImage, width: u32, height: u32) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.thumbnail_exact(width, height)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_u32_get_err_code(ctx: *const WiGrayImageU32) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return WiErrorCode::InvalidArgument;
+        }
+        match (&*ctx).0 {
+            Ok(_) => WiErrorCode::Ok,
+            Err(ref x) => x.code,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_blur(ctx: *const WiImage, sigma: f32) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.blur(sigma)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_f32_is_ok(ctx: *const WiGrayImageF32) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return 0;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 1,
+            Err(_) => 0,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_unsharpen(ctx: *const WiImage, sigma: f32, threshold: i32) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.unsharpen(sigma, threshold)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_f32_is_err(ctx: *const WiGrayImageF32) -> c_int {
+    catch_panic_or(1, || {
+        if ctx.is_null() {
+            return 1;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    })
 }
 
+/// The error message of a failed handle, or NULL if it's valid.
+///
+/// The string is owned by the handle, and stays valid until it's released
+/// with `wi_grayimg_f32_free`.
 #[no_mangle]
-pub unsafe extern fn wi_img_filter3x3(ctx: *const WiImage, value: *const f32) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if value.is_null() {
-        return std::ptr::null_mut();
-    }
-    let value = [
-        *value.offset(0),
-        *value.offset(1),
-        *value.offset(2),
-        *value.offset(3),
-        *value.offset(4),
-        *value.offset(5),
-        *value.offset(6),
-        *value.offset(7),
-        *value.offset(8),
-    ];
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.filter3x3(&value)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_f32_get_err_msg(ctx: *const WiGrayImageF32) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return std::ptr::null();
+        }
+        match (&*ctx).0 {
+            Ok(_) => std::ptr::null(),
+            Err(ref x) => x.message.as_ptr(),
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_adjust_contrast(ctx: *const WiImage, value: f32) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.adjust_contrast(value)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_grayimg_f32_get_err_code(ctx: *const WiGrayImageF32) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return WiErrorCode::InvalidArgument;
+        }
+        match (&*ctx).0 {
+            Ok(_) => WiErrorCode::Ok,
+            Err(ref x) => x.code,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_brighten(ctx: *const WiImage, value: c_int) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.brighten(value)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_bytes_is_ok(ctx: *const WiBytes) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return 0;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 1,
+            Err(_) => 0,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_huerotate(ctx: *const WiImage, value: c_int) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.huerotate(value)),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_bytes_is_err(ctx: *const WiBytes) -> c_int {
+    catch_panic_or(1, || {
+        if ctx.is_null() {
+            return 1;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    })
 }
 
+/// The error message of a failed handle, or NULL if it's valid.
+///
+/// The string is owned by the handle, and stays valid until it's released
+/// with `wi_bytes_free`.
 #[no_mangle]
-pub unsafe extern fn wi_img_flipv(ctx: *const WiImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.flipv()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_bytes_get_err_msg(ctx: *const WiBytes) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return std::ptr::null();
+        }
+        match (&*ctx).0 {
+            Ok(_) => std::ptr::null(),
+            Err(ref x) => x.message.as_ptr(),
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_fliph(ctx: *const WiImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.fliph()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_bytes_get_err_code(ctx: *const WiBytes) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return WiErrorCode::InvalidArgument;
+        }
+        match (&*ctx).0 {
+            Ok(_) => WiErrorCode::Ok,
+            Err(ref x) => x.code,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_rotate90(ctx: *const WiImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.rotate90()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_animation_is_ok(ctx: *const WiAnimation) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return 0;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 1,
+            Err(_) => 0,
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_rotate180(ctx: *const WiImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.rotate180()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_animation_is_err(ctx: *const WiAnimation) -> c_int {
+    catch_panic_or(1, || {
+        if ctx.is_null() {
+            return 1;
+        }
+        match (&*ctx).0 {
+            Ok(_) => 0,
+            Err(_) => 1,
+        }
+    })
 }
 
+/// The error message of a failed handle, or NULL if it's valid.
+///
+/// The string is owned by the handle, and stays valid until it's released
+/// with `wi_animation_free`.
 #[no_mangle]
-pub unsafe extern fn wi_img_rotate270(ctx: *const WiImage) -> *mut WiImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(ref x) => Ok(x.rotate270()),
-        Err(x) => Err(x.clone()),
-    };
-    let result = Box::new(WiImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_animation_get_err_msg(ctx: *const WiAnimation) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return std::ptr::null();
+        }
+        match (&*ctx).0 {
+            Ok(_) => std::ptr::null(),
+            Err(ref x) => x.message.as_ptr(),
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_save(ctx: *const WiImage, path: *const c_char) -> c_int {
-    if ctx.is_null() {
-        return 0;
-    }
-    if path.is_null() {
-        return 0;
-    }
-    let result = CStr::from_ptr(path)
-        .to_str()
-        .ok()
-        .and_then(|path| {
-            match &(&*ctx).0 {
-                Ok(ref x) => x.save(path).ok(),
-                Err(x) => None,
-            }
-        });
-    match result {
-        Some(_) => 1,
-        None => 0,
-    }
+pub unsafe extern "C" fn wi_animation_get_err_code(ctx: *const WiAnimation) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return WiErrorCode::InvalidArgument;
+        }
+        match (&*ctx).0 {
+            Ok(_) => WiErrorCode::Ok,
+            Err(ref x) => x.code,
+        }
+    })
 }
 
 
-#[no_mangle]
-pub unsafe extern fn wi_img_save_with_format(ctx: *const WiImage, path: *const c_char, format: *const c_char) -> c_int {
-    if ctx.is_null() {
-        return 0;
-    }
-    if path.is_null() {
-        return 0;
-    }
-    if format.is_null() {
-        return 0;
-    }
-    let result = CStr::from_ptr(path)
-        .to_str()
-        .ok()
-        .and_then(|path| CStr::from_ptr(format).to_str().ok().map(|x| (path, x)))
-        .and_then(|(path, format)| {
-            match format {
-                "jpeg" => Some((path, image::ImageFormat::JPEG)),
-                "png" => Some((path, image::ImageFormat::PNG)),
-                _ => None
-            }
-        })
-        .and_then(|(path, format)| {
-            match &(&*ctx).0 {
-                Ok(ref x) => x.save_with_format(path, format).ok(),
-                Err(x) => None,
-            }
-        });
-    match result {
-        Some(_) => 1,
-        None => 0,
-    }
-}
-
 
 ///////////////////////////////////////////////////////////////////////////////
-// IMAGE-VIEW - HELPER TYPES
+// BASICS - MEMORY
 ///////////////////////////////////////////////////////////////////////////////
 
-#[repr(C)]
-pub struct RgbaPixel {
-    pub r: u8,
-    pub g: u8,
-    pub b: u8,
-    pub a: u8,
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_clone(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.clone()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
 }
 
-#[repr(C)]
-pub struct LumaPixel {
-    pub l: u8
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_free(ctx: *mut WiImage) {
+    catch_panic_or((), || {
+        if !ctx.is_null() {
+            let value = Box::from_raw(ctx);
+            std::mem::drop(value);
+        }
+    })
 }
 
-
-///////////////////////////////////////////////////////////////////////////////
-// IMAGE-VIEW - METHODS
-///////////////////////////////////////////////////////////////////////////////
-
 #[no_mangle]
-pub unsafe extern fn wi_img_width(ctx: *const WiImage) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            x.width() as c_int
+pub unsafe extern "C" fn wi_grayimg_clone(ctx: *const WiGrayImage) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
         }
-        Err(x) => -1
-    }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.clone()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_height(ctx: *const WiImage) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            x.height() as c_int
+pub unsafe extern "C" fn wi_grayimg_free(ctx: *mut WiGrayImage) {
+    catch_panic_or((), || {
+        if !ctx.is_null() {
+            let value = Box::from_raw(ctx);
+            std::mem::drop(value);
         }
-        Err(x) => -1
-    }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_width(ctx: *const WiGrayImage) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            x.width() as c_int
+pub unsafe extern "C" fn wi_grayimg_u32_clone(ctx: *const WiGrayImageU32) -> *mut WiGrayImageU32 {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
         }
-        Err(x) => -1
-    }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.clone()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImageU32(result));
+        Box::into_raw(result)
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_height(ctx: *const WiGrayImage) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            x.height() as c_int
+pub unsafe extern "C" fn wi_grayimg_u32_free(ctx: *mut WiGrayImageU32) {
+    catch_panic_or((), || {
+        if !ctx.is_null() {
+            let value = Box::from_raw(ctx);
+            std::mem::drop(value);
         }
-        Err(x) => -1
-    }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_width(ctx: *const WiGrayImageU32) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            x.width() as c_int
+pub unsafe extern "C" fn wi_grayimg_f32_clone(ctx: *const WiGrayImageF32) -> *mut WiGrayImageF32 {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
         }
-        Err(x) => -1
-    }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.clone()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImageF32(result));
+        Box::into_raw(result)
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_height(ctx: *const WiGrayImageU32) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            x.height() as c_int
+pub unsafe extern "C" fn wi_grayimg_f32_free(ctx: *mut WiGrayImageF32) {
+    catch_panic_or((), || {
+        if !ctx.is_null() {
+            let value = Box::from_raw(ctx);
+            std::mem::drop(value);
         }
-        Err(x) => -1
-    }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_get_rgba_pixel(ctx: *const WiImage, px: *mut RgbaPixel, cx: u32, cy: u32) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            if x.in_bounds(cx, cy) {
-                let [r, g, b, a] = x.get_pixel(cx, cy).0;
-                *px = RgbaPixel {r, g, b, a};
-                1
-            } else {
-                -1
-            }
+pub unsafe extern "C" fn wi_bytes_free(ctx: *mut WiBytes) {
+    catch_panic_or((), || {
+        if !ctx.is_null() {
+            let value = Box::from_raw(ctx);
+            std::mem::drop(value);
         }
-        Err(x) => -1
-    }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_img_set_rgba_pixel(ctx: *mut WiImage, cx: u32, cy: u32, px: RgbaPixel) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &mut (&mut *ctx).0 {
-        Ok(ref mut x) => {
-            if x.in_bounds(cx, cy) {
-                let value = image::Rgba([px.r, px.g, px.b, px.a]);
-                x.put_pixel(cx, cy, value);
-                1
-            } else {
-                -1
-            }
+pub unsafe extern "C" fn wi_animation_clone(ctx: *const WiAnimation) -> *mut WiAnimation {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
         }
-        Err(x) => -1
-    }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.clone()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiAnimation(result));
+        Box::into_raw(result)
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_get_pixel(ctx: *const WiGrayImage, px: *mut u8, cx: u32, cy: u32) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            if x.in_bounds(cx, cy) {
-                let [l] = x.get_pixel(cx, cy).0;
-                *px = l;
-                1
-            } else {
-                -1
-            }
+pub unsafe extern "C" fn wi_animation_free(ctx: *mut WiAnimation) {
+    catch_panic_or((), || {
+        if !ctx.is_null() {
+            let value = Box::from_raw(ctx);
+            std::mem::drop(value);
         }
-        Err(x) => -1
-    }
+    })
 }
 
+
+///////////////////////////////////////////////////////////////////////////////
+// BASICS - CONVERSION
+///////////////////////////////////////////////////////////////////////////////
+
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_set_pixel(ctx: *mut WiGrayImage, cx: u32, cy: u32, px: u8) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &mut (&mut *ctx).0 {
-        Ok(ref mut x) => {
-            if x.in_bounds(cx, cy) {
-                let value = image::Luma([px]);
-                x.put_pixel(cx, cy, value);
-                1
-            } else {
-                -1
-            }
+pub unsafe extern "C" fn wi_img_to_luma(ctx: *const WiImage) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
         }
-        Err(x) => -1
-    }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(rgb_pixels(x, &(&*ctx).1).to_luma8()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
 }
 
+
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_get_pixel(ctx: *const WiGrayImageU32, px: *mut u32, cx: u32, cy: u32) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(ref x) => {
-            if x.in_bounds(cx, cy) {
-                let [l] = x.get_pixel(cx, cy).0;
-                *px = l;
-                1
-            } else {
-                -1
-            }
+pub unsafe extern "C" fn wi_grayimg_to_img(ctx: *const WiGrayImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
         }
-        Err(x) => -1
-    }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(::image::DynamicImage::ImageLuma8(x.clone())),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
 }
 
+/// Convert the image to 16-bit grayscale, keeping the precision of 16-bit and float inputs.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_u32_set_pixel(ctx: *mut WiGrayImage, cx: u32, cy: u32, px: u8) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &mut (&mut *ctx).0 {
-        Ok(ref mut x) => {
-            if x.in_bounds(cx, cy) {
-                let value = image::Luma([px]);
-                x.put_pixel(cx, cy, value);
-                1
-            } else {
-                -1
-            }
+pub unsafe extern "C" fn wi_img_to_luma16(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
         }
-        Err(x) => -1
-    }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(DynamicImage::ImageLuma16(x.to_luma16())),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
 }
 
-///////////////////////////////////////////////////////////////////////////////
-// IMAGEPROC - CONTRAST
-///////////////////////////////////////////////////////////////////////////////
-
+/// Convert the image to 16-bit RGB.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_contrast_adaptive_threshold(
-    ctx: *const WiGrayImage,
-    block_radius: u32,
-) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::contrast::adaptive_threshold(x, block_radius)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_img_to_rgb16(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(DynamicImage::ImageRgb16(x.to_rgb16())),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
 }
 
+/// Convert the image to 16-bit RGBA.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_contrast_equalize_histogram(
-    ctx: *const WiGrayImage,
-) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::contrast::equalize_histogram(x)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+pub unsafe extern "C" fn wi_img_to_rgba16(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(DynamicImage::ImageRgba16(x.to_rgba16())),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
 }
 
+/// Convert the image to a float grayscale image, in the range `0.0` to `1.0`.
+///
+/// In contrast to `wi_img_to_luma`, 16-bit and float inputs keep their precision.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_contrast_match_histogram(
-    ctx: *const WiGrayImage,
-    target: *const WiGrayImage,
-) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if target.is_null() {
-        return std::ptr::null_mut();
-    }
-    match (&(&*ctx).0, &(&*target).0) {
-        (Ok(x), Ok(y)) => {
-            let result = imageproc::contrast::match_histogram(x, y);
-            let result = Box::new(WiGrayImage(Ok(result)));
-            Box::into_raw(result)
-        },
-        _ => std::ptr::null_mut(),
-    }
+pub unsafe extern "C" fn wi_img_to_luma_f32(ctx: *const WiImage) -> *mut WiGrayImageF32 {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.to_luma32f()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImageF32(result));
+        Box::into_raw(result)
+    })
 }
 
+/// Convert an 8-bit grayscale image to floats, mapping 0–255 to `0.0`–`1.0`.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_contrast_otsu_level(
+pub unsafe extern "C" fn wi_grayimg_to_grayimg_f32(ctx: *const WiGrayImage) -> *mut WiGrayImageF32 {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::map::map_subpixels(x, |l| l as f32 / 255.0)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImageF32(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Convert a float grayscale image back to 8 bits, clamping to `0.0`–`1.0`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_f32_to_grayimg(ctx: *const WiGrayImageF32) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::map::map_subpixels(x, |l| (l.clamp(0.0, 1.0) * 255.0).round() as u8)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Convert a float grayscale image to a 16-bit grayscale `WiImage`, clamping to `0.0`–`1.0`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_f32_to_img(ctx: *const WiGrayImageF32) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => {
+                let output = imageproc::map::map_subpixels(x, |l| (l.clamp(0.0, 1.0) * 65535.0).round() as u16);
+                Ok(DynamicImage::ImageLuma16(output))
+            }
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_u32_to_img_with_pretty_labels(ctx: *const WiGrayImageU32) -> *mut WiImage {
+    catch_panic(|| {
+        fn random_color_map(keys: HashSet<u32>) -> HashMap<u32, image::Rgb<u8>> {
+            use colourado::{Color, ColorPalette, PaletteType};
+            let palette = ColorPalette::new(keys.len() as u32, PaletteType::Random, false);
+            let mut output: HashMap<u32, image::Rgb<u8>> = HashMap::new();
+            for (ix, key) in keys.iter().enumerate() {
+                let key = *key;
+                if key == 0 {
+                    output.insert(key, image::Rgb([0, 0, 0]));
+                } else {
+                    fn convert(x: f32) -> u8 {
+                        (x * 255.0) as u8
+                    }
+                    let red = convert(palette.colors[ix].red);
+                    let green = convert(palette.colors[ix].green);
+                    let blue = convert(palette.colors[ix].blue);
+
+                    output.insert(key, image::Rgb([red, green, blue]));
+                }
+            }
+            output
+        }
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let go = |width, height, components: &imageproc::definitions::Image<image::Luma<u32>>| {
+            let pixels = components
+                .pixels()
+                .map(|p| p[0])
+                .collect();
+            let debug_colors = random_color_map(pixels);
+            let new_image = image::ImageBuffer::from_fn(width, height, |x, y| {
+                let px_key = components.get_pixel(x, y).channels()[0];
+                let color = debug_colors.get(&px_key).expect("missing color entry");
+                *color
+            });
+            image::DynamicImage::ImageRgb8(new_image)
+        };
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(go(x.width(), x.height(), x)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE - IO - HELPER TYPES
+///////////////////////////////////////////////////////////////////////////////
+
+/// User supplied I/O callbacks, used for streaming decoding and encoding.
+///
+/// Every callback receives `user_data` as its first argument.
+///
+/// - `read` copies up to `len` bytes into `buf`, returning the number of bytes
+///   read, 0 at the end of the stream, or a negative value on failure.
+/// - `seek` moves to `offset` relative to `whence` (0 = start, 1 = current,
+///   2 = end), returning the new absolute position or a negative value on failure.
+/// - `write` consumes up to `len` bytes from `buf`, returning the number of bytes
+///   written or a negative value on failure.
+///
+/// Only `read` is required for decoding (`seek` is optional; without it the
+/// stream is buffered in memory first), and only `write` is required for encoding.
+#[repr(C)]
+pub struct WiIoCallbacks {
+    pub user_data: *mut c_void,
+    pub read: Option<unsafe extern "C" fn(user_data: *mut c_void, buf: *mut u8, len: size_t) -> isize>,
+    pub seek: Option<unsafe extern "C" fn(user_data: *mut c_void, offset: i64, whence: c_int) -> i64>,
+    pub write: Option<unsafe extern "C" fn(user_data: *mut c_void, buf: *const u8, len: size_t) -> isize>,
+}
+
+/// Adapts `WiIoCallbacks` to the `std::io` traits expected by the image crate.
+struct CallbackIo<'a>(&'a WiIoCallbacks);
+
+impl<'a> std::io::Read for CallbackIo<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.0.read.ok_or_else(|| {
+            std::io::Error::other("missing read callback")
+        })?;
+        let count = unsafe { read(self.0.user_data, buf.as_mut_ptr(), buf.len()) };
+        if count < 0 || count as usize > buf.len() {
+            return Err(std::io::Error::other("read callback failed"));
+        }
+        Ok(count as usize)
+    }
+}
+
+impl<'a> std::io::Seek for CallbackIo<'a> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let seek = self.0.seek.ok_or_else(|| {
+            std::io::Error::other("missing seek callback")
+        })?;
+        let (offset, whence) = match pos {
+            std::io::SeekFrom::Start(x) => (x as i64, 0),
+            std::io::SeekFrom::Current(x) => (x, 1),
+            std::io::SeekFrom::End(x) => (x, 2),
+        };
+        let position = unsafe { seek(self.0.user_data, offset, whence) };
+        if position < 0 {
+            return Err(std::io::Error::other("seek callback failed"));
+        }
+        Ok(position as u64)
+    }
+}
+
+impl<'a> std::io::Write for CallbackIo<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let write = self.0.write.ok_or_else(|| {
+            std::io::Error::other("missing write callback")
+        })?;
+        let count = unsafe { write(self.0.user_data, buf.as_ptr(), buf.len()) };
+        if count < 0 || count as usize > buf.len() {
+            return Err(std::io::Error::other("write callback failed"));
+        }
+        Ok(count as usize)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE - METHODS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Decode an image, after checking its dimensions from the header against `limits`.
+fn decode_image<R: std::io::BufRead + std::io::Seek>(
+    mut reader: R,
+    format: Option<image::ImageFormat>,
+    limits: &WiDecodeLimits,
+) -> Result<DynamicImage, WiError> {
+    use std::io::Seek;
+    let format = match format {
+        Some(format) => Some(format),
+        None => image::io::Reader::new(&mut reader)
+            .with_guessed_format()
+            .map_err(WiError::from)?
+            .format(),
+    };
+    if let Some(format) = format {
+        let start = reader.stream_position().map_err(WiError::from)?;
+        let (width, height) = image::io::Reader::with_format(&mut reader, format)
+            .into_dimensions()
+            .map_err(WiError::from)?;
+        limits.check(width, height)?;
+        reader.seek(std::io::SeekFrom::Start(start)).map_err(WiError::from)?;
+    }
+    let mut reader = image::io::Reader::new(reader);
+    if let Some(format) = format {
+        reader.set_format(format);
+    }
+    reader.limits(limits.image_limits());
+    reader.decode().map_err(WiError::from)
+}
+
+/// Map an optional user supplied format name to an `image::ImageFormat`.
+unsafe fn image_format_arg(format: *const c_char) -> Result<Option<image::ImageFormat>, WiError> {
+    if format.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(format)
+        .to_str()
+        .map_err(WiError::from)
+        .and_then(|format| {
+            parse_image_format(format).ok_or_else(|| {
+                WiError::new(WiErrorCode::UnsupportedFormat, format!("unknown image format: {}", format))
+            })
+        })
+        .map(Some)
+}
+
+/// Decode an image file, whose format is given by the extension of `path`.
+///
+/// Uses the default decode limits (see `wi_set_default_decode_limits`).
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_open(path: *const c_char) -> *mut WiImage {
+    catch_panic(|| {
+        wi_img_open_with_limits(path, std::ptr::null())
+    })
+}
+
+/// Same as `wi_img_open`, but checks the image against the given limits,
+/// or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_open_with_limits(path: *const c_char, limits: *const WiDecodeLimits) -> *mut WiImage {
+    catch_panic(|| {
+        let limits = decode_limits(limits);
+        if path.is_null() {
+            return null_argument("path", std::ptr::null_mut());
+        }
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| std::fs::read(path).map_err(WiError::from).map(|x| (path, x)))
+            .and_then(|(path, encoded)| {
+                let format = ::image::ImageFormat::from_path(path).map_err(WiError::from)?;
+                let image = decode_image(std::io::Cursor::new(&encoded), Some(format), &limits)?;
+                Ok((image, ImageMetadata::read(&encoded, &limits)))
+            });
+        let result = match result {
+            Ok((image, metadata)) => Box::new(WiImage(Ok(image), metadata)),
+            Err(x) => Box::new(WiImage(Err(x), ImageMetadata::default())),
+        };
+        Box::into_raw(result)
+    })
+}
+
+/// Decode an image from an in-memory byte buffer.
+///
+/// The image format is guessed from the leading bytes of the buffer.
+/// The buffer is only read during the call, and may be freed afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_decode_from_memory(data: *const u8, len: size_t) -> *mut WiImage {
+    catch_panic(|| {
+        wi_img_decode_from_memory_with_format(data, len, std::ptr::null())
+    })
+}
+
+/// Decode an image from an in-memory byte buffer, using the given format hint
+/// (e.g. "png", "jpeg" or "jpg", case-insensitive).
+///
+/// If `format` is NULL, the format is guessed from the leading bytes of the buffer.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_decode_from_memory_with_format(
+    data: *const u8,
+    len: size_t,
+    format: *const c_char,
+) -> *mut WiImage {
+    catch_panic(|| {
+        wi_img_decode_from_memory_with_limits(data, len, format, std::ptr::null())
+    })
+}
+
+/// Same as `wi_img_decode_from_memory_with_format`, but checks the image
+/// against the given limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_decode_from_memory_with_limits(
+    data: *const u8,
+    len: size_t,
+    format: *const c_char,
+    limits: *const WiDecodeLimits,
+) -> *mut WiImage {
+    catch_panic(|| {
+        let limits = decode_limits(limits);
+        if data.is_null() {
+            return null_argument("data", std::ptr::null_mut());
+        }
+        let buffer = std::slice::from_raw_parts(data, len);
+        let result = image_format_arg(format)
+            .and_then(|format| decode_image(std::io::Cursor::new(buffer), format, &limits));
+        let metadata = match result {
+            Ok(_) => ImageMetadata::read(buffer, &limits),
+            Err(_) => ImageMetadata::default(),
+        };
+        let result = Box::new(WiImage(result, metadata));
+        Box::into_raw(result)
+    })
+}
+
+/// Decode an image from a stream of user supplied callbacks.
+///
+/// If `format` is NULL, the format is guessed from the leading bytes of the stream.
+/// Streams without a `seek` callback are read to the end before decoding.
+///
+/// For streams with a `seek` callback, metadata is only read from the headers
+/// of JPEG and PNG files, so that the stream isn't read twice. Other formats,
+/// and metadata stored after the image data, need a stream without `seek`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_decode_from_callbacks(
+    io: *const WiIoCallbacks,
+    format: *const c_char,
+) -> *mut WiImage {
+    catch_panic(|| {
+        wi_img_decode_from_callbacks_with_limits(io, format, std::ptr::null())
+    })
+}
+
+/// Same as `wi_img_decode_from_callbacks`, but checks the image against the
+/// given limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_decode_from_callbacks_with_limits(
+    io: *const WiIoCallbacks,
+    format: *const c_char,
+    limits: *const WiDecodeLimits,
+) -> *mut WiImage {
+    catch_panic(|| {
+        use std::io::Read;
+        let limits = decode_limits(limits);
+        if io.is_null() {
+            return null_argument("io", std::ptr::null_mut());
+        }
+        let io = &*io;
+        let result = image_format_arg(format).and_then(|format| {
+            if io.seek.is_some() {
+                use std::io::Seek;
+                let mut reader = std::io::BufReader::new(CallbackIo(io));
+                let start = reader.stream_position().map_err(WiError::from)?;
+                let image = decode_image(&mut reader, format, &limits)?;
+                let metadata = match metadata_prefix(&mut reader, start) {
+                    Ok(prefix) => ImageMetadata::read(&prefix, &limits),
+                    Err(_) => ImageMetadata::default(),
+                };
+                Ok((image, metadata))
+            } else {
+                let mut buffer = Vec::new();
+                CallbackIo(io)
+                    .read_to_end(&mut buffer)
+                    .map_err(WiError::from)?;
+                let image = decode_image(std::io::Cursor::new(&buffer), format, &limits)?;
+                Ok((image, ImageMetadata::read(&buffer, &limits)))
+            }
+        });
+        let result = match result {
+            Ok((image, metadata)) => Box::new(WiImage(Ok(image), metadata)),
+            Err(x) => Box::new(WiImage(Err(x), ImageMetadata::default())),
+        };
+        Box::into_raw(result)
+    })
+}
+
+/// Map a user supplied format name (case-insensitive) to an `image::ImageFormat`.
+fn parse_image_format(format: &str) -> Option<image::ImageFormat> {
+    match format.to_lowercase().as_str() {
+        "png" => Some(image::ImageFormat::Png),
+        "jpeg" | "jpg" => Some(image::ImageFormat::Jpeg),
+        "gif" => Some(image::ImageFormat::Gif),
+        "webp" => Some(image::ImageFormat::WebP),
+        "pnm" | "pbm" | "pgm" | "ppm" | "pam" => Some(image::ImageFormat::Pnm),
+        "tiff" | "tif" => Some(image::ImageFormat::Tiff),
+        "tga" => Some(image::ImageFormat::Tga),
+        "bmp" => Some(image::ImageFormat::Bmp),
+        "ico" => Some(image::ImageFormat::Ico),
+        "hdr" => Some(image::ImageFormat::Hdr),
+        "dds" => Some(image::ImageFormat::Dds),
+        "farbfeld" | "ff" => Some(image::ImageFormat::Farbfeld),
+        "exr" | "openexr" => Some(image::ImageFormat::OpenExr),
+        "qoi" => Some(image::ImageFormat::Qoi),
+        "avif" => Some(image::ImageFormat::Avif),
+        _ => None
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_new_luma8_img(width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        let result = Ok(DynamicImage::new_luma8(width, height));
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_new_rgb8_img(width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        let result = Ok(DynamicImage::new_rgb8(width, height));
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_new_rgba8_img(width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        let result = Ok(DynamicImage::new_rgba8(width, height));
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_new_luma16_img(width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        let result = Ok(DynamicImage::new_luma16(width, height));
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_new_rgb16_img(width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        let result = Ok(DynamicImage::new_rgb16(width, height));
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_new_rgba16_img(width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        let result = Ok(DynamicImage::new_rgba16(width, height));
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_new_grayimg_f32(width: u32, height: u32) -> *mut WiGrayImageF32 {
+    catch_panic(|| {
+        let result = Ok(image::ImageBuffer::new(width, height));
+        let result = Box::new(WiGrayImageF32(result));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_crop(ctx: *const WiImage, cx: u32, cy: u32, width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.clone().crop(cx, cy, width, height)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+fn color_type_of(image: &DynamicImage) -> WiColorType {
+    match image.color() {
+        ::image::ColorType::L8 => WiColorType::L8,
+        ::image::ColorType::La8 => WiColorType::La8,
+        ::image::ColorType::Rgb8 => WiColorType::Rgb8,
+        ::image::ColorType::Rgba8 => WiColorType::Rgba8,
+        ::image::ColorType::L16 => WiColorType::L16,
+        ::image::ColorType::La16 => WiColorType::La16,
+        ::image::ColorType::Rgb16 => WiColorType::Rgb16,
+        ::image::ColorType::Rgba16 => WiColorType::Rgba16,
+        ::image::ColorType::Rgb32F => WiColorType::Rgb32F,
+        ::image::ColorType::Rgba32F => WiColorType::Rgba32F,
+        _ => WiColorType::Unknown,
+    }
+}
+
+/// The color type of the pixels of a `WiImage`, taking their channel order
+/// into account.
+fn image_color_type(image: &DynamicImage, metadata: &ImageMetadata) -> WiColorType {
+    match (color_type_of(image), metadata.bgr) {
+        (WiColorType::Rgb8, true) => WiColorType::Bgr8,
+        (WiColorType::Rgba8, true) => WiColorType::Bgra8,
+        (color, _) => color,
+    }
+}
+
+/// Swap the red and blue channels of 8-bit RGB(A) pixels, which turns RGB(A)
+/// into BGR(A) and back. Other color types are left as they are.
+fn swap_red_blue(image: &mut DynamicImage) {
+    match image {
+        DynamicImage::ImageRgb8(ref mut x) => x.pixels_mut().for_each(|px| px.0.swap(0, 2)),
+        DynamicImage::ImageRgba8(ref mut x) => x.pixels_mut().for_each(|px| px.0.swap(0, 2)),
+        _ => {}
+    }
+}
+
+/// The pixels of a `WiImage` in RGB(A) order, which is what every other
+/// operation expects.
+fn rgb_pixels<'a>(image: &'a DynamicImage, metadata: &ImageMetadata) -> std::borrow::Cow<'a, DynamicImage> {
+    if !metadata.bgr {
+        return std::borrow::Cow::Borrowed(image);
+    }
+    let mut image = image.clone();
+    swap_red_blue(&mut image);
+    std::borrow::Cow::Owned(image)
+}
+
+fn color_type_name(color: WiColorType) -> &'static [u8] {
+    match color {
+        WiColorType::Unknown => b"unknown\0",
+        WiColorType::L8 => b"gray:8\0",
+        WiColorType::La8 => b"graya:8\0",
+        WiColorType::Rgb8 => b"rgb:8\0",
+        WiColorType::Rgba8 => b"rgba:8\0",
+        WiColorType::L16 => b"gray:16\0",
+        WiColorType::La16 => b"graya:16\0",
+        WiColorType::Rgb16 => b"rgb:16\0",
+        WiColorType::Rgba16 => b"rgba:16\0",
+        WiColorType::Rgb32F => b"rgb:32f\0",
+        WiColorType::Rgba32F => b"rgba:32f\0",
+        WiColorType::Bgr8 => b"bgr:8\0",
+        WiColorType::Bgra8 => b"bgra:8\0",
+    }
+}
+
+/// Map a `WiColorType` value received from C, which may be any integer.
+fn color_type_from_c(value: c_int) -> Result<WiColorType, WiError> {
+    match value {
+        0 => Ok(WiColorType::Unknown),
+        1 => Ok(WiColorType::L8),
+        2 => Ok(WiColorType::La8),
+        3 => Ok(WiColorType::Rgb8),
+        4 => Ok(WiColorType::Rgba8),
+        5 => Ok(WiColorType::L16),
+        6 => Ok(WiColorType::La16),
+        7 => Ok(WiColorType::Rgb16),
+        8 => Ok(WiColorType::Rgba16),
+        9 => Ok(WiColorType::Rgb32F),
+        10 => Ok(WiColorType::Rgba32F),
+        11 => Ok(WiColorType::Bgr8),
+        12 => Ok(WiColorType::Bgra8),
+        _ => {
+            let message = format!("unknown color type value: {}", value);
+            Err(WiError::new(WiErrorCode::InvalidArgument, message))
+        }
+    }
+}
+
+/// Parse a color type such as `"rgba8"`, `"l16"` or `"rgb32f"`. The strings
+/// returned by `wi_img_color` are accepted as well, and `"luma"` or `"gray"`
+/// may be spelled out instead of `"l"`.
+fn parse_color_type(color: &str) -> Result<WiColorType, WiError> {
+    match color.to_lowercase().as_str() {
+        "l8" | "luma8" | "gray8" | "gray:8" => Ok(WiColorType::L8),
+        "la8" | "lumaa8" | "graya8" | "graya:8" => Ok(WiColorType::La8),
+        "l16" | "luma16" | "gray16" | "gray:16" => Ok(WiColorType::L16),
+        "la16" | "lumaa16" | "graya16" | "graya:16" => Ok(WiColorType::La16),
+        "rgb8" | "rgb:8" => Ok(WiColorType::Rgb8),
+        "rgba8" | "rgba:8" => Ok(WiColorType::Rgba8),
+        "rgb16" | "rgb:16" => Ok(WiColorType::Rgb16),
+        "rgba16" | "rgba:16" => Ok(WiColorType::Rgba16),
+        "rgb32f" | "rgb:32f" => Ok(WiColorType::Rgb32F),
+        "rgba32f" | "rgba:32f" => Ok(WiColorType::Rgba32F),
+        "bgr8" | "bgr:8" => Ok(WiColorType::Bgr8),
+        "bgra8" | "bgra:8" => Ok(WiColorType::Bgra8),
+        _ => {
+            let message = format!("unknown color type: {}", color);
+            Err(WiError::new(WiErrorCode::InvalidArgument, message))
+        }
+    }
+}
+
+fn convert_image(image: &DynamicImage, color: WiColorType) -> Result<DynamicImage, WiError> {
+    match color {
+        WiColorType::L8 => Ok(DynamicImage::ImageLuma8(image.to_luma8())),
+        WiColorType::La8 => Ok(DynamicImage::ImageLumaA8(image.to_luma_alpha8())),
+        WiColorType::Rgb8 => Ok(DynamicImage::ImageRgb8(image.to_rgb8())),
+        WiColorType::Rgba8 => Ok(DynamicImage::ImageRgba8(image.to_rgba8())),
+        WiColorType::L16 => Ok(DynamicImage::ImageLuma16(image.to_luma16())),
+        WiColorType::La16 => Ok(DynamicImage::ImageLumaA16(image.to_luma_alpha16())),
+        WiColorType::Rgb16 => Ok(DynamicImage::ImageRgb16(image.to_rgb16())),
+        WiColorType::Rgba16 => Ok(DynamicImage::ImageRgba16(image.to_rgba16())),
+        WiColorType::Rgb32F => Ok(DynamicImage::ImageRgb32F(image.to_rgb32f())),
+        WiColorType::Rgba32F => Ok(DynamicImage::ImageRgba32F(image.to_rgba32f())),
+        WiColorType::Bgr8 | WiColorType::Bgra8 => {
+            let mut image = match color {
+                WiColorType::Bgr8 => DynamicImage::ImageRgb8(image.to_rgb8()),
+                _ => DynamicImage::ImageRgba8(image.to_rgba8()),
+            };
+            swap_red_blue(&mut image);
+            Ok(image)
+        }
+        WiColorType::Unknown => Err(WiError::new(WiErrorCode::InvalidArgument, "can't convert to an unknown color type")),
+    }
+}
+
+/// Convert the pixels of a `WiImage`, keeping its metadata and recording the
+/// channel order of the result.
+fn convert_handle(input: &WiImage, color: Result<WiColorType, WiError>) -> WiImage {
+    let mut metadata = input.1.clone();
+    let result = match (&input.0, color) {
+        (Ok(ref x), Ok(color)) => {
+            metadata.bgr = color == WiColorType::Bgr8 || color == WiColorType::Bgra8;
+            convert_image(&rgb_pixels(x, &input.1), color)
+        }
+        (Err(x), _) => Err(x.clone()),
+        (_, Err(x)) => Err(x),
+    };
+    WiImage(result, metadata)
+}
+
+/// The color type of the image, e.g. `"rgba:8"` or `"gray:16"`.
+/// See `wi_img_color_type` for the same as an enum.
+///
+/// The 8-bit names of earlier releases are unchanged, 16-bit and float images
+/// add `"gray:16"`, `"rgba:32f"` and so on. Decoders produce RGB(A), so
+/// `"bgr:8"` and `"bgra:8"` are only returned for images converted to them with
+/// `wi_img_convert`, and `"palette:N"` is never returned, as before.
+///
+/// The returned string is static and must not be freed.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_color(ctx: *const WiImage) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => color_type_name(image_color_type(x, &(&*ctx).1)).as_ptr() as *const c_char,
+            Err(x) => x.report(std::ptr::null())
+        }
+    })
+}
+
+/// The color type of the image, or `Unknown` on error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_color_type(ctx: *const WiImage) -> WiColorType {
+    catch_panic_or(WiColorType::Unknown, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiColorType::Unknown);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => image_color_type(x, &(&*ctx).1),
+            Err(x) => x.report(WiColorType::Unknown)
+        }
+    })
+}
+
+/// The name of a `WiColorType` value, as returned by `wi_img_color`, or NULL
+/// if `color` isn't a `WiColorType` value.
+///
+/// The returned string is static and must not be freed.
+#[no_mangle]
+pub unsafe extern "C" fn wi_color_type_name(color: c_int) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        match color_type_from_c(color) {
+            Ok(color) => color_type_name(color).as_ptr() as *const c_char,
+            Err(x) => x.report(std::ptr::null()),
+        }
+    })
+}
+
+/// Convert the image to the given color type, such as `"rgb8"` (dropping alpha),
+/// `"rgba8"` (adding opaque alpha), `"la8"`, `"l16"`, `"rgba16"` or `"rgb32f"`.
+/// The strings returned by `wi_img_color` are accepted as well.
+///
+/// `"bgr8"` and `"bgra8"` give an image whose raw pixels (see `wi_img_raw_data`)
+/// are stored in BGR(A) order, and whose color is `"bgr:8"` or `"bgra:8"`. Other
+/// operations still see the colors as they are: encoding, copying a region and
+/// converting again all swap the channels back.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_convert(ctx: *const WiImage, color: *const c_char) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if color.is_null() {
+            return null_argument("color", std::ptr::null_mut());
+        }
+        let color = CStr::from_ptr(color)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_color_type);
+        Box::into_raw(Box::new(convert_handle(&*ctx, color)))
+    })
+}
+
+/// Convert the image to the given `WiColorType` value. See `wi_img_convert`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_convert_to_color_type(ctx: *const WiImage, color: c_int) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        Box::into_raw(Box::new(convert_handle(&*ctx, color_type_from_c(color))))
+    })
+}
+
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_grayscale(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(rgb_pixels(x, &(&*ctx).1).grayscale()),
+            Err(x) => Err(x.clone()),
+        };
+        let metadata = ImageMetadata {bgr: false, ..(&*ctx).1.clone()};
+        let result = Box::new(WiImage(result, metadata));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_invert(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok({
+                let mut x = x.clone();
+                x.invert();
+                x
+            }),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_resize(ctx: *const WiImage, width: u32, height: u32, format: *const c_char) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if format.is_null() {
+            return null_argument("format", std::ptr::null_mut());
+        }
+        let result = CStr::from_ptr(format)
+            .to_str()
+            .ok()
+            .and_then(|format| {
+                match format {
+                    "Nearest" => Some(image::imageops::FilterType::Nearest),
+                    "Triangle" => Some(image::imageops::FilterType::Triangle),
+                    "CatmullRom" => Some(image::imageops::FilterType::CatmullRom),
+                    "Gaussian" => Some(image::imageops::FilterType::Gaussian),
+                    "Lanczos3" => Some(image::imageops::FilterType::Lanczos3),
+                    _ => None
+                }
+            })
+            .ok_or_else(|| WiError::new(WiErrorCode::InvalidArgument, "invalid filter type"))
+            .and_then(|format| {
+                match &(&*ctx).0 {
+                    Ok(ref x) => Ok(x.resize(width, height, format)),
+                    Err(x) => Err(x.clone()),
+                }
+            });
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_resize_exact(ctx: *const WiImage, width: u32, height: u32, format: *const c_char) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if format.is_null() {
+            return null_argument("format", std::ptr::null_mut());
+        }
+        let result = CStr::from_ptr(format)
+            .to_str()
+            .ok()
+            .and_then(|format| {
+                match format.to_lowercase().as_str() {
+                    "nearest" => Some(image::imageops::FilterType::Nearest),
+                    "triangle" => Some(image::imageops::FilterType::Triangle),
+                    "catmullrom" => Some(image::imageops::FilterType::CatmullRom),
+                    "gaussian" => Some(image::imageops::FilterType::Gaussian),
+                    "lanczos3" => Some(image::imageops::FilterType::Lanczos3),
+                    _ => None
+                }
+            })
+            .ok_or_else(|| WiError::new(WiErrorCode::InvalidArgument, "invalid filter type"))
+            .and_then(|format| {
+                match &(&*ctx).0 {
+                    Ok(ref x) => Ok(x.resize_exact(width, height, format)),
+                    Err(x) => Err(x.clone()),
+                }
+            });
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_thumbnail(ctx: *const WiImage, width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.thumbnail(width, height)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_thumbnail_exact(ctx: *const WiImage, width: u32, height: u32) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.thumbnail_exact(width, height)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_blur(ctx: *const WiImage, sigma: f32) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.blur(sigma)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_unsharpen(ctx: *const WiImage, sigma: f32, threshold: i32) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.unsharpen(sigma, threshold)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_filter3x3(ctx: *const WiImage, value: *const f32) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if value.is_null() {
+            return null_argument("value", std::ptr::null_mut());
+        }
+        let value = [
+            *value.offset(0),
+            *value.offset(1),
+            *value.offset(2),
+            *value.offset(3),
+            *value.offset(4),
+            *value.offset(5),
+            *value.offset(6),
+            *value.offset(7),
+            *value.offset(8),
+        ];
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.filter3x3(&value)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_adjust_contrast(ctx: *const WiImage, value: f32) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.adjust_contrast(value)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_brighten(ctx: *const WiImage, value: c_int) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.brighten(value)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_huerotate(ctx: *const WiImage, value: c_int) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.huerotate(value)),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+/// The EXIF orientation of the result is reset to 1.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_flipv(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.flipv()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.reoriented()));
+        Box::into_raw(result)
+    })
+}
+
+/// The EXIF orientation of the result is reset to 1.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_fliph(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.fliph()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.reoriented()));
+        Box::into_raw(result)
+    })
+}
+
+/// The EXIF orientation of the result is reset to 1.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_rotate90(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.rotate90()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.reoriented()));
+        Box::into_raw(result)
+    })
+}
+
+/// The EXIF orientation of the result is reset to 1.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_rotate180(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.rotate180()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.reoriented()));
+        Box::into_raw(result)
+    })
+}
+
+/// The EXIF orientation of the result is reset to 1.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_rotate270(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => Ok(x.rotate270()),
+            Err(x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.reoriented()));
+        Box::into_raw(result)
+    })
+}
+
+/// Save the image to `path`, in the format implied by its extension.
+///
+/// Metadata isn't written, use `wi_img_save_with_format` to keep it.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_save(ctx: *const WiImage, path: *const c_char) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if path.is_null() {
+            return null_argument("path", 0);
+        }
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| {
+                match &(&*ctx).0 {
+                    Ok(ref x) => rgb_pixels(x, &(&*ctx).1).save(path).map_err(WiError::from),
+                    Err(x) => Err(x.clone()),
+                }
+            });
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+
+/// Save the image to `path` in the given format (case-insensitive), such as
+/// "png", "jpeg"/"jpg", "gif", "bmp", "ico", "pnm"/"pgm"/"ppm"/"pam",
+/// "tga", "tiff"/"tif", "farbfeld", "exr", "qoi", "webp" or "avif".
+///
+/// Returns 1 on success, 0 on failure, and -1 if `format` is unknown or
+/// cannot be encoded.
+///
+/// The metadata of the image (see `wi_img_metadata_count`) and its ICC
+/// profile are written to JPEG (EXIF, XMP and IPTC) and PNG (EXIF and XMP)
+/// files. The same goes for the other save and encode functions, except
+/// `wi_img_save`. JPEG segments hold at most 64 KB: larger XMP packets are
+/// written as extended XMP, while larger EXIF and IPTC blocks are left out.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_save_with_format(ctx: *const WiImage, path: *const c_char, format: *const c_char) -> c_int {
+    catch_panic_or(0, || {
+        wi_img_save_with_options(ctx, path, format, std::ptr::null())
+    })
+}
+
+/// Save the image to `path` in the given format, using the given encoder options.
+///
+/// If `options` is NULL, the encoder defaults are used.
+/// Returns 1 on success, 0 on failure, and -1 if `format` is unknown or
+/// cannot be encoded.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_save_with_options(
+    ctx: *const WiImage,
+    path: *const c_char,
+    format: *const c_char,
+    options: *const WiEncodeOptions,
+) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if path.is_null() {
+            return null_argument("path", 0);
+        }
+        if format.is_null() {
+            return null_argument("format", 0);
+        }
+        let default_options = WiEncodeOptions::default();
+        let options = options.as_ref().unwrap_or(&default_options);
+        let format = CStr::from_ptr(format)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_save_format);
+        let format = match format {
+            Ok(format) => format,
+            Err(x) => return x.report(-1),
+        };
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| {
+                match &(&*ctx).0 {
+                    Ok(ref x) => {
+                        let output = encode_with_metadata(x, &(&*ctx).1, format, options)?;
+                        std::fs::write(path, output).map_err(WiError::from)
+                    }
+                    Err(x) => Err(x.clone()),
+                }
+            });
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// Encode the image into a library-owned byte buffer.
+///
+/// Accepts the same format strings as `wi_img_save_with_format`.
+/// The returned value must be released with `wi_bytes_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_encode(ctx: *const WiImage, format: *const c_char) -> *mut WiBytes {
+    catch_panic(|| {
+        wi_img_encode_with_options(ctx, format, std::ptr::null())
+    })
+}
+
+/// Encode the image into a library-owned byte buffer, using the given encoder options.
+///
+/// If `options` is NULL, the encoder defaults are used.
+/// The returned value must be released with `wi_bytes_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_encode_with_options(
+    ctx: *const WiImage,
+    format: *const c_char,
+    options: *const WiEncodeOptions,
+) -> *mut WiBytes {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if format.is_null() {
+            return null_argument("format", std::ptr::null_mut());
+        }
+        let default_options = WiEncodeOptions::default();
+        let options = options.as_ref().unwrap_or(&default_options);
+        let result = CStr::from_ptr(format)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_save_format)
+            .and_then(|format| {
+                match &(&*ctx).0 {
+                    Ok(ref x) => encode_with_metadata(x, &(&*ctx).1, format, options),
+                    Err(x) => Err(x.clone()),
+                }
+            });
+        let result = Box::new(WiBytes(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Encode the image into the `write` callback of the given I/O callbacks.
+///
+/// Accepts the same format strings as `wi_img_save_with_format`.
+/// Returns 1 on success, 0 on failure, and -1 if `format` is unknown or
+/// cannot be encoded.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_encode_to_callbacks(
+    ctx: *const WiImage,
+    io: *const WiIoCallbacks,
+    format: *const c_char,
+) -> c_int {
+    catch_panic_or(0, || {
+        wi_img_encode_to_callbacks_with_options(ctx, io, format, std::ptr::null())
+    })
+}
+
+/// Encode the image into the `write` callback of the given I/O callbacks,
+/// using the given encoder options, or the defaults if `options` is NULL.
+///
+/// The encoded file is written as it's produced, except for TIFF and
+/// OpenEXR files (whose encoders seek back into their output), and JPEG and
+/// PNG files with metadata, which are encoded in memory first. `seek` is
+/// never called. Returns like `wi_img_encode_to_callbacks`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_encode_to_callbacks_with_options(
+    ctx: *const WiImage,
+    io: *const WiIoCallbacks,
+    format: *const c_char,
+    options: *const WiEncodeOptions,
+) -> c_int {
+    catch_panic_or(0, || {
+        use image::ImageOutputFormat;
+        use std::io::Write;
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if io.is_null() {
+            return null_argument("io", 0);
+        }
+        if format.is_null() {
+            return null_argument("format", 0);
+        }
+        let format = CStr::from_ptr(format)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_save_format);
+        let format = match format {
+            Ok(format) => format,
+            Err(x) => return x.report(-1),
+        };
+        let default_options = WiEncodeOptions::default();
+        let options = options.as_ref().unwrap_or(&default_options);
+        let mut writer = std::io::BufWriter::new(CallbackIo(&*io));
+        let result = match (&(&*ctx).0, format) {
+            (Ok(x), format @ OutputFormat::Image(ImageOutputFormat::Tiff))
+            | (Ok(x), format @ OutputFormat::Image(ImageOutputFormat::OpenExr)) => {
+                encode_with_metadata(x, &(&*ctx).1, format, options)
+                    .and_then(|output| writer.write_all(&output).map_err(WiError::from))
+            }
+            (Ok(x), format) => write_with_metadata(x, &(&*ctx).1, format, options, &mut writer),
+            (Err(x), _) => Err(x.clone()),
+        };
+        let result = result.and_then(|_| writer.flush().map_err(WiError::from));
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// An encodable output format.
+///
+/// AVIF is encoded with `ravif` directly (without its assembly optimizations),
+/// so that no system libraries or assemblers are needed at build time.
+enum OutputFormat {
+    Image(image::ImageOutputFormat),
+    Avif,
+}
+
+/// Map the format names accepted by `wi_img_save_with_format` and `wi_img_encode`
+/// (case-insensitive) to an output format.
+///
+/// Known formats that can only be decoded are reported as an error, as are unknown names.
+fn parse_save_format(format: &str) -> Result<OutputFormat, WiError> {
+    use image::ImageOutputFormat;
+    use image::codecs::pnm::{PnmSubtype, SampleEncoding};
+    let format = match format.to_lowercase().as_str() {
+        "png" => ImageOutputFormat::Png,
+        "jpeg" | "jpg" => ImageOutputFormat::Jpeg(75),
+        "gif" => ImageOutputFormat::Gif,
+        "bmp" => ImageOutputFormat::Bmp,
+        "ico" => ImageOutputFormat::Ico,
+        "pnm" | "pam" => ImageOutputFormat::Pnm(PnmSubtype::ArbitraryMap),
+        "pbm" => {
+            let message = "encoding is not supported for PBM bitmaps";
+            return Err(WiError::new(WiErrorCode::UnsupportedFormat, message));
+        }
+        "pgm" => ImageOutputFormat::Pnm(PnmSubtype::Graymap(SampleEncoding::Binary)),
+        "ppm" => ImageOutputFormat::Pnm(PnmSubtype::Pixmap(SampleEncoding::Binary)),
+        "tga" => ImageOutputFormat::Tga,
+        "tiff" | "tif" => ImageOutputFormat::Tiff,
+        "farbfeld" | "ff" => ImageOutputFormat::Farbfeld,
+        "exr" | "openexr" => ImageOutputFormat::OpenExr,
+        "qoi" => ImageOutputFormat::Qoi,
+        "webp" => ImageOutputFormat::WebP,
+        "avif" => return Ok(OutputFormat::Avif),
+        other => return match parse_image_format(other) {
+            Some(x) => {
+                let message = format!("encoding is not supported for {:?} images", x);
+                Err(WiError::new(WiErrorCode::UnsupportedFormat, message))
+            }
+            None => {
+                let message = format!("unknown image format: {}", other);
+                Err(WiError::new(WiErrorCode::UnsupportedFormat, message))
+            }
+        }
+    };
+    Ok(OutputFormat::Image(format))
+}
+
+/// Encode the image in memory like `encode_image`, and embed its metadata in
+/// JPEG and PNG output.
+fn encode_with_metadata(
+    image: &DynamicImage,
+    metadata: &ImageMetadata,
+    format: OutputFormat,
+    options: &WiEncodeOptions,
+) -> Result<Vec<u8>, WiError> {
+    use image::ImageOutputFormat;
+    let image = &*rgb_pixels(image, metadata);
+    match format {
+        OutputFormat::Image(ImageOutputFormat::Jpeg(_)) => {
+            embed_jpeg_metadata(encode_image(image, format, options)?, metadata)
+        }
+        OutputFormat::Image(ImageOutputFormat::Png) => {
+            embed_png_metadata(encode_image(image, format, options)?, metadata)
+        }
+        format => encode_image(image, format, options),
+    }
+}
+
+/// Encode the image like `encode_with_metadata`, but straight into `output`
+/// unless metadata has to be spliced into the encoded file.
+fn write_with_metadata<W: std::io::Write + std::io::Seek>(
+    image: &DynamicImage,
+    metadata: &ImageMetadata,
+    format: OutputFormat,
+    options: &WiEncodeOptions,
+    output: &mut W,
+) -> Result<(), WiError> {
+    use image::ImageOutputFormat;
+    match format {
+        OutputFormat::Image(ImageOutputFormat::Jpeg(_)) | OutputFormat::Image(ImageOutputFormat::Png)
+            if !metadata.is_empty() =>
+        {
+            let encoded = encode_with_metadata(image, metadata, format, options)?;
+            output.write_all(&encoded).map_err(WiError::from)
+        }
+        format => write_image(&rgb_pixels(image, metadata), format, options, output),
+    }
+}
+
+/// Encode the image in memory, applying the format specific encoder options.
+fn encode_image(
+    image: &DynamicImage,
+    format: OutputFormat,
+    options: &WiEncodeOptions,
+) -> Result<Vec<u8>, WiError> {
+    let mut output = std::io::Cursor::new(Vec::new());
+    write_image(image, format, options, &mut output)?;
+    Ok(output.into_inner())
+}
+
+/// Encode the image into `output`, applying the format specific encoder options.
+///
+/// Formats that only support specific pixel layouts get a converted copy of the image.
+fn write_image<W: std::io::Write + std::io::Seek>(
+    image: &DynamicImage,
+    format: OutputFormat,
+    options: &WiEncodeOptions,
+    mut output: &mut W,
+) -> Result<(), WiError> {
+    use image::codecs::pnm::PnmSubtype;
+    let format = match format {
+        OutputFormat::Image(format) => format,
+        OutputFormat::Avif => return output.write_all(&encode_avif(image, options)?).map_err(WiError::from),
+    };
+    let result = match format {
+        image::ImageOutputFormat::Jpeg(_) => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(
+                &mut output,
+                options.jpeg_quality,
+            );
+            image.write_with_encoder(encoder)
+        }
+        image::ImageOutputFormat::Png => {
+            let encoder = image::codecs::png::PngEncoder::new_with_quality(
+                &mut output,
+                options.png_compression,
+                options.png_filter,
+            );
+            image.write_with_encoder(encoder)
+        }
+        image::ImageOutputFormat::WebP => {
+            // The WebP encoders only accept 8-bit RGB(A) input.
+            let image = if image.color().has_alpha() {
+                DynamicImage::ImageRgba8(image.to_rgba8())
+            } else {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            };
+            if !options.webp_lossless {
+                return encode_lossy_webp(&image, options.webp_quality, output);
+            }
+            let encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut output);
+            image.write_with_encoder(encoder)
+        }
+        image::ImageOutputFormat::Pnm(PnmSubtype::Graymap(_)) => {
+            DynamicImage::ImageLuma8(image.to_luma8()).write_to(&mut output, format)
+        }
+        image::ImageOutputFormat::Pnm(PnmSubtype::Pixmap(_)) => {
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_to(&mut output, format)
+        }
+        image::ImageOutputFormat::Farbfeld => {
+            DynamicImage::ImageRgba16(image.to_rgba16()).write_to(&mut output, format)
+        }
+        image::ImageOutputFormat::OpenExr => {
+            DynamicImage::ImageRgba32F(image.to_rgba32f()).write_to(&mut output, format)
+        }
+        format => image.write_to(&mut output, format),
+    };
+    result.map_err(WiError::from)
+}
+
+/// Encode 8-bit RGB(A) pixels as lossy WebP with libwebp.
+#[cfg(feature = "webp")]
+fn encode_lossy_webp<W: std::io::Write>(image: &DynamicImage, quality: u8, output: &mut W) -> Result<(), WiError> {
+    let encoder = match image {
+        DynamicImage::ImageRgba8(x) => webp::Encoder::from_rgba(x.as_raw(), x.width(), x.height()),
+        x => webp::Encoder::from_rgb(x.as_bytes(), x.width(), x.height()),
+    };
+    let encoded = encoder.encode_simple(false, quality as f32).map_err(|x| {
+        WiError::new(WiErrorCode::EncodeFailed, format!("webp encoding failed: {:?}", x))
+    })?;
+    output.write_all(&encoded).map_err(WiError::from)
+}
+
+/// Lossy WebP needs libwebp, which is left out when the `webp` feature is off.
+#[cfg(not(feature = "webp"))]
+fn encode_lossy_webp<W: std::io::Write>(_: &DynamicImage, _: u8, _: &mut W) -> Result<(), WiError> {
+    let message = "lossy webp output requires the webp feature, enable lossless output instead";
+    Err(WiError::new(WiErrorCode::UnsupportedFormat, message))
+}
+
+fn encode_avif(image: &DynamicImage, options: &WiEncodeOptions) -> Result<Vec<u8>, WiError> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let encoder = ravif::Encoder::new()
+        .with_quality(options.avif_quality as f32)
+        .with_alpha_quality(options.avif_quality as f32)
+        .with_speed(options.avif_speed);
+    let result = if image.color().has_alpha() {
+        let pixels: Vec<ravif::RGBA8> = image
+            .to_rgba8()
+            .pixels()
+            .map(|px| ravif::RGBA8::new(px[0], px[1], px[2], px[3]))
+            .collect();
+        encoder.encode_rgba(ravif::Img::new(&pixels[..], width, height))
+    } else {
+        let pixels: Vec<ravif::RGB8> = image
+            .to_rgb8()
+            .pixels()
+            .map(|px| ravif::RGB8::new(px[0], px[1], px[2]))
+            .collect();
+        encoder.encode_rgb(ravif::Img::new(&pixels[..], width, height))
+    };
+    result
+        .map(|x| x.avif_file)
+        .map_err(|x| WiError::new(WiErrorCode::EncodeFailed, x.to_string()))
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE - COLOR-SPACES
+///////////////////////////////////////////////////////////////////////////////
+
+/// A color space that an image can be split into float planes of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorSpace {
+    LinearRgb,
+    Hsv,
+    Hsl,
+    Lab,
+    YCbCr,
+}
+
+fn parse_color_space(space: &str) -> Result<ColorSpace, WiError> {
+    match space.to_lowercase().as_str() {
+        "linear" | "linear-rgb" | "linrgb" => Ok(ColorSpace::LinearRgb),
+        "hsv" => Ok(ColorSpace::Hsv),
+        "hsl" => Ok(ColorSpace::Hsl),
+        "lab" | "cielab" => Ok(ColorSpace::Lab),
+        "ycbcr" => Ok(ColorSpace::YCbCr),
+        other => {
+            let message = format!("unknown color space: {}", other);
+            Err(WiError::new(WiErrorCode::InvalidArgument, message))
+        }
+    }
+}
+
+fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(x: f32) -> f32 {
+    let x = x.clamp(0.0, 1.0);
+    if x <= 0.003_130_8 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// The hue (as a fraction of a turn), max and min of an sRGB color.
+fn rgb_to_hue([r, g, b]: [f32; 3]) -> (f32, f32, f32) {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    };
+    (hue / 6.0, max, min)
+}
+
+/// The sRGB color of a hue with the given chroma, offset by `m`.
+fn hue_to_rgb(hue: f32, chroma: f32, m: f32) -> [f32; 3] {
+    let h = hue.rem_euclid(1.0) * 6.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let [r, g, b] = match h as u32 {
+        0 => [chroma, x, 0.0],
+        1 => [x, chroma, 0.0],
+        2 => [0.0, chroma, x],
+        3 => [0.0, x, chroma],
+        4 => [x, 0.0, chroma],
+        _ => [chroma, 0.0, x],
+    };
+    [r + m, g + m, b + m]
+}
+
+// The sRGB (D65) primaries, and the D65 white point.
+const RGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412_456_4, 0.357_576_1, 0.180_437_5],
+    [0.212_672_9, 0.715_152_2, 0.072_175],
+    [0.019_333_9, 0.119_192, 0.950_304_1],
+];
+const XYZ_TO_RGB: [[f32; 3]; 3] = [
+    [3.240_454_2, -1.537_138_5, -0.498_531_4],
+    [-0.969_266, 1.876_010_8, 0.041_556],
+    [0.055_643_4, -0.204_025_9, 1.057_225_2],
+];
+const D65_WHITE: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+
+fn mul3(m: &[[f32; 3]; 3], [x, y, z]: [f32; 3]) -> [f32; 3] {
+    [
+        m[0][0] * x + m[0][1] * y + m[0][2] * z,
+        m[1][0] * x + m[1][1] * y + m[1][2] * z,
+        m[2][0] * x + m[2][1] * y + m[2][2] * z,
+    ]
+}
+
+/// Convert a gamma-encoded sRGB color (each channel in `0.0`–`1.0`) to the given color space.
+fn srgb_to_color_space(space: ColorSpace, rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb;
+    match space {
+        ColorSpace::LinearRgb => rgb.map(srgb_to_linear),
+        ColorSpace::Hsv => {
+            let (hue, max, min) = rgb_to_hue(rgb);
+            let saturation = if max == 0.0 { 0.0 } else { (max - min) / max };
+            [hue, saturation, max]
+        }
+        ColorSpace::Hsl => {
+            let (hue, max, min) = rgb_to_hue(rgb);
+            let lightness = (max + min) / 2.0;
+            let saturation = if max == min {
+                0.0
+            } else {
+                (max - min) / (1.0 - (2.0 * lightness - 1.0).abs())
+            };
+            [hue, saturation, lightness]
+        }
+        ColorSpace::Lab => {
+            let f = |t: f32| {
+                let delta: f32 = 6.0 / 29.0;
+                if t > delta.powi(3) {
+                    t.cbrt()
+                } else {
+                    t / (3.0 * delta * delta) + 4.0 / 29.0
+                }
+            };
+            let xyz = mul3(&RGB_TO_XYZ, rgb.map(srgb_to_linear));
+            let [fx, fy, fz] = [
+                f(xyz[0] / D65_WHITE[0]),
+                f(xyz[1] / D65_WHITE[1]),
+                f(xyz[2] / D65_WHITE[2]),
+            ];
+            [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+        }
+        ColorSpace::YCbCr => [
+            0.299 * r + 0.587 * g + 0.114 * b,
+            0.5 - 0.168_736 * r - 0.331_264 * g + 0.5 * b,
+            0.5 + 0.5 * r - 0.418_688 * g - 0.081_312 * b,
+        ],
+    }
+}
+
+/// Convert a color in the given color space back to gamma-encoded sRGB, clamped to `0.0`–`1.0`.
+fn color_space_to_srgb(space: ColorSpace, [c0, c1, c2]: [f32; 3]) -> [f32; 3] {
+    let rgb = match space {
+        ColorSpace::LinearRgb => [c0, c1, c2].map(linear_to_srgb),
+        ColorSpace::Hsv => {
+            let chroma = c2 * c1;
+            hue_to_rgb(c0, chroma, c2 - chroma)
+        }
+        ColorSpace::Hsl => {
+            let chroma = (1.0 - (2.0 * c2 - 1.0).abs()) * c1;
+            hue_to_rgb(c0, chroma, c2 - chroma / 2.0)
+        }
+        ColorSpace::Lab => {
+            let finv = |t: f32| {
+                let delta: f32 = 6.0 / 29.0;
+                if t > delta {
+                    t.powi(3)
+                } else {
+                    3.0 * delta * delta * (t - 4.0 / 29.0)
+                }
+            };
+            let fy = (c0 + 16.0) / 116.0;
+            let xyz = [
+                D65_WHITE[0] * finv(fy + c1 / 500.0),
+                D65_WHITE[1] * finv(fy),
+                D65_WHITE[2] * finv(fy - c2 / 200.0),
+            ];
+            mul3(&XYZ_TO_RGB, xyz).map(linear_to_srgb)
+        }
+        ColorSpace::YCbCr => [
+            c0 + 1.402 * (c2 - 0.5),
+            c0 - 0.344_136 * (c1 - 0.5) - 0.714_136 * (c2 - 0.5),
+            c0 + 1.772 * (c1 - 0.5),
+        ],
+    };
+    rgb.map(|x| x.clamp(0.0, 1.0))
+}
+
+/// Split the image into three float planes in the given color space, plus an alpha plane.
+///
+/// `space` is one of:
+/// - `"linear-rgb"`: linear-light R, G and B, decoded from sRGB, in `0.0`–`1.0`.
+/// - `"hsv"` and `"hsl"`: hue as a fraction of a turn, then saturation and
+///   value or lightness, all in `0.0`–`1.0`.
+/// - `"lab"`: CIELAB under D65, with L in `0.0`–`100.0` and a and b roughly
+///   in `-128.0`–`127.0`.
+/// - `"ycbcr"`: full-range BT.601 (as used by JPEG), in `0.0`–`1.0` with
+///   Cb and Cr centered on `0.5`.
+///
+/// HSV, HSL and YCbCr are computed from gamma-encoded sRGB values, while
+/// linear RGB and CIELAB decode the sRGB transfer function first.
+///
+/// The new planes are written to `c0`, `c1` and `c2`, and the alpha channel
+/// (`1.0` for opaque images) to `alpha` unless it's NULL. They must be released
+/// with `wi_grayimg_f32_free`. Returns `Ok`, or the code of the error, which is
+/// also recorded as the last error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_to_color_space(
+    ctx: *const WiImage,
+    space: *const c_char,
+    c0: *mut *mut WiGrayImageF32,
+    c1: *mut *mut WiGrayImageF32,
+    c2: *mut *mut WiGrayImageF32,
+    alpha: *mut *mut WiGrayImageF32,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiErrorCode::InvalidArgument);
+        }
+        if space.is_null() {
+            return null_argument("space", WiErrorCode::InvalidArgument);
+        }
+        if c0.is_null() || c1.is_null() || c2.is_null() {
+            return null_argument("c0, c1 or c2", WiErrorCode::InvalidArgument);
+        }
+        let space = CStr::from_ptr(space)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_color_space);
+        let (image, space) = match (&(&*ctx).0, space) {
+            (Ok(ref x), Ok(space)) => (x.to_rgba32f(), space),
+            (Err(x), _) => return x.report(x.code),
+            (_, Err(x)) => return x.report(x.code),
+        };
+        let (width, height) = image.dimensions();
+        let mut planes: [image::ImageBuffer<image::Luma<f32>, Vec<f32>>; 4] = [
+            image::ImageBuffer::new(width, height),
+            image::ImageBuffer::new(width, height),
+            image::ImageBuffer::new(width, height),
+            image::ImageBuffer::new(width, height),
+        ];
+        for (x, y, px) in image.enumerate_pixels() {
+            let [r, g, b, a] = px.0;
+            let value = srgb_to_color_space(space, [r, g, b]);
+            planes[0].put_pixel(x, y, image::Luma([value[0]]));
+            planes[1].put_pixel(x, y, image::Luma([value[1]]));
+            planes[2].put_pixel(x, y, image::Luma([value[2]]));
+            planes[3].put_pixel(x, y, image::Luma([a]));
+        }
+        let [p0, p1, p2, p3] = planes;
+        *c0 = Box::into_raw(Box::new(WiGrayImageF32(Ok(p0))));
+        *c1 = Box::into_raw(Box::new(WiGrayImageF32(Ok(p1))));
+        *c2 = Box::into_raw(Box::new(WiGrayImageF32(Ok(p2))));
+        if !alpha.is_null() {
+            *alpha = Box::into_raw(Box::new(WiGrayImageF32(Ok(p3))));
+        }
+        WiErrorCode::Ok
+    })
+}
+
+/// Combine three float planes in the given color space, and an optional alpha
+/// plane, back into an image. See `wi_img_to_color_space` for the color spaces.
+///
+/// The result is an `"rgba:32f"` image when `alpha` is given, and `"rgb:32f"`
+/// otherwise, with sRGB-encoded channels clamped to `0.0`–`1.0`. Use
+/// `wi_img_convert` to turn it into an 8 or 16-bit image. Planes of different
+/// sizes give a `DimensionMismatch` error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_from_color_space(
+    space: *const c_char,
+    c0: *const WiGrayImageF32,
+    c1: *const WiGrayImageF32,
+    c2: *const WiGrayImageF32,
+    alpha: *const WiGrayImageF32,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if space.is_null() {
+            return null_argument("space", std::ptr::null_mut());
+        }
+        if c0.is_null() || c1.is_null() || c2.is_null() {
+            return null_argument("c0, c1 or c2", std::ptr::null_mut());
+        }
+        let go = || -> Result<DynamicImage, WiError> {
+            let space = parse_color_space(CStr::from_ptr(space).to_str()?)?;
+            let planes = [&(&*c0).0, &(&*c1).0, &(&*c2).0];
+            let planes = planes.iter().map(|x| (*x).clone()).collect::<Result<Vec<_>, _>>()?;
+            let alpha = match alpha.is_null() {
+                true => None,
+                false => Some((&*alpha).0.clone()?),
+            };
+            let dimensions = planes[0].dimensions();
+            let mismatch = planes.iter().chain(alpha.iter()).any(|x| x.dimensions() != dimensions);
+            if mismatch {
+                let message = "color space planes have different dimensions";
+                return Err(WiError::new(WiErrorCode::DimensionMismatch, message));
+            }
+            let (width, height) = dimensions;
+            let color = |x: u32, y: u32| {
+                let value = [
+                    planes[0].get_pixel(x, y)[0],
+                    planes[1].get_pixel(x, y)[0],
+                    planes[2].get_pixel(x, y)[0],
+                ];
+                color_space_to_srgb(space, value)
+            };
+            match alpha {
+                Some(alpha) => Ok(DynamicImage::ImageRgba32F(image::ImageBuffer::from_fn(width, height, |x, y| {
+                    let [r, g, b] = color(x, y);
+                    image::Rgba([r, g, b, alpha.get_pixel(x, y)[0].clamp(0.0, 1.0)])
+                }))),
+                None => Ok(DynamicImage::ImageRgb32F(image::ImageBuffer::from_fn(width, height, |x, y| {
+                    image::Rgb(color(x, y))
+                }))),
+            }
+        };
+        let result = Box::new(WiImage(go(), ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE - CHANNELS
+///////////////////////////////////////////////////////////////////////////////
+
+fn split_channels(image: &image::RgbaImage) -> [image::GrayImage; 4] {
+    let channel = |index: usize| {
+        image::GrayImage::from_fn(image.width(), image.height(), |x, y| {
+            image::Luma([image.get_pixel(x, y)[index]])
+        })
+    };
+    [channel(0), channel(1), channel(2), channel(3)]
+}
+
+/// Combine R, G and B channels, and optionally alpha, into an 8-bit image.
+fn merge_channels(
+    [r, g, b]: [&image::GrayImage; 3],
+    a: Option<&image::GrayImage>,
+) -> Result<DynamicImage, WiError> {
+    let dimensions = r.dimensions();
+    let mismatch = [g, b].iter().chain(a.iter()).any(|x| x.dimensions() != dimensions);
+    if mismatch {
+        let message = "channels have different dimensions";
+        return Err(WiError::new(WiErrorCode::DimensionMismatch, message));
+    }
+    let (width, height) = dimensions;
+    let rgb = |x, y| [r.get_pixel(x, y)[0], g.get_pixel(x, y)[0], b.get_pixel(x, y)[0]];
+    match a {
+        Some(a) => Ok(DynamicImage::ImageRgba8(image::RgbaImage::from_fn(width, height, |x, y| {
+            let [r, g, b] = rgb(x, y);
+            image::Rgba([r, g, b, a.get_pixel(x, y)[0]])
+        }))),
+        None => Ok(DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb(rgb(x, y))
+        }))),
+    }
+}
+
+/// Run a grayscale filter on each channel of an 8-bit copy of the image.
+///
+/// The alpha channel is filtered as well, unless `keep_alpha` is set. Images
+/// without alpha give an `"rgb:8"` result, and all others an `"rgba:8"` one.
+fn filter_channels<F>(image: &DynamicImage, keep_alpha: bool, filter: F) -> Result<DynamicImage, WiError>
+where
+    F: Fn(&image::GrayImage) -> image::GrayImage,
+{
+    let [r, g, b, a] = split_channels(&image.to_rgba8());
+    let [r, g, b] = [filter(&r), filter(&g), filter(&b)];
+    if !image.color().has_alpha() {
+        merge_channels([&r, &g, &b], None)
+    } else if keep_alpha {
+        merge_channels([&r, &g, &b], Some(&a))
+    } else {
+        merge_channels([&r, &g, &b], Some(&filter(&a)))
+    }
+}
+
+/// Convert the image to 8-bit RGBA, or RGB if it has no alpha, and apply `rgba` or `rgb` to it.
+fn map_rgb8_or_rgba8<F, G>(image: &DynamicImage, rgb: F, rgba: G) -> DynamicImage
+where
+    F: FnOnce(&image::RgbImage) -> image::RgbImage,
+    G: FnOnce(&image::RgbaImage) -> image::RgbaImage,
+{
+    match image.color().has_alpha() {
+        true => DynamicImage::ImageRgba8(rgba(&image.to_rgba8())),
+        false => DynamicImage::ImageRgb8(rgb(&image.to_rgb8())),
+    }
+}
+
+/// Split the image into its 8-bit R, G, B and A channels.
+///
+/// The new channels are written to `r`, `g` and `b`, and to `a` unless it's NULL
+/// (images without alpha give an opaque, all 255 channel). They must be released
+/// with `wi_grayimg_free`. Returns `Ok`, or the code of the error, which is also
+/// recorded as the last error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_split_channels(
+    ctx: *const WiImage,
+    r: *mut *mut WiGrayImage,
+    g: *mut *mut WiGrayImage,
+    b: *mut *mut WiGrayImage,
+    a: *mut *mut WiGrayImage,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiErrorCode::InvalidArgument);
+        }
+        if r.is_null() || g.is_null() || b.is_null() {
+            return null_argument("r, g or b", WiErrorCode::InvalidArgument);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                let [cr, cg, cb, ca] = split_channels(&x.to_rgba8());
+                *r = Box::into_raw(Box::new(WiGrayImage(Ok(cr))));
+                *g = Box::into_raw(Box::new(WiGrayImage(Ok(cg))));
+                *b = Box::into_raw(Box::new(WiGrayImage(Ok(cb))));
+                if !a.is_null() {
+                    *a = Box::into_raw(Box::new(WiGrayImage(Ok(ca))));
+                }
+                WiErrorCode::Ok
+            }
+            Err(x) => x.report(x.code),
+        }
+    })
+}
+
+/// Combine grayscale R, G and B channels into an `"rgb:8"` image, or with an
+/// alpha channel `a` (unless it's NULL) into an `"rgba:8"` image.
+///
+/// Channels of different sizes give a `DimensionMismatch` error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_merge_channels(
+    r: *const WiGrayImage,
+    g: *const WiGrayImage,
+    b: *const WiGrayImage,
+    a: *const WiGrayImage,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if r.is_null() || g.is_null() || b.is_null() {
+            return null_argument("r, g or b", std::ptr::null_mut());
+        }
+        let go = || -> Result<DynamicImage, WiError> {
+            let rgb = [&(&*r).0, &(&*g).0, &(&*b).0];
+            let [r, g, b] = match rgb {
+                [Ok(r), Ok(g), Ok(b)] => [r, g, b],
+                [Err(x), _, _] | [_, Err(x), _] | [_, _, Err(x)] => return Err(x.clone()),
+            };
+            let a = match a.is_null() {
+                true => None,
+                false => Some((&*a).0.as_ref().map_err(|x| x.clone())?),
+            };
+            merge_channels([r, g, b], a)
+        };
+        let result = Box::new(WiImage(go(), ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE - METADATA
+///////////////////////////////////////////////////////////////////////////////
+
+/// The EXIF `Orientation` tag.
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+
+/// TIFF tags describing the pixel data of a TIFF file rather than the photo,
+/// left out when its IFD0 is carried over as EXIF data.
+const TIFF_STRUCTURE_TAGS: [u16; 20] = [
+    254, 256, 257, 258, 259, 262, 266, 273, 277, 278,
+    279, 284, 317, 320, 322, 323, 324, 325, 338, 339,
+];
+
+/// The TIFF tags holding an XMP packet, IPTC-IIM datasets and an ICC profile.
+const TIFF_XMP_TAG: u16 = 700;
+const TIFF_IPTC_TAG: u16 = 33723;
+const TIFF_ICC_TAG: u16 = 34675;
+
+/// The EXIF fields with text values, which can be set with `wi_img_metadata_set`.
+const EXIF_TEXT_TAGS: [exif::Tag; 18] = [
+    exif::Tag::ImageDescription,
+    exif::Tag::Make,
+    exif::Tag::Model,
+    exif::Tag::Software,
+    exif::Tag::DateTime,
+    exif::Tag::Artist,
+    exif::Tag::Copyright,
+    exif::Tag::DateTimeOriginal,
+    exif::Tag::DateTimeDigitized,
+    exif::Tag::OffsetTime,
+    exif::Tag::OffsetTimeOriginal,
+    exif::Tag::OffsetTimeDigitized,
+    exif::Tag::ImageUniqueID,
+    exif::Tag::CameraOwnerName,
+    exif::Tag::BodySerialNumber,
+    exif::Tag::LensMake,
+    exif::Tag::LensModel,
+    exif::Tag::LensSerialNumber,
+];
+
+/// The names of the IPTC-IIM application record (2) datasets.
+const IPTC_DATASETS: &[(u8, &str)] = &[
+    (5, "ObjectName"),
+    (7, "EditStatus"),
+    (10, "Urgency"),
+    (12, "SubjectReference"),
+    (15, "Category"),
+    (20, "SuppCategory"),
+    (22, "FixtureId"),
+    (25, "Keywords"),
+    (26, "LocationCode"),
+    (27, "LocationName"),
+    (30, "ReleaseDate"),
+    (35, "ReleaseTime"),
+    (37, "ExpirationDate"),
+    (38, "ExpirationTime"),
+    (40, "SpecialInstructions"),
+    (45, "ReferenceService"),
+    (47, "ReferenceDate"),
+    (50, "ReferenceNumber"),
+    (55, "DateCreated"),
+    (60, "TimeCreated"),
+    (62, "DigitizationDate"),
+    (63, "DigitizationTime"),
+    (65, "Program"),
+    (70, "ProgramVersion"),
+    (75, "ObjectCycle"),
+    (80, "Byline"),
+    (85, "BylineTitle"),
+    (90, "City"),
+    (92, "SubLocation"),
+    (95, "ProvinceState"),
+    (100, "CountryCode"),
+    (101, "CountryName"),
+    (103, "TransmissionReference"),
+    (105, "Headline"),
+    (110, "Credit"),
+    (115, "Source"),
+    (116, "Copyright"),
+    (118, "Contact"),
+    (120, "Caption"),
+    (122, "Writer"),
+    (130, "ImageType"),
+    (131, "ImageOrientation"),
+    (135, "Language"),
+];
+
+/// The namespaces of the XMP prefixes that `wi_img_metadata_set` can declare.
+const XMP_NAMESPACES: [(&str, &str); 11] = [
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+    ("xmp", "http://ns.adobe.com/xap/1.0/"),
+    ("xmpRights", "http://ns.adobe.com/xap/1.0/rights/"),
+    ("xmpMM", "http://ns.adobe.com/xap/1.0/mm/"),
+    ("photoshop", "http://ns.adobe.com/photoshop/1.0/"),
+    ("exif", "http://ns.adobe.com/exif/1.0/"),
+    ("exifEX", "http://cipa.jp/exif/1.0/"),
+    ("tiff", "http://ns.adobe.com/tiff/1.0/"),
+    ("aux", "http://ns.adobe.com/exif/1.0/aux/"),
+    ("Iptc4xmpCore", "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/"),
+    ("xmpNote", "http://ns.adobe.com/xmp/note/"),
+];
+
+/// An XMP packet holding a single empty description, for images without one.
+const XMP_TEMPLATE: &str = concat!(
+    "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+    "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+    " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+    "  <rdf:Description rdf:about=\"\"/>\n",
+    " </rdf:RDF>\n",
+    "</x:xmpmeta>\n",
+    "<?xpacket end=\"w\"?>",
+);
+
+const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const JPEG_XMP_EXTENSION_HEADER: &[u8] = b"http://ns.adobe.com/xmp/extension/\0";
+/// The most extended XMP per segment, after the header, GUID, length and offset.
+const JPEG_XMP_EXTENSION_CHUNK: usize = 65533 - 35 - 32 - 8;
+const JPEG_PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+/// How much of a stream is read at most to find its metadata.
+const METADATA_PREFIX_LIMIT: usize = 16 << 20;
+
+impl ImageMetadata {
+    fn new(exif: Option<Vec<u8>>, xmp: Option<String>, iptc: Option<Vec<u8>>) -> ImageMetadata {
+        let mut metadata = ImageMetadata {exif, xmp, iptc, icc: None, entries: Vec::new(), bgr: false};
+        metadata.refresh();
+        metadata
+    }
+    /// Extract the metadata of an encoded file. Containers without (or with
+    /// malformed) metadata simply yield nothing, since it is never needed to
+    /// decode the pixels. `limits` bounds the size of the ICC profile.
+    fn read(encoded: &[u8], limits: &WiDecodeLimits) -> ImageMetadata {
+        let exif = exif::Reader::new()
+            .read_from_container(&mut std::io::Cursor::new(encoded))
+            .ok()
+            .map(|x| x.buf().to_vec());
+        let mut metadata = if encoded.starts_with(b"II*\0") || encoded.starts_with(b"MM\0*") {
+            exif.map(|x| ImageMetadata::from_tiff(&x)).unwrap_or_default()
+        } else if encoded.starts_with(&[0xFF, 0xD8]) {
+            let (xmp, iptc) = read_jpeg_metadata(encoded);
+            ImageMetadata::new(exif, xmp, iptc)
+        } else if encoded.starts_with(PNG_SIGNATURE) {
+            ImageMetadata::new(exif, read_png_xmp(encoded), None)
+        } else {
+            ImageMetadata::new(exif, None, None)
+        };
+        metadata.icc = read_icc_profile(encoded, limits);
+        metadata
+    }
+    /// Split the IFD0 of a TIFF file into EXIF, XMP and IPTC data, leaving out
+    /// the fields that only describe how its pixels are stored.
+    fn from_tiff(tiff: &[u8]) -> ImageMetadata {
+        let parsed = match exif::Reader::new().read_raw(tiff.to_vec()) {
+            Ok(x) => x,
+            Err(_) => return ImageMetadata::default(),
+        };
+        let block = |number: u16| {
+            let tag = exif::Tag(exif::Context::Tiff, number);
+            let field = parsed.get_field(tag, exif::In::PRIMARY)?;
+            exif_value_bytes(&field.value, parsed.little_endian())
+        };
+        let xmp = block(TIFF_XMP_TAG).map(|x| String::from_utf8_lossy(&x).into_owned());
+        let iptc = block(TIFF_IPTC_TAG);
+        let fields = exif_fields(&parsed)
+            .filter(|x| {
+                let skipped = TIFF_STRUCTURE_TAGS.contains(&x.tag.number())
+                    || [TIFF_XMP_TAG, TIFF_IPTC_TAG, TIFF_ICC_TAG].contains(&x.tag.number());
+                x.tag.context() != exif::Context::Tiff || !skipped
+            })
+            .collect::<Vec<_>>();
+        let exif = write_exif(&fields, parsed.little_endian()).ok().flatten();
+        ImageMetadata::new(exif, xmp, iptc)
+    }
+    /// Whether there is nothing to embed in an encoded file.
+    fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none() && self.iptc.is_none() && self.icc.is_none()
+    }
+    /// Rebuild the key/value entries after the metadata has changed.
+    fn refresh(&mut self) {
+        let mut entries = Vec::new();
+        if let Some(exif) = self.parsed_exif() {
+            for field in exif_fields(&exif) {
+                entries.push((exif_key(field.tag), exif_value(field, &exif)));
+            }
+        }
+        if let Some(iptc) = &self.iptc {
+            for (record, dataset, value) in parse_iim(iptc) {
+                if record == 2 && dataset != 0 {
+                    entries.push((iptc_key(dataset), iptc_value(&value)));
+                }
+            }
+        }
+        if let Some(packet) = self.xmp.as_deref().and_then(parse_xmp) {
+            for property in packet.properties {
+                let key = format!("Xmp.{}", property.name.replacen(':', ".", 1));
+                for value in property.values {
+                    entries.push((key.clone(), value));
+                }
+            }
+        }
+        self.entries = entries
+            .into_iter()
+            .map(|(key, value)| (c_string_lossy(key), c_string_lossy(value)))
+            .collect();
+    }
+    fn parsed_exif(&self) -> Option<exif::Exif> {
+        exif::Reader::new().read_raw(self.exif.clone()?).ok()
+    }
+    /// The EXIF orientation (1 to 8), if present and valid.
+    fn orientation(&self) -> Option<u32> {
+        let exif = self.parsed_exif()?;
+        let field = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+        field.value.get_uint(0).filter(|x| (1..=8).contains(x))
+    }
+    /// Rewrite the orientation tag of the EXIF block in place, leaving every
+    /// other field untouched. Does nothing if the tag is absent.
+    fn set_orientation(&mut self, orientation: u16) {
+        if let Some(buf) = self.exif.as_mut() {
+            set_exif_orientation(buf, orientation);
+            self.refresh();
+        }
+    }
+    /// A copy for an image whose pixels were rotated or flipped, which makes
+    /// the orientation of the source meaningless.
+    fn reoriented(&self) -> ImageMetadata {
+        let mut metadata = self.clone();
+        metadata.set_orientation(1);
+        metadata
+    }
+    /// Remove the entries matching `pattern` (see `wi_img_metadata_remove`),
+    /// returning how many were removed.
+    fn remove(&mut self, pattern: &str) -> Result<usize, WiError> {
+        let before = self.entries.len();
+        if covers_family(pattern, "Exif.") {
+            self.exif = None;
+        } else if let Some(exif) = self.parsed_exif() {
+            let fields = exif_fields(&exif)
+                .filter(|x| !key_matches(pattern, &exif_key(x.tag)))
+                .collect::<Vec<_>>();
+            if fields.len() != exif_fields(&exif).count() {
+                self.exif = write_exif(&fields, exif.little_endian())?;
+            }
+        }
+        if covers_family(pattern, "Iptc.") {
+            self.iptc = None;
+        } else if let Some(iptc) = &self.iptc {
+            let datasets = parse_iim(iptc)
+                .into_iter()
+                .filter(|(record, dataset, _)| {
+                    *record != 2 || *dataset == 0 || !key_matches(pattern, &iptc_key(*dataset))
+                })
+                .collect::<Vec<_>>();
+            let listed = datasets.iter().any(|(record, dataset, _)| *record == 2 && *dataset != 0);
+            self.iptc = listed.then(|| write_iim(&datasets));
+        }
+        if covers_family(pattern, "Xmp.") {
+            self.xmp = None;
+        } else if let Some(xmp) = &self.xmp {
+            self.xmp = Some(xmp_remove(xmp, |name| {
+                key_matches(pattern, &format!("Xmp.{}", name.replacen(':', ".", 1)))
+            }));
+        }
+        self.refresh();
+        Ok(before - self.entries.len())
+    }
+    /// Set a text entry (see `wi_img_metadata_set`), replacing all entries with the same key.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), WiError> {
+        let invalid_key = || {
+            WiError::new(WiErrorCode::InvalidArgument, format!("unsupported metadata key: {}", key))
+        };
+        if let Some(name) = key.strip_prefix("Exif.") {
+            let tag = EXIF_TEXT_TAGS
+                .iter()
+                .find(|x| x.to_string() == name)
+                .ok_or_else(invalid_key)?;
+            let field = exif::Field {
+                tag: *tag,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Ascii(vec![value.as_bytes().to_vec()]),
+            };
+            let exif = self.parsed_exif();
+            let mut fields = exif
+                .iter()
+                .flat_map(exif_fields)
+                .filter(|x| x.tag != *tag)
+                .collect::<Vec<_>>();
+            fields.push(&field);
+            let little_endian = exif.as_ref().map(|x| x.little_endian()).unwrap_or(true);
+            self.exif = write_exif(&fields, little_endian)?;
+        } else if let Some(name) = key.strip_prefix("Iptc.") {
+            let dataset = IPTC_DATASETS
+                .iter()
+                .find(|x| x.1 == name)
+                .map(|x| x.0)
+                .or_else(|| name.parse().ok().filter(|x| *x != 0))
+                .ok_or_else(invalid_key)?;
+            if value.len() > 0x7FFF {
+                let message = format!("IPTC values are limited to 32767 bytes, got {}", value.len());
+                return Err(WiError::new(WiErrorCode::InvalidArgument, message));
+            }
+            let mut datasets = match &self.iptc {
+                Some(iptc) => parse_iim(iptc),
+                // A new block declares UTF-8 (1:90) and the record version (2:0).
+                None => vec![(1, 90, b"\x1b%G".to_vec()), (2, 0, vec![0, 4])],
+            };
+            datasets.retain(|x| (x.0, x.1) != (2, dataset));
+            datasets.push((2, dataset, value.as_bytes().to_vec()));
+            datasets.sort_by_key(|x| x.0);
+            self.iptc = Some(write_iim(&datasets));
+        } else if let Some(name) = key.strip_prefix("Xmp.") {
+            let (prefix, local) = name.split_once('.').ok_or_else(invalid_key)?;
+            let is_name = |x: &str| {
+                x.chars().next().is_some_and(|x| x.is_alphabetic() || x == '_')
+                    && x.chars().all(|x| x.is_alphanumeric() || x == '_' || x == '-')
+            };
+            if !is_name(prefix) || !is_name(local) {
+                return Err(invalid_key());
+            }
+            let packet = self.xmp.as_deref().unwrap_or(XMP_TEMPLATE);
+            self.xmp = Some(xmp_set(packet, &format!("{}:{}", prefix, local), value)?);
+        } else {
+            return Err(invalid_key());
+        }
+        self.refresh();
+        Ok(())
+    }
+}
+
+/// Whether a metadata key matches a pattern, which may end with a `*` wildcard.
+fn key_matches(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == pattern,
+    }
+}
+
+/// Whether a pattern matches every key of a family (such as `"Exif."`).
+fn covers_family(pattern: &str, family: &str) -> bool {
+    pattern.strip_suffix('*').is_some_and(|x| family.starts_with(x))
+}
+
+fn c_string_lossy(text: String) -> CString {
+    CString::new(text.replace('\0', "")).unwrap_or_default()
+}
+
+/// The fields of the main image, without the pointers to its sub-IFDs.
+fn exif_fields(exif: &exif::Exif) -> impl Iterator<Item = &exif::Field> {
+    let pointers = [
+        exif::Tag::ExifIFDPointer,
+        exif::Tag::GPSInfoIFDPointer,
+        exif::Tag::InteropIFDPointer,
+    ];
+    exif.fields()
+        .filter(|x| x.ifd_num == exif::In::PRIMARY)
+        .filter(move |x| !pointers.contains(&x.tag))
+        .filter(|x| !matches!(x.value, exif::Value::Unknown(..)))
+}
+
+/// Serialize EXIF fields as TIFF structured data, or nothing if there are none.
+///
+/// Only the main image is written, so thumbnails are dropped.
+fn write_exif(fields: &[&exif::Field], little_endian: bool) -> Result<Option<Vec<u8>>, WiError> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut output = std::io::Cursor::new(Vec::new());
+    writer.write(&mut output, little_endian).map_err(|x| {
+        WiError::new(WiErrorCode::InvalidArgument, format!("failed to write EXIF data: {}", x))
+    })?;
+    Ok(Some(output.into_inner()))
+}
+
+fn exif_key(tag: exif::Tag) -> String {
+    match tag.description() {
+        Some(_) => format!("Exif.{}", tag),
+        None => format!("Exif.{:?}.{:#06x}", tag.context(), tag.number()),
+    }
+}
+
+fn exif_value(field: &exif::Field, exif: &exif::Exif) -> String {
+    match &field.value {
+        exif::Value::Ascii(x) => x
+            .iter()
+            .map(|x| String::from_utf8_lossy(x).trim_end_matches('\0').to_owned())
+            .collect::<Vec<_>>()
+            .join(", "),
+        _ => field.display_value().with_unit(exif).to_string(),
+    }
+}
+
+/// The bytes of an embedded block, stored as bytes or (by Photoshop) as longs.
+fn exif_value_bytes(value: &exif::Value, little_endian: bool) -> Option<Vec<u8>> {
+    match value {
+        exif::Value::Byte(x) | exif::Value::Undefined(x, _) => Some(x.clone()),
+        exif::Value::Long(x) if little_endian => Some(x.iter().flat_map(|x| x.to_le_bytes()).collect()),
+        exif::Value::Long(x) => Some(x.iter().flat_map(|x| x.to_be_bytes()).collect()),
+        _ => None,
+    }
+}
+
+fn iptc_key(dataset: u8) -> String {
+    match IPTC_DATASETS.iter().find(|x| x.0 == dataset) {
+        Some((_, name)) => format!("Iptc.{}", name),
+        None => format!("Iptc.{}", dataset),
+    }
+}
+
+/// Decode an IPTC value, as UTF-8 or else as Latin-1 (the common legacy charset).
+fn iptc_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(x) => x.to_owned(),
+        Err(_) => value.iter().map(|x| *x as char).collect(),
+    }
+}
+
+/// Split IPTC-IIM data into its datasets, as `(record, dataset, value)`.
+fn parse_iim(data: &[u8]) -> Vec<(u8, u8, Vec<u8>)> {
+    let mut datasets = Vec::new();
+    let mut pos = 0;
+    while pos + 5 <= data.len() && data[pos] == 0x1C {
+        let len = u16::from_be_bytes([data[pos + 3], data[pos + 4]]) as usize;
+        // Extended (over 32767 bytes) datasets aren't used for text.
+        if len & 0x8000 != 0 || pos + 5 + len > data.len() {
+            break;
+        }
+        datasets.push((data[pos + 1], data[pos + 2], data[pos + 5..pos + 5 + len].to_vec()));
+        pos += 5 + len;
+    }
+    datasets
+}
+
+fn write_iim(datasets: &[(u8, u8, Vec<u8>)]) -> Vec<u8> {
+    let mut output = Vec::new();
+    for (record, dataset, value) in datasets {
+        output.extend_from_slice(&[0x1C, *record, *dataset]);
+        output.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        output.extend_from_slice(value);
+    }
+    output
+}
+
+/// The APPn segments of a JPEG file as `(marker, payload)`, up to the image data.
+fn jpeg_app_segments(data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if len < 2 || pos + 2 + len > data.len() {
+            break;
+        }
+        if (0xE0..=0xEF).contains(&marker) {
+            segments.push((marker, &data[pos + 4..pos + 2 + len]));
+        }
+        pos += 2 + len;
+    }
+    segments
+}
+
+/// The XMP packet (APP1) and IPTC-IIM data (APP13) of a JPEG file.
+fn read_jpeg_metadata(data: &[u8]) -> (Option<String>, Option<Vec<u8>>) {
+    let mut xmp = None;
+    let mut extension = Vec::new();
+    let mut iptc = None;
+    for (marker, payload) in jpeg_app_segments(data) {
+        match marker {
+            0xE1 if payload.starts_with(JPEG_XMP_EXTENSION_HEADER) => {
+                extension.push(&payload[JPEG_XMP_EXTENSION_HEADER.len()..]);
+            }
+            0xE1 if xmp.is_none() => {
+                xmp = payload
+                    .strip_prefix(JPEG_XMP_HEADER)
+                    .map(|x| String::from_utf8_lossy(x).into_owned());
+            }
+            0xED if iptc.is_none() => {
+                iptc = payload
+                    .strip_prefix(JPEG_PHOTOSHOP_HEADER)
+                    .and_then(photoshop_iptc)
+                    .map(|x| x.to_vec());
+            }
+            _ => {}
+        }
+    }
+    (xmp.map(|x| merge_extended_xmp(x, &extension)), iptc)
+}
+
+/// Merge the extended XMP of a JPEG file into its main packet. `chunks` are
+/// the extension segments after their header, and the main packet is kept as
+/// is if the one it points to is missing or incomplete.
+fn merge_extended_xmp(main: String, chunks: &[&[u8]]) -> String {
+    let guid = parse_xmp(&main)
+        .and_then(|x| x.properties.into_iter().find(|x| x.name == "xmpNote:HasExtendedXMP"))
+        .and_then(|x| x.values.into_iter().next());
+    let guid = match guid {
+        Some(x) => x,
+        None => return main,
+    };
+    let mut parts = chunks
+        .iter()
+        .filter(|x| x.len() > 40 && x[..32] == *guid.as_bytes())
+        .map(|x| {
+            let total = u32::from_be_bytes([x[32], x[33], x[34], x[35]]) as usize;
+            let offset = u32::from_be_bytes([x[36], x[37], x[38], x[39]]) as usize;
+            (offset, total, &x[40..])
+        })
+        .collect::<Vec<_>>();
+    parts.sort_by_key(|x| x.0);
+    let mut extended = Vec::new();
+    for (offset, total, data) in &parts {
+        if *offset != extended.len() || *total != parts[0].1 {
+            return main;
+        }
+        extended.extend_from_slice(data);
+    }
+    let extended = match String::from_utf8(extended) {
+        Ok(x) if parts.first().map(|x| x.1) == Some(x.len()) => x,
+        _ => return main,
+    };
+    // Add the descriptions of the extended XMP to the properties left in the
+    // main packet, if there are any.
+    let mut main = xmp_remove(&main, |x| x == "xmpNote:HasExtendedXMP");
+    if parse_xmp(&main).is_none_or(|x| x.properties.is_empty()) {
+        return extended;
+    }
+    match (xmp_rdf_content(&extended), xmp_rdf_content(&main)) {
+        (Some(descriptions), Some(end)) => main.insert_str(end.end, &extended[descriptions]),
+        _ => return main,
+    }
+    main
+}
+
+/// The span of the contents of the `rdf:RDF` element of an XMP packet.
+fn xmp_rdf_content(packet: &str) -> Option<std::ops::Range<usize>> {
+    let tags = scan_xml(packet)?;
+    let start = tags.iter().position(|x| x.name == "rdf:RDF" && !x.closing && !x.empty)?;
+    let end = xml_matching_end(&tags, start)?;
+    Some(tags[start].span.end..tags[end].span.start)
+}
+
+/// Find the IPTC-IIM resource (0x0404) among Photoshop image resource blocks.
+fn photoshop_iptc(mut data: &[u8]) -> Option<&[u8]> {
+    while data.len() >= 12 && data.starts_with(b"8BIM") {
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        // The name is a Pascal string, padded to an even length.
+        let name_end = 6 + ((data[6] as usize + 2) & !1);
+        let size = data.get(name_end..name_end + 4)?;
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let start = name_end + 4;
+        let block = data.get(start..start + size)?;
+        if id == 0x0404 {
+            return Some(block);
+        }
+        data = data.get(start + ((size + 1) & !1)..)?;
+    }
+    None
+}
+
+/// The chunks of a PNG file as `(type, data)`.
+fn png_chunks(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 12 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        if pos + 12 + len > data.len() {
+            break;
+        }
+        chunks.push((&data[pos + 4..pos + 8], &data[pos + 8..pos + 8 + len]));
+        pos += 12 + len;
+    }
+    chunks
+}
+
+/// The XMP packet of a PNG file, stored in an uncompressed `iTXt` chunk.
+fn read_png_xmp(data: &[u8]) -> Option<String> {
+    png_chunks(data).into_iter().find_map(|(kind, body)| {
+        let text = body.strip_prefix(PNG_XMP_KEYWORD)?.strip_prefix(b"\0")?;
+        // Skip the compression flag and method, then the language and translated keyword.
+        if kind != b"iTXt" || text.first()? != &0 {
+            return None;
+        }
+        let text = text.get(2..)?;
+        let text = &text[text.iter().position(|x| *x == 0)? + 1..];
+        let text = &text[text.iter().position(|x| *x == 0)? + 1..];
+        Some(String::from_utf8_lossy(text).into_owned())
+    })
+}
+
+/// The leading part of a JPEG or PNG stream that holds its metadata: the
+/// segments before the image data of a JPEG file, and the chunks before the
+/// first `IDAT` chunk of a PNG file. Empty for other formats, whose metadata
+/// may be anywhere in the file.
+///
+/// The file starts at `start` in the stream, which is left where it was.
+fn metadata_prefix<R: std::io::Read + std::io::Seek>(reader: &mut R, start: u64) -> std::io::Result<Vec<u8>> {
+    let position = reader.stream_position()?;
+    reader.seek(std::io::SeekFrom::Start(start))?;
+    let prefix = read_metadata_prefix(reader);
+    reader.seek(std::io::SeekFrom::Start(position))?;
+    prefix
+}
+
+fn read_metadata_prefix<R: std::io::Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut prefix = Vec::new();
+    // Read up to `len` bytes in total, returning false at the end of the stream.
+    let mut fill = |prefix: &mut Vec<u8>, len: usize| -> std::io::Result<bool> {
+        if len > METADATA_PREFIX_LIMIT {
+            return Ok(false);
+        }
+        let missing = len.saturating_sub(prefix.len()) as u64;
+        reader.by_ref().take(missing).read_to_end(prefix)?;
+        Ok(prefix.len() >= len)
+    };
+    if !fill(&mut prefix, PNG_SIGNATURE.len())? {
+        return Ok(Vec::new());
+    }
+    if prefix.starts_with(&[0xFF, 0xD8]) {
+        let mut pos = 2;
+        while fill(&mut prefix, pos + 4)? && prefix[pos] == 0xFF && prefix[pos + 1] != 0xD9 {
+            let marker = prefix[pos + 1];
+            let len = u16::from_be_bytes([prefix[pos + 2], prefix[pos + 3]]) as usize;
+            // The decoder needs the start of scan header to read the others.
+            if !fill(&mut prefix, pos + 2 + len)? || marker == 0xDA {
+                break;
+            }
+            pos += 2 + len;
+        }
+    } else if prefix.starts_with(PNG_SIGNATURE) {
+        let mut pos = PNG_SIGNATURE.len();
+        // The decoder needs the header of the first `IDAT` chunk to read the others.
+        while fill(&mut prefix, pos + 8)? && &prefix[pos + 4..pos + 8] != b"IDAT" {
+            let len = u32::from_be_bytes([prefix[pos], prefix[pos + 1], prefix[pos + 2], prefix[pos + 3]]) as usize;
+            if !fill(&mut prefix, pos + 12 + len)? || &prefix[pos + 4..pos + 8] == b"IEND" {
+                break;
+            }
+            pos += 12 + len;
+        }
+    } else {
+        prefix.clear();
+    }
+    Ok(prefix)
+}
+
+fn png_chunk(kind: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let len: usize = parts.iter().map(|x| x.len()).sum();
+    let mut chunk = (len as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    for part in parts {
+        chunk.extend_from_slice(part);
+    }
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&chunk[4..]);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+    chunk
+}
+
+fn jpeg_segment(marker: u8, parts: &[&[u8]]) -> Result<Vec<u8>, WiError> {
+    let len = 2 + parts.iter().map(|x| x.len()).sum::<usize>();
+    if len > u16::MAX as usize {
+        let message = format!("{} bytes of metadata don't fit in a JPEG segment", len);
+        return Err(WiError::new(WiErrorCode::EncodeFailed, message));
+    }
+    let mut segment = vec![0xFF, marker];
+    segment.extend_from_slice(&(len as u16).to_be_bytes());
+    for part in parts {
+        segment.extend_from_slice(part);
+    }
+    Ok(segment)
+}
+
+/// The APP1 segments of an XMP packet. A packet too large for one segment is
+/// written as extended XMP, split over as many segments as it takes, after a
+/// main packet that only holds its GUID (the MD5 digest of the packet).
+fn jpeg_xmp_segments(xmp: &str) -> Result<Vec<u8>, WiError> {
+    use std::convert::TryFrom;
+    if let Ok(segment) = jpeg_segment(0xE1, &[JPEG_XMP_HEADER, xmp.as_bytes()]) {
+        return Ok(segment);
+    }
+    let total = u32::try_from(xmp.len()).map_err(|_| {
+        WiError::new(WiErrorCode::EncodeFailed, "the XMP packet is too large for extended XMP")
+    })?;
+    let guid = md5(xmp.as_bytes()).iter().map(|x| format!("{:02X}", x)).collect::<String>();
+    let main = xmp_set(XMP_TEMPLATE, "xmpNote:HasExtendedXMP", &guid)?;
+    let mut segments = jpeg_segment(0xE1, &[JPEG_XMP_HEADER, main.as_bytes()])?;
+    for (index, chunk) in xmp.as_bytes().chunks(JPEG_XMP_EXTENSION_CHUNK).enumerate() {
+        let offset = (index * JPEG_XMP_EXTENSION_CHUNK) as u32;
+        let parts = [
+            JPEG_XMP_EXTENSION_HEADER,
+            guid.as_bytes(),
+            &total.to_be_bytes(),
+            &offset.to_be_bytes(),
+            chunk,
+        ];
+        segments.extend(jpeg_segment(0xE1, &parts)?);
+    }
+    Ok(segments)
+}
+
+/// The MD5 digest of `data`, which extended XMP uses as its GUID.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let table: Vec<u32> = (0..64).map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32).collect();
+    let mut message = data.to_vec();
+    message.push(0x80);
+    message.resize((message.len() + 8).div_ceil(64) * 64 - 8, 0);
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks_exact(64) {
+        let words: Vec<u32> = block
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let sum = a.wrapping_add(f).wrapping_add(table[i]).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(sum.rotate_left(SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (x, y) in state.iter_mut().zip([a, b, c, d]) {
+            *x = x.wrapping_add(y);
+        }
+    }
+    let mut digest = [0; 16];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state.iter()) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+/// Insert the metadata into an encoded JPEG file, after its JFIF header.
+fn embed_jpeg_metadata(mut jpeg: Vec<u8>, metadata: &ImageMetadata) -> Result<Vec<u8>, WiError> {
+    let mut segments = Vec::new();
+    // EXIF and IPTC blocks that don't fit in a segment are left out rather
+    // than failing the encode, XMP moves to extended XMP instead.
+    if let Some(exif) = &metadata.exif {
+        segments.extend(jpeg_segment(0xE1, &[JPEG_EXIF_HEADER, exif]).unwrap_or_default());
+    }
+    if let Some(icc) = &metadata.icc {
+        segments.extend(jpeg_icc_segments(icc)?);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        segments.extend(jpeg_xmp_segments(xmp).unwrap_or_default());
+    }
+    if let Some(iptc) = &metadata.iptc {
+        let padding: &[u8] = if iptc.len() % 2 == 1 {&[0]} else {&[]};
+        let size = (iptc.len() as u32).to_be_bytes();
+        let parts = [JPEG_PHOTOSHOP_HEADER, b"8BIM\x04\x04\0\0", &size, iptc, padding];
+        segments.extend(jpeg_segment(0xED, &parts).unwrap_or_default());
+    }
+    let mut pos = 2;
+    if let Some((0xE0, payload)) = jpeg_app_segments(&jpeg).first() {
+        pos += 4 + payload.len();
+    }
+    jpeg.splice(pos..pos, segments);
+    Ok(jpeg)
+}
+
+/// Insert the metadata into an encoded PNG file, after its header chunk.
+///
+/// PNG has no standard place for IPTC-IIM data, so it's left out.
+fn embed_png_metadata(mut png: Vec<u8>, metadata: &ImageMetadata) -> Result<Vec<u8>, WiError> {
+    let mut chunks = Vec::new();
+    if let Some(icc) = &metadata.icc {
+        chunks.extend(png_icc_chunk(icc)?);
+    }
+    if let Some(exif) = &metadata.exif {
+        chunks.extend(png_chunk(b"eXIf", &[exif]));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        // No compression, language or translated keyword.
+        chunks.extend(png_chunk(b"iTXt", &[PNG_XMP_KEYWORD, b"\0\0\0\0\0", xmp.as_bytes()]));
+    }
+    // IHDR always comes first, with 13 bytes of data.
+    let pos = PNG_SIGNATURE.len() + 12 + 13;
+    png.splice(pos..pos, chunks);
+    Ok(png)
+}
+
+/// An XML tag, as scanned by `scan_xml`.
+struct XmlTag<'a> {
+    span: std::ops::Range<usize>,
+    name: &'a str,
+    closing: bool,
+    empty: bool,
+    /// The position of the closing `>` or `/>`.
+    attrs_end: usize,
+    /// Names, (escaped) values and spans of the attributes, with leading whitespace.
+    attrs: Vec<(&'a str, &'a str, std::ops::Range<usize>)>,
+}
+
+/// The part of `text` that `bytes` borrows, if any.
+fn xml_slice<'a>(text: &'a str, bytes: &[u8]) -> Option<&'a str> {
+    let start = (bytes.as_ptr() as usize).checked_sub(text.as_ptr() as usize)?;
+    text.get(start..start + bytes.len())
+}
+
+/// Scan the tags of an XML document, skipping declarations, comments and CDATA.
+///
+/// This is just enough XML for the RDF of XMP packets, and returns nothing
+/// on malformed input.
+fn scan_xml(text: &str) -> Option<Vec<XmlTag<'_>>> {
+    use quick_xml::events::Event;
+    let mut reader = quick_xml::Reader::from_str(text);
+    let mut tags = Vec::new();
+    loop {
+        let start = reader.buffer_position();
+        let event = reader.read_event().ok()?;
+        let end = reader.buffer_position();
+        let (tag, closing, empty) = match &event {
+            Event::Start(tag) => (tag, false, false),
+            Event::Empty(tag) => (tag, false, true),
+            Event::End(tag) => {
+                let name = xml_slice(text, tag.name().into_inner())?;
+                let attrs = Vec::new();
+                tags.push(XmlTag {span: start..end, name, closing: true, empty: false, attrs_end: end - 1, attrs});
+                continue;
+            }
+            Event::Eof => return Some(tags),
+            _ => continue,
+        };
+        let name = xml_slice(text, tag.name().into_inner())?;
+        let mut attrs = Vec::new();
+        for attr in tag.attributes() {
+            let attr = attr.ok()?;
+            let attr_name = xml_slice(text, attr.key.into_inner())?;
+            let value = xml_slice(text, &attr.value)?;
+            let value_end = value.as_ptr() as usize - text.as_ptr() as usize + value.len();
+            let name_start = attr_name.as_ptr() as usize - text.as_ptr() as usize;
+            // Include the leading whitespace and the closing quote.
+            let attr_start = text[..name_start].trim_end().len();
+            attrs.push((attr_name, value, attr_start..value_end + 1));
+        }
+        let attrs_end = end - if empty {2} else {1};
+        tags.push(XmlTag {span: start..end, name, closing, empty, attrs_end, attrs});
+    }
+}
+
+fn xml_unescape(text: &str) -> String {
+    match quick_xml::escape::unescape(text) {
+        Ok(x) => x.into_owned(),
+        Err(_) => text.to_owned(),
+    }
+}
+
+/// The text content of an XML fragment, with CDATA sections kept as is
+/// and comments left out.
+fn xml_text(fragment: &str) -> String {
+    use quick_xml::events::Event;
+    let mut reader = quick_xml::Reader::from_str(fragment);
+    let mut text = String::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Text(x)) => match xml_slice(fragment, &x) {
+                Some(x) => text.push_str(&xml_unescape(x)),
+                None => text.push_str(&String::from_utf8_lossy(&x)),
+            },
+            Ok(Event::CData(x)) => text.push_str(&String::from_utf8_lossy(&x)),
+            Ok(Event::Eof) | Err(_) => return text,
+            Ok(_) => {}
+        }
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    quick_xml::escape::escape(text).into_owned()
+}
+
+/// The properties of an XMP packet, from its top-level `rdf:Description`s.
+struct XmpPacket {
+    properties: Vec<XmpProperty>,
+    /// The end of the attributes of the first description, and whether it's an empty tag.
+    description: Option<(usize, bool)>,
+}
+
+struct XmpProperty {
+    /// The qualified name, such as `dc:creator`.
+    name: String,
+    /// The text value, or the items of an array. Empty for structures.
+    values: Vec<String>,
+    /// The span of the attribute or element in the packet.
+    span: std::ops::Range<usize>,
+}
+
+fn is_xmp_property(name: &str) -> bool {
+    match name.split_once(':') {
+        Some((prefix, _)) => !["xmlns", "xml", "rdf", "x"].contains(&prefix),
+        None => false,
+    }
+}
+
+fn parse_xmp(packet: &str) -> Option<XmpPacket> {
+    let tags = scan_xml(packet)?;
+    let mut properties = Vec::new();
+    let mut description = None;
+    let mut stack = Vec::new();
+    let mut index = 0;
+    while index < tags.len() {
+        let tag = &tags[index];
+        if tag.closing {
+            stack.pop();
+            index += 1;
+            continue;
+        }
+        let top_level = stack.len() >= 2
+            && stack[stack.len() - 1] == "rdf:Description"
+            && stack[stack.len() - 2] == "rdf:RDF";
+        if tag.name == "rdf:Description" && stack.last() == Some(&"rdf:RDF") {
+            description.get_or_insert((tag.attrs_end, tag.empty));
+            for (name, value, span) in &tag.attrs {
+                if is_xmp_property(name) {
+                    let values = vec![xml_unescape(value)];
+                    properties.push(XmpProperty {name: name.to_string(), values, span: span.clone()});
+                }
+            }
+        } else if top_level && is_xmp_property(tag.name) {
+            let end = match tag.empty {
+                true => index,
+                false => xml_matching_end(&tags, index)?,
+            };
+            let values = if tag.empty {
+                tag.attrs
+                    .iter()
+                    .filter(|x| x.0 == "rdf:resource")
+                    .map(|x| xml_unescape(x.1))
+                    .collect()
+            } else if end == index + 1 {
+                vec![xml_text(&packet[tag.span.end..tags[end].span.start])]
+            } else {
+                // The items of an array, skipping any structures.
+                tags[index + 1..end]
+                    .windows(2)
+                    .filter(|x| x[0].name == "rdf:li" && !x[0].closing && !x[0].empty && x[1].closing)
+                    .map(|x| xml_text(&packet[x[0].span.end..x[1].span.start]))
+                    .collect()
+            };
+            let span = tag.span.start..tags[end].span.end;
+            properties.push(XmpProperty {name: tag.name.to_string(), values, span});
+            index = end + 1;
+            continue;
+        }
+        if !tag.empty {
+            stack.push(tag.name);
+        }
+        index += 1;
+    }
+    Some(XmpPacket {properties, description})
+}
+
+/// The index of the closing tag of the element opened at `index`.
+fn xml_matching_end(tags: &[XmlTag], index: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (offset, tag) in tags[index + 1..].iter().enumerate() {
+        if tag.closing && depth == 0 {
+            return Some(index + 1 + offset);
+        } else if tag.closing {
+            depth -= 1;
+        } else if !tag.empty {
+            depth += 1;
+        }
+    }
+    None
+}
+
+/// Remove the properties whose name matches from an XMP packet.
+fn xmp_remove(packet: &str, matches: impl Fn(&str) -> bool) -> String {
+    let mut packet = packet.to_owned();
+    if let Some(parsed) = parse_xmp(&packet) {
+        let mut spans = parsed
+            .properties
+            .into_iter()
+            .filter(|x| matches(&x.name))
+            .map(|x| x.span)
+            .collect::<Vec<_>>();
+        spans.sort_by_key(|x| std::cmp::Reverse(x.start));
+        for span in spans {
+            packet.replace_range(span, "");
+        }
+    }
+    packet
+}
+
+/// The array type of the well-known XMP properties that hold one.
+fn xmp_array_kind(name: &str) -> Option<&'static str> {
+    match name {
+        "dc:title" | "dc:description" | "dc:rights" | "xmpRights:UsageTerms" => Some("rdf:Alt"),
+        "dc:creator" | "dc:date" => Some("rdf:Seq"),
+        "dc:subject" | "dc:contributor" | "dc:publisher" | "dc:language" | "dc:relation"
+        | "dc:type" | "photoshop:SupplementalCategories" => Some("rdf:Bag"),
+        _ => None,
+    }
+}
+
+/// Set a property of an XMP packet, replacing any previous value.
+///
+/// Simple properties are written as attributes of the first description, and
+/// well-known arrays as elements with a single item.
+fn xmp_set(packet: &str, name: &str, value: &str) -> Result<String, WiError> {
+    let malformed = || WiError::new(WiErrorCode::InvalidArgument, "malformed XMP packet");
+    let mut packet = xmp_remove(packet, |x| x == name);
+    let (attrs_end, empty) = parse_xmp(&packet)
+        .and_then(|x| x.description)
+        .ok_or_else(malformed)?;
+    let (prefix, _) = name.split_once(':').ok_or_else(malformed)?;
+    let mut attrs = String::new();
+    if !packet.contains(&format!("xmlns:{}=", prefix)) {
+        let namespace = XMP_NAMESPACES.iter().find(|x| x.0 == prefix).ok_or_else(|| {
+            WiError::new(WiErrorCode::InvalidArgument, format!("unknown XMP namespace prefix: {}", prefix))
+        })?;
+        attrs.push_str(&format!(" xmlns:{}=\"{}\"", prefix, namespace.1));
+    }
+    let element = match xmp_array_kind(name) {
+        None => {
+            attrs.push_str(&format!(" {}=\"{}\"", name, xml_escape(value)));
+            String::new()
+        }
+        Some(kind) => {
+            let lang = if kind == "rdf:Alt" {" xml:lang=\"x-default\""} else {""};
+            format!(
+                "<{name}><{kind}><rdf:li{lang}>{value}</rdf:li></{kind}></{name}>",
+                name = name,
+                kind = kind,
+                lang = lang,
+                value = xml_escape(value),
+            )
+        }
+    };
+    let close = match (empty, element.is_empty()) {
+        (true, true) => "/>".to_owned(),
+        (true, false) => format!(">{}</rdf:Description>", element),
+        (false, _) => format!(">{}", element),
+    };
+    let close_len = if empty {2} else {1};
+    packet.replace_range(attrs_end..attrs_end + close_len, &format!("{}{}", attrs, close));
+    Ok(packet)
+}
+
+/// Patch the orientation entry of IFD0 in a TIFF structured EXIF block.
+fn set_exif_orientation(buf: &mut [u8], orientation: u16) -> Option<()> {
+    let little_endian = match buf.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let read_u16 = |buf: &[u8], at: usize| -> Option<u16> {
+        let bytes = [*buf.get(at)?, *buf.get(at + 1)?];
+        Some(if little_endian {u16::from_le_bytes(bytes)} else {u16::from_be_bytes(bytes)})
+    };
+    let read_u32 = |buf: &[u8], at: usize| -> Option<u32> {
+        let bytes = [*buf.get(at)?, *buf.get(at + 1)?, *buf.get(at + 2)?, *buf.get(at + 3)?];
+        Some(if little_endian {u32::from_le_bytes(bytes)} else {u32::from_be_bytes(bytes)})
+    };
+    let ifd = read_u32(buf, 4)? as usize;
+    let count = read_u16(buf, ifd)? as usize;
+    for index in 0..count {
+        let entry = ifd + 2 + index * 12;
+        // Only a SHORT value fits the spec, and it's stored inline.
+        if read_u16(buf, entry)? == EXIF_ORIENTATION_TAG && read_u16(buf, entry + 2)? == 3 {
+            let bytes = if little_endian {orientation.to_le_bytes()} else {orientation.to_be_bytes()};
+            buf.get_mut(entry + 8..entry + 10)?.copy_from_slice(&bytes);
+            return Some(());
+        }
+    }
+    None
+}
+
+/// Transform an image stored with the given EXIF orientation so that it's upright.
+fn apply_exif_orientation(image: &DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image.clone(),
+    }
+}
+
+/// Apply the EXIF orientation of an image, resetting the tag to 1 (upright)
+/// so that it isn't applied twice by other software.
+fn orient_image(image: WiImage) -> WiImage {
+    match image {
+        WiImage(Ok(x), mut metadata) => match metadata.orientation() {
+            Some(orientation) if orientation != 1 => {
+                let x = apply_exif_orientation(&x, orientation);
+                metadata.set_orientation(1);
+                WiImage(Ok(x), metadata)
+            }
+            _ => WiImage(Ok(x), metadata),
+        },
+        image => image,
+    }
+}
+
+/// The EXIF orientation of the image, as stored by the source file.
+///
+/// Values 1 to 8 follow the EXIF specification (1 being upright, 6 needing a
+/// clockwise quarter turn, ...). Returns 0 if the source has no orientation
+/// tag (or no EXIF data at all), and -1 on error (see `wi_last_error_code`).
+///
+/// EXIF data is read from JPEG, TIFF, PNG and WebP sources.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_exif_orientation(ctx: *const WiImage) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(_) => (&*ctx).1.orientation().map(|x| x as c_int).unwrap_or(0),
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// Rotate and/or flip the image according to its EXIF orientation, so that it's upright.
+///
+/// The orientation of the returned image is reset to 1. Images without an
+/// orientation tag are returned unchanged.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_apply_exif_orientation(ctx: *const WiImage) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let image = WiImage((&*ctx).0.clone(), (&*ctx).1.clone());
+        Box::into_raw(Box::new(orient_image(image)))
+    })
+}
+
+/// Same as `wi_img_open`, but applies the EXIF orientation (see `wi_img_apply_exif_orientation`).
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_open_oriented(path: *const c_char) -> *mut WiImage {
+    catch_panic(|| {
+        wi_img_open_oriented_with_limits(path, std::ptr::null())
+    })
+}
+
+/// Same as `wi_img_open_oriented`, but checks the image against the given
+/// limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_open_oriented_with_limits(
+    path: *const c_char,
+    limits: *const WiDecodeLimits,
+) -> *mut WiImage {
+    catch_panic(|| {
+        let image = wi_img_open_with_limits(path, limits);
+        if image.is_null() {
+            return image;
+        }
+        let image = Box::from_raw(image);
+        Box::into_raw(Box::new(orient_image(*image)))
+    })
+}
+
+/// Same as `wi_img_decode_from_memory_with_format`, but applies the EXIF
+/// orientation (see `wi_img_apply_exif_orientation`).
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_decode_from_memory_oriented(
+    data: *const u8,
+    len: size_t,
+    format: *const c_char,
+) -> *mut WiImage {
+    catch_panic(|| {
+        wi_img_decode_from_memory_oriented_with_limits(data, len, format, std::ptr::null())
+    })
+}
+
+/// Same as `wi_img_decode_from_memory_oriented`, but checks the image against
+/// the given limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_decode_from_memory_oriented_with_limits(
+    data: *const u8,
+    len: size_t,
+    format: *const c_char,
+    limits: *const WiDecodeLimits,
+) -> *mut WiImage {
+    catch_panic(|| {
+        let image = wi_img_decode_from_memory_with_limits(data, len, format, limits);
+        if image.is_null() {
+            return image;
+        }
+        let image = Box::from_raw(image);
+        Box::into_raw(Box::new(orient_image(*image)))
+    })
+}
+
+
+
+/// The number of metadata entries of the image, or -1 on error.
+///
+/// Each entry is a key and a text value, read with `wi_img_metadata_key` and
+/// `wi_img_metadata_value`. Keys are prefixed with their family:
+///
+/// - `Exif.<Tag>` for the EXIF fields of the main image, e.g. `Exif.Make` or `Exif.GPSLatitude`.
+/// - `Iptc.<Dataset>` for the IPTC-IIM application datasets, e.g. `Iptc.Keywords`.
+/// - `Xmp.<prefix>.<name>` for XMP properties, e.g. `Xmp.dc.creator`.
+///
+/// Keys repeat for each item of a list, such as IPTC keywords. Metadata is
+/// read from JPEG, PNG and TIFF sources (and EXIF from WebP).
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_metadata_count(ctx: *const WiImage) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(_) => (&*ctx).1.entries.len() as c_int,
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// Look up the metadata entry at `index`, recording an error if there's none.
+unsafe fn metadata_entry<'a>(ctx: *const WiImage, index: c_int) -> Option<&'a (CString, CString)> {
+    use std::convert::TryFrom;
+    if ctx.is_null() {
+        return null_argument("ctx", None);
+    }
+    match &(&*ctx).0 {
+        Ok(_) => {
+            let entries = &(&*ctx).1.entries;
+            let entry = usize::try_from(index).ok().and_then(|x| entries.get(x));
+            if entry.is_none() {
+                let message = format!("metadata index {} is out of bounds for {} entries", index, entries.len());
+                return WiError::new(WiErrorCode::OutOfBounds, message).report(None);
+            }
+            entry
+        }
+        Err(x) => x.report(None),
+    }
+}
+
+/// The key of the metadata entry at `index`, or NULL on error.
+///
+/// The string stays valid until the metadata of the image is changed, or the
+/// image is passed to `wi_img_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_metadata_key(ctx: *const WiImage, index: c_int) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        metadata_entry(ctx, index).map_or(std::ptr::null(), |x| x.0.as_ptr())
+    })
+}
+
+/// The value of the metadata entry at `index`, or NULL on error.
+///
+/// The string stays valid until the metadata of the image is changed, or the
+/// image is passed to `wi_img_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_metadata_value(ctx: *const WiImage, index: c_int) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        metadata_entry(ctx, index).map_or(std::ptr::null(), |x| x.1.as_ptr())
+    })
+}
+
+/// The value of the first metadata entry with the given key (such as
+/// `"Exif.Copyright"`), or NULL if there's none.
+///
+/// A missing key is not recorded as an error. The string stays valid until
+/// the metadata of the image is changed, or the image is passed to `wi_img_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_metadata_get(ctx: *const WiImage, key: *const c_char) -> *const c_char {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null());
+        }
+        if key.is_null() {
+            return null_argument("key", std::ptr::null());
+        }
+        let key = CStr::from_ptr(key);
+        match &(&*ctx).0 {
+            Ok(_) => (&*ctx).1.entries
+                .iter()
+                .find(|x| x.0.as_c_str() == key)
+                .map_or(std::ptr::null(), |x| x.1.as_ptr()),
+            Err(x) => x.report(std::ptr::null()),
+        }
+    })
+}
+
+/// Remove the metadata entries matching `key`, and return how many were
+/// removed (or -1 on error).
+///
+/// A trailing `*` matches every key with the given prefix, so `"Exif.GPS*"`
+/// and `"Xmp.exif.GPS*"` strip the location of a photo. Patterns covering a
+/// whole family (`"Exif.*"`, `"Iptc.*"`, `"Xmp.*"` or `"*"`) drop it entirely,
+/// including data that isn't listed as entries, such as XMP structures.
+/// The ICC profile isn't metadata in this sense, and is kept even by `"*"`;
+/// remove it with `wi_img_set_icc_profile`.
+///
+/// Editing the EXIF data drops its embedded thumbnail.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_metadata_remove(ctx: *mut WiImage, key: *const c_char) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        if key.is_null() {
+            return null_argument("key", -1);
+        }
+        let ctx = &mut *ctx;
+        let result = match &ctx.0 {
+            Ok(_) => CStr::from_ptr(key)
+                .to_str()
+                .map_err(WiError::from)
+                .and_then(|key| ctx.1.remove(key)),
+            Err(x) => Err(x.clone()),
+        };
+        match result {
+            Ok(removed) => removed as c_int,
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// Set a text metadata entry, replacing all entries with the same key.
+/// Returns 1 on success, and 0 on failure.
+///
+/// Supported keys are the EXIF text fields (`Exif.Artist`, `Exif.Copyright`,
+/// `Exif.ImageDescription`, `Exif.Make`, `Exif.Model`, `Exif.Software`, the
+/// `Exif.DateTime*` fields, ...), the IPTC application datasets (by name, or
+/// number as in `Iptc.25`), and XMP properties whose prefix is declared by the
+/// packet or well-known (dc, xmp, xmpRights, xmpMM, photoshop, exif, exifEX,
+/// tiff, aux and Iptc4xmpCore).
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_metadata_set(ctx: *mut WiImage, key: *const c_char, value: *const c_char) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if key.is_null() {
+            return null_argument("key", 0);
+        }
+        if value.is_null() {
+            return null_argument("value", 0);
+        }
+        let ctx = &mut *ctx;
+        let result = match &ctx.0 {
+            Ok(_) => CStr::from_ptr(key)
+                .to_str()
+                .and_then(|key| Ok((key, CStr::from_ptr(value).to_str()?)))
+                .map_err(WiError::from)
+                .and_then(|(key, value)| ctx.1.set(key, value)),
+            Err(x) => Err(x.clone()),
+        };
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE - ICC-PROFILES
+///////////////////////////////////////////////////////////////////////////////
+
+const JPEG_ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+
+/// The largest part of an ICC profile that fits in a JPEG APP2 segment.
+const JPEG_ICC_CHUNK: usize = u16::MAX as usize - 2 - JPEG_ICC_HEADER.len() - 2;
+
+/// The embedded ICC profile of an encoded file, as found by its decoder.
+/// Profiles larger than the allocation limit are left out.
+fn read_icc_profile(encoded: &[u8], limits: &WiDecodeLimits) -> Option<Vec<u8>> {
+    use image::ImageDecoder;
+    use image::codecs::{jpeg, png, tiff, webp};
+    let cursor = std::io::Cursor::new(encoded);
+    let profile = match image::guess_format(encoded).ok()? {
+        image::ImageFormat::Jpeg => jpeg::JpegDecoder::new(cursor).ok()?.icc_profile(),
+        // The profile of a PNG file is compressed, and the decoder bounds its size.
+        image::ImageFormat::Png => png::PngDecoder::with_limits(cursor, limits.image_limits()).ok()?.icc_profile(),
+        image::ImageFormat::Tiff => {
+            let mut decoder = tiff::TiffDecoder::new(cursor).ok()?;
+            decoder.set_limits(limits.image_limits()).ok()?;
+            decoder.icc_profile()
+        }
+        image::ImageFormat::WebP => webp::WebPDecoder::new(cursor).ok()?.icc_profile(),
+        _ => None,
+    };
+    profile.filter(|x| limits.max_alloc.is_none_or(|max| x.len() as u64 <= max))
+}
+
+/// Check the header of an ICC profile, without interpreting it.
+fn check_icc_profile(profile: &[u8]) -> Result<(), WiError> {
+    let size = profile.get(0..4).map(|x| u32::from_be_bytes([x[0], x[1], x[2], x[3]]) as usize);
+    if profile.len() < 128 || profile.get(36..40) != Some(b"acsp") || size != Some(profile.len()) {
+        return Err(WiError::new(WiErrorCode::InvalidArgument, "not an ICC profile"));
+    }
+    Ok(())
+}
+
+/// The APP2 segments holding an ICC profile, split in numbered chunks.
+fn jpeg_icc_segments(profile: &[u8]) -> Result<Vec<u8>, WiError> {
+    let count = profile.len().div_ceil(JPEG_ICC_CHUNK);
+    if count > u8::MAX as usize {
+        let message = format!("an ICC profile of {} bytes doesn't fit in a JPEG file", profile.len());
+        return Err(WiError::new(WiErrorCode::EncodeFailed, message));
+    }
+    let mut segments = Vec::new();
+    for (index, chunk) in profile.chunks(JPEG_ICC_CHUNK).enumerate() {
+        let numbering = [index as u8 + 1, count as u8];
+        segments.extend(jpeg_segment(0xE2, &[JPEG_ICC_HEADER, &numbering, chunk])?);
+    }
+    Ok(segments)
+}
+
+/// The `iCCP` chunk holding an ICC profile, which is always zlib compressed.
+fn png_icc_chunk(profile: &[u8]) -> Result<Vec<u8>, WiError> {
+    use std::io::Write;
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(profile).map_err(WiError::from)?;
+    let compressed = encoder.finish().map_err(WiError::from)?;
+    Ok(png_chunk(b"iCCP", &[b"ICC Profile\0\0", &compressed]))
+}
+
+/// Map a rendering intent name (case-insensitive) to a qcms intent.
+fn parse_rendering_intent(value: &str) -> Result<qcms::Intent, WiError> {
+    match value.to_lowercase().as_str() {
+        "perceptual" => Ok(qcms::Intent::Perceptual),
+        "relative" | "relative-colorimetric" => Ok(qcms::Intent::RelativeColorimetric),
+        "saturation" => Ok(qcms::Intent::Saturation),
+        "absolute" | "absolute-colorimetric" => Ok(qcms::Intent::AbsoluteColorimetric),
+        other => {
+            let message = format!("unknown rendering intent: {}", other);
+            Err(WiError::new(WiErrorCode::InvalidArgument, message))
+        }
+    }
+}
+
+/// Convert the pixels of an image from an ICC profile to sRGB.
+///
+/// RGB and grayscale profiles are supported, and the result is 8-bit RGB(A).
+fn convert_to_srgb(image: &DynamicImage, profile: &[u8], intent: qcms::Intent) -> Result<DynamicImage, WiError> {
+    use qcms::DataType;
+    let unsupported = || WiError::new(WiErrorCode::UnsupportedFormat, "unsupported ICC profile");
+    let input = qcms::Profile::new_from_slice(profile, false).ok_or_else(unsupported)?;
+    let output = qcms::Profile::new_sRGB();
+    let has_alpha = image.color().has_alpha();
+    let (width, height) = image.dimensions();
+    // The color space signature of the profile header.
+    if profile.get(16..20) == Some(b"GRAY") {
+        let (source, input_type, output_type) = match has_alpha {
+            true => (image.to_luma_alpha8().into_raw(), DataType::GrayA8, DataType::RGBA8),
+            false => (image.to_luma8().into_raw(), DataType::Gray8, DataType::RGB8),
+        };
+        let transform = qcms::Transform::new_to(&input, &output, input_type, output_type, intent)
+            .ok_or_else(unsupported)?;
+        let mut pixels = vec![0; width as usize * height as usize * output_type.bytes_per_pixel()];
+        transform.convert(&source, &mut pixels);
+        return Ok(match has_alpha {
+            true => DynamicImage::ImageRgba8(image::RgbaImage::from_raw(width, height, pixels).ok_or_else(unsupported)?),
+            false => DynamicImage::ImageRgb8(image::RgbImage::from_raw(width, height, pixels).ok_or_else(unsupported)?),
+        });
+    }
+    if has_alpha {
+        let mut pixels = image.to_rgba8();
+        let transform = qcms::Transform::new(&input, &output, DataType::RGBA8, intent).ok_or_else(unsupported)?;
+        transform.apply(&mut pixels);
+        Ok(DynamicImage::ImageRgba8(pixels))
+    } else {
+        let mut pixels = image.to_rgb8();
+        let transform = qcms::Transform::new(&input, &output, DataType::RGB8, intent).ok_or_else(unsupported)?;
+        transform.apply(&mut pixels);
+        Ok(DynamicImage::ImageRgb8(pixels))
+    }
+}
+
+/// Pointer to the embedded ICC profile of the image, or NULL if it has none.
+///
+/// Profiles are read from JPEG, PNG, TIFF and WebP sources. The pointer stays
+/// valid until the profile of the image is changed, or the image is passed to
+/// `wi_img_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_icc_profile_data(ctx: *const WiImage) -> *const u8 {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null());
+        }
+        match &(&*ctx).0 {
+            Ok(_) => (&*ctx).1.icc.as_ref().map_or(std::ptr::null(), |x| x.as_ptr()),
+            Err(x) => x.report(std::ptr::null()),
+        }
+    })
+}
+
+/// Length of the embedded ICC profile of the image in bytes, or 0 if it has none.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_icc_profile_len(ctx: *const WiImage) -> size_t {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        match &(&*ctx).0 {
+            Ok(_) => (&*ctx).1.icc.as_ref().map_or(0, |x| x.len()),
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// Replace the ICC profile of the image, which is embedded when encoding JPEG
+/// and PNG files (see `wi_img_save_with_format`). If `data` is NULL, the
+/// profile is removed instead.
+///
+/// The pixels are left untouched, so this tags them as being in the given
+/// color space. Returns 1 on success, and 0 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_set_icc_profile(ctx: *mut WiImage, data: *const u8, len: size_t) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        let ctx = &mut *ctx;
+        let result = match &ctx.0 {
+            Ok(_) if data.is_null() => Ok(None),
+            Ok(_) => {
+                let profile = std::slice::from_raw_parts(data, len);
+                check_icc_profile(profile).map(|_| Some(profile.to_vec()))
+            }
+            Err(x) => Err(x.clone()),
+        };
+        match result {
+            Ok(profile) => {
+                ctx.1.icc = profile;
+                1
+            }
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// Convert the pixels of the image from its embedded ICC profile (such as
+/// Display P3 or Adobe RGB) to sRGB, and drop the profile.
+///
+/// The `intent` is one of "perceptual", "relative" (colorimetric), "saturation"
+/// or "absolute" (colorimetric), case-insensitive; NULL means "perceptual".
+/// RGB and grayscale profiles are supported, and the result has 8-bit channels.
+/// Images without a profile are returned unchanged.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_convert_to_srgb(ctx: *const WiImage, intent: *const c_char) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let go = || -> Result<(DynamicImage, ImageMetadata), WiError> {
+            let image = (&*ctx).0.as_ref().map_err(|x| x.clone())?;
+            let mut metadata = (&*ctx).1.clone();
+            let intent = match intent.is_null() {
+                true => qcms::Intent::Perceptual,
+                false => parse_rendering_intent(CStr::from_ptr(intent).to_str()?)?,
+            };
+            match metadata.icc.take() {
+                Some(profile) => Ok((convert_to_srgb(image, &profile, intent)?, metadata)),
+                None => Ok((image.clone(), metadata)),
+            }
+        };
+        let result = match go() {
+            Ok((image, metadata)) => WiImage(Ok(image), metadata),
+            Err(x) => WiImage(Err(x), (&*ctx).1.clone()),
+        };
+        Box::into_raw(Box::new(result))
+    })
+}
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ANIMATION - METHODS
+///////////////////////////////////////////////////////////////////////////////
+
+#[derive(Clone)]
+struct Animation {
+    frames: Vec<AnimationFrame>,
+    /// How many times the animation plays, 0 meaning forever.
+    loop_count: u32,
+}
+
+#[derive(Clone)]
+struct AnimationFrame {
+    image: image::RgbaImage,
+    /// The delay before the next frame, in milliseconds.
+    delay: u32,
+    disposal: WiDisposal,
+}
+
+/// An encodable animation format.
+enum AnimationFormat {
+    Gif,
+    Apng,
+}
+
+fn parse_animation_format(format: &str) -> Result<AnimationFormat, WiError> {
+    match format.to_lowercase().as_str() {
+        "gif" => Ok(AnimationFormat::Gif),
+        "apng" | "png" => Ok(AnimationFormat::Apng),
+        other => {
+            let message = format!("unsupported animation format: {}", other);
+            Err(WiError::new(WiErrorCode::UnsupportedFormat, message))
+        }
+    }
+}
+
+/// Decode every frame of a GIF or PNG (animated or not) file.
+///
+/// The canvas is checked against `limits` before decoding, and the frames
+/// decoded so far against its allocation limit.
+fn decode_animation(encoded: &[u8], limits: &WiDecodeLimits) -> Result<Animation, WiError> {
+    use image::{AnimationDecoder, ImageDecoder};
+    use image::codecs::{gif::GifDecoder, png::PngDecoder};
+    let cursor = std::io::Cursor::new(encoded);
+    let collect = |frames: image::Frames<'_>| -> Result<Vec<image::Frame>, WiError> {
+        let mut budget = limits.max_alloc;
+        frames
+            .map(|frame| {
+                let frame = frame.map_err(WiError::from)?;
+                limits.reserve(&mut budget, frame.buffer().as_raw().len() as u64)?;
+                Ok(frame)
+            })
+            .collect()
+    };
+    let (frames, disposals, loop_count) = match image::guess_format(encoded).map_err(WiError::from)? {
+        image::ImageFormat::Gif => {
+            let decoder = GifDecoder::new(cursor).map_err(WiError::from)?;
+            let (width, height) = decoder.dimensions();
+            limits.check(width, height)?;
+            let frames = collect(decoder.into_frames())?;
+            let (disposals, loop_count) = read_gif_timing(encoded);
+            (frames, disposals, loop_count)
+        }
+        image::ImageFormat::Png => {
+            let decoder = PngDecoder::new(cursor).map_err(WiError::from)?;
+            if !decoder.is_apng() {
+                let image = decode_image(std::io::Cursor::new(encoded), Some(image::ImageFormat::Png), limits)?;
+                let frame = AnimationFrame {image: image.into_rgba8(), delay: 0, disposal: WiDisposal::Unspecified};
+                return Ok(Animation {frames: vec![frame], loop_count: 0});
+            }
+            let (width, height) = decoder.dimensions();
+            limits.check(width, height)?;
+            let frames = collect(decoder.apng().into_frames())?;
+            let (disposals, loop_count) = read_apng_timing(encoded);
+            (frames, disposals, loop_count)
+        }
+        format => {
+            let message = format!("{:?} images can't be decoded as animations", format);
+            return Err(WiError::new(WiErrorCode::UnsupportedFormat, message));
+        }
+    };
+    let frames = frames
+        .into_iter()
+        .enumerate()
+        .map(|(index, frame)| {
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            AnimationFrame {
+                delay: (numerator + denominator / 2).checked_div(denominator).unwrap_or(0),
+                disposal: disposals.get(index).copied().unwrap_or(WiDisposal::Unspecified),
+                image: frame.into_buffer(),
+            }
+        })
+        .collect();
+    Ok(Animation {frames, loop_count})
+}
+
+/// The disposal of each frame of a GIF file, and its loop count.
+///
+/// A GIF without a loop count plays once, and one that repeats `n` times plays `n + 1` times.
+fn read_gif_timing(encoded: &[u8]) -> (Vec<WiDisposal>, u32) {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = match options.read_info(encoded) {
+        Ok(x) => x,
+        Err(_) => return (Vec::new(), 1),
+    };
+    let mut disposals = Vec::new();
+    while let Ok(Some(frame)) = decoder.next_frame_info() {
+        disposals.push(match frame.dispose {
+            gif::DisposalMethod::Any => WiDisposal::Unspecified,
+            gif::DisposalMethod::Keep => WiDisposal::Keep,
+            gif::DisposalMethod::Background => WiDisposal::Background,
+            gif::DisposalMethod::Previous => WiDisposal::Previous,
+        });
+    }
+    let loop_count = match decoder.repeat() {
+        gif::Repeat::Infinite => 0,
+        gif::Repeat::Finite(x) => x as u32 + 1,
+    };
+    (disposals, loop_count)
+}
+
+/// The disposal of each frame of an APNG file (from its `fcTL` chunks), and
+/// its loop count (from its `acTL` chunk).
+fn read_apng_timing(encoded: &[u8]) -> (Vec<WiDisposal>, u32) {
+    let mut disposals = Vec::new();
+    let mut loop_count = 0;
+    for (kind, data) in png_chunks(encoded) {
+        match kind {
+            b"acTL" if data.len() >= 8 => {
+                loop_count = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+            }
+            b"fcTL" if data.len() >= 26 => {
+                disposals.push(match data[24] {
+                    0 => WiDisposal::Keep,
+                    1 => WiDisposal::Background,
+                    2 => WiDisposal::Previous,
+                    _ => WiDisposal::Unspecified,
+                });
+            }
+            _ => {}
+        }
+    }
+    (disposals, loop_count)
+}
+
+/// Encode an animation. Frames are whole pictures, so each one replaces the
+/// previous one entirely, whatever the disposal of its source.
+fn encode_animation(animation: &Animation, format: AnimationFormat) -> Result<Vec<u8>, WiError> {
+    let (width, height) = match animation.frames.first() {
+        Some(x) => x.image.dimensions(),
+        None => return Err(WiError::new(WiErrorCode::InvalidArgument, "animation has no frames")),
+    };
+    let mut output = Vec::new();
+    match format {
+        AnimationFormat::Gif => {
+            let gif_error = |x: gif::EncodingError| WiError::new(WiErrorCode::EncodeFailed, x.to_string());
+            if width > u16::MAX as u32 || height > u16::MAX as u32 {
+                let message = format!("{}x{} is too large for a GIF", width, height);
+                return Err(WiError::new(WiErrorCode::EncodeFailed, message));
+            }
+            let mut encoder = gif::Encoder::new(&mut output, width as u16, height as u16, &[])
+                .map_err(gif_error)?;
+            if animation.loop_count != 1 {
+                let repeat = match animation.loop_count {
+                    0 => gif::Repeat::Infinite,
+                    x => gif::Repeat::Finite((x - 1).min(u16::MAX as u32) as u16),
+                };
+                encoder.set_repeat(repeat).map_err(gif_error)?;
+            }
+            for frame in &animation.frames {
+                let mut pixels = frame.image.as_raw().clone();
+                let mut gif_frame = gif::Frame::from_rgba_speed(width as u16, height as u16, &mut pixels, 10);
+                gif_frame.dispose = gif::DisposalMethod::Background;
+                gif_frame.delay = ((frame.delay + 5) / 10).min(u16::MAX as u32) as u16;
+                encoder.write_frame(&gif_frame).map_err(gif_error)?;
+            }
+        }
+        AnimationFormat::Apng => {
+            let png_error = |x: png::EncodingError| WiError::new(WiErrorCode::EncodeFailed, x.to_string());
+            let mut encoder = png::Encoder::new(&mut output, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder
+                .set_animated(animation.frames.len() as u32, animation.loop_count)
+                .map_err(png_error)?;
+            let mut writer = encoder.write_header().map_err(png_error)?;
+            for frame in &animation.frames {
+                // Milliseconds, or centiseconds for delays that don't fit.
+                let (numerator, denominator) = match frame.delay {
+                    x if x <= u16::MAX as u32 => (x as u16, 1000),
+                    x => ((x / 10).min(u16::MAX as u32) as u16, 100),
+                };
+                writer.set_frame_delay(numerator, denominator).map_err(png_error)?;
+                writer.set_dispose_op(png::DisposeOp::None).map_err(png_error)?;
+                writer.set_blend_op(png::BlendOp::Source).map_err(png_error)?;
+                writer.write_image_data(frame.image.as_raw()).map_err(png_error)?;
+            }
+            writer.finish().map_err(png_error)?;
+        }
+    }
+    Ok(output)
+}
+
+/// Look up a frame of an animation.
+fn animation_frame(animation: &Animation, index: c_int) -> Result<&AnimationFrame, WiError> {
+    use std::convert::TryFrom;
+    usize::try_from(index)
+        .ok()
+        .and_then(|x| animation.frames.get(x))
+        .ok_or_else(|| {
+            let message = format!("frame {} is out of bounds for {} frames", index, animation.frames.len());
+            WiError::new(WiErrorCode::OutOfBounds, message)
+        })
+}
+
+/// Check that a frame can be added to an animation, whose frames all share one size.
+fn check_frame_size(animation: &Animation, image: &image::RgbaImage) -> Result<(), WiError> {
+    match animation.frames.first() {
+        Some(x) if x.image.dimensions() != image.dimensions() => {
+            let (width, height) = x.image.dimensions();
+            let message = format!(
+                "frame of {}x{} doesn't match the animation size of {}x{}",
+                image.width(), image.height(), width, height,
+            );
+            Err(WiError::new(WiErrorCode::DimensionMismatch, message))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Decode every frame of an animated GIF or APNG file.
+///
+/// Still PNG files are decoded as a single frame. Uses the default decode
+/// limits (see `wi_set_default_decode_limits`).
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_open(path: *const c_char) -> *mut WiAnimation {
+    catch_panic(|| {
+        wi_animation_open_with_limits(path, std::ptr::null())
+    })
+}
+
+/// Same as `wi_animation_open`, but checks the frames against the given
+/// limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_open_with_limits(
+    path: *const c_char,
+    limits: *const WiDecodeLimits,
+) -> *mut WiAnimation {
+    catch_panic(|| {
+        let limits = decode_limits(limits);
+        if path.is_null() {
+            return null_argument("path", std::ptr::null_mut());
+        }
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| std::fs::read(path).map_err(WiError::from))
+            .and_then(|encoded| decode_animation(&encoded, &limits));
+        Box::into_raw(Box::new(WiAnimation(result)))
+    })
+}
+
+/// Decode every frame of an animated GIF or APNG file from an in-memory byte buffer.
+///
+/// The buffer is only read during the call, and may be freed afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_decode_from_memory(data: *const u8, len: size_t) -> *mut WiAnimation {
+    catch_panic(|| {
+        wi_animation_decode_from_memory_with_limits(data, len, std::ptr::null())
+    })
+}
+
+/// Same as `wi_animation_decode_from_memory`, but checks the frames against
+/// the given limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_decode_from_memory_with_limits(
+    data: *const u8,
+    len: size_t,
+    limits: *const WiDecodeLimits,
+) -> *mut WiAnimation {
+    catch_panic(|| {
+        let limits = decode_limits(limits);
+        if data.is_null() {
+            return null_argument("data", std::ptr::null_mut());
+        }
+        let result = decode_animation(std::slice::from_raw_parts(data, len), &limits);
+        Box::into_raw(Box::new(WiAnimation(result)))
+    })
+}
+
+/// Create an animation without frames, to be added with `wi_animation_push_frame`.
+///
+/// A `loop_count` of 0 plays the animation forever.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_new(loop_count: u32) -> *mut WiAnimation {
+    catch_panic(|| {
+        let result = Ok(Animation {frames: Vec::new(), loop_count});
+        Box::into_raw(Box::new(WiAnimation(result)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_frame_count(ctx: *const WiAnimation) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.frames.len() as c_int,
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// The width of the frames, or 0 if there are none.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_width(ctx: *const WiAnimation) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.frames.first().map_or(0, |x| x.image.width() as c_int),
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// The height of the frames, or 0 if there are none.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_height(ctx: *const WiAnimation) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.frames.first().map_or(0, |x| x.image.height() as c_int),
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// How many times the animation plays, 0 meaning forever, or -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_loop_count(ctx: *const WiAnimation) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.loop_count.min(c_int::MAX as u32) as c_int,
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// Set how many times the animation plays, 0 meaning forever.
+/// Returns 1 on success, and 0 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_set_loop_count(ctx: *mut WiAnimation, loop_count: u32) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        match &mut (&mut *ctx).0 {
+            Ok(ref mut x) => {
+                x.loop_count = loop_count;
+                1
+            }
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// A copy of the frame at `index`, as an RGBA8 image.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_frame(ctx: *const WiAnimation, index: c_int) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => animation_frame(x, index).map(|x| DynamicImage::ImageRgba8(x.image.clone())),
+            Err(x) => Err(x.clone()),
+        };
+        Box::into_raw(Box::new(WiImage(result, ImageMetadata::default())))
+    })
+}
+
+/// The delay after the frame at `index` in milliseconds, or -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_frame_delay(ctx: *const WiAnimation, index: c_int) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => animation_frame(x, index).map(|x| x.delay.min(c_int::MAX as u32) as c_int),
+            Err(x) => Err(x.clone()),
+        };
+        result.unwrap_or_else(|x| x.report(-1))
+    })
+}
+
+/// Set the delay after the frame at `index` in milliseconds.
+/// Returns 1 on success, and 0 on failure.
+///
+/// GIF files store delays in centiseconds, so they're rounded when encoded as GIF.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_set_frame_delay(ctx: *mut WiAnimation, index: c_int, delay: u32) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        let result = match &mut (&mut *ctx).0 {
+            Ok(ref mut x) => animation_frame(x, index).map(|_| index as usize).map(|index| {
+                x.frames[index].delay = delay;
+            }),
+            Err(x) => Err(x.clone()),
+        };
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// How the frame at `index` was disposed of in the source file.
+///
+/// Decoded frames are already composited, so this is for information only.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_frame_disposal(ctx: *const WiAnimation, index: c_int) -> WiDisposal {
+    catch_panic_or(WiDisposal::Unspecified, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiDisposal::Unspecified);
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => animation_frame(x, index).map(|x| x.disposal),
+            Err(x) => Err(x.clone()),
+        };
+        result.unwrap_or_else(|x| x.report(WiDisposal::Unspecified))
+    })
+}
+
+/// Append a copy of `frame` (converted to RGBA8), shown for `delay` milliseconds.
+/// Returns 1 on success, and 0 on failure.
+///
+/// All frames must have the same size, otherwise `DimensionMismatch` is reported.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_push_frame(ctx: *mut WiAnimation, frame: *const WiImage, delay: u32) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if frame.is_null() {
+            return null_argument("frame", 0);
+        }
+        let result = match (&mut (&mut *ctx).0, &(&*frame).0) {
+            (Ok(ref mut animation), Ok(ref image)) => {
+                let image = image.to_rgba8();
+                check_frame_size(animation, &image).map(|_| {
+                    let frame = AnimationFrame {image, delay, disposal: WiDisposal::Unspecified};
+                    animation.frames.push(frame);
+                })
+            }
+            (Err(x), _) => Err(x.clone()),
+            (_, Err(x)) => Err(x.clone()),
+        };
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// Apply an operation to every frame, keeping the delays and loop count.
+///
+/// The `op` callback receives each frame (as an RGBA8 image, only valid during
+/// the call) and `user_data`, and returns a new image, which the library takes
+/// ownership of. For example, it could return `wi_img_thumbnail(frame, 64, 64)`.
+/// The result must be a new handle: returning `frame` itself is an
+/// `InvalidArgument` error, since the library doesn't own it.
+///
+/// If the callback returns NULL or a failed image, or the results differ in
+/// size, the returned animation holds the error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_map_frames(
+    ctx: *const WiAnimation,
+    op: Option<unsafe extern "C" fn(frame: *const WiImage, user_data: *mut c_void) -> *mut WiImage>,
+    user_data: *mut c_void,
+) -> *mut WiAnimation {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let op = match op {
+            Some(op) => op,
+            None => return null_argument("op", std::ptr::null_mut()),
+        };
+        let go = || -> Result<Animation, WiError> {
+            let source = (&*ctx).0.as_ref().map_err(|x| x.clone())?;
+            let mut output = Animation {frames: Vec::new(), loop_count: source.loop_count};
+            for frame in &source.frames {
+                let input = WiImage(Ok(DynamicImage::ImageRgba8(frame.image.clone())), ImageMetadata::default());
+                let result = op(&input, user_data);
+                if result.is_null() {
+                    return Err(WiError::new(WiErrorCode::InvalidArgument, "frame operation returned NULL"));
+                }
+                if std::ptr::eq(result, &input) {
+                    let message = "frame operation returned its input instead of a new image";
+                    return Err(WiError::new(WiErrorCode::InvalidArgument, message));
+                }
+                let result = Box::from_raw(result);
+                let image = result.0?.into_rgba8();
+                check_frame_size(&output, &image)?;
+                output.frames.push(AnimationFrame {image, ..frame.clone()});
+            }
+            Ok(output)
+        };
+        Box::into_raw(Box::new(WiAnimation(go())))
+    })
+}
+
+/// Encode the animation as "gif" or "apng" (also accepted as "png"),
+/// case-insensitive, into a library-owned byte buffer.
+///
+/// The returned value must be released with `wi_bytes_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_encode(ctx: *const WiAnimation, format: *const c_char) -> *mut WiBytes {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if format.is_null() {
+            return null_argument("format", std::ptr::null_mut());
+        }
+        let result = CStr::from_ptr(format)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_animation_format)
+            .and_then(|format| match &(&*ctx).0 {
+                Ok(ref x) => encode_animation(x, format),
+                Err(x) => Err(x.clone()),
+            });
+        Box::into_raw(Box::new(WiBytes(result)))
+    })
+}
+
+/// Save the animation to `path` as "gif" or "apng" (see `wi_animation_encode`).
+///
+/// Returns 1 on success, 0 on failure, and -1 if `format` is unknown.
+#[no_mangle]
+pub unsafe extern "C" fn wi_animation_save(ctx: *const WiAnimation, path: *const c_char, format: *const c_char) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if path.is_null() {
+            return null_argument("path", 0);
+        }
+        if format.is_null() {
+            return null_argument("format", 0);
+        }
+        let format = CStr::from_ptr(format)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_animation_format);
+        let format = match format {
+            Ok(format) => format,
+            Err(x) => return x.report(-1),
+        };
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| match &(&*ctx).0 {
+                Ok(ref x) => {
+                    let output = encode_animation(x, format)?;
+                    std::fs::write(path, output).map_err(WiError::from)
+                }
+                Err(x) => Err(x.clone()),
+            });
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TIFF - METHODS
+///////////////////////////////////////////////////////////////////////////////
+
+/// A TIFF compression scheme, applied to every page.
+#[derive(Clone, Copy)]
+enum TiffCompression {
+    None,
+    Lzw,
+    Deflate,
+    Packbits,
+}
+
+/// Map a compression name (case-insensitive) to a TIFF compression, NULL meaning none.
+unsafe fn parse_tiff_compression(value: *const c_char) -> Result<TiffCompression, WiError> {
+    if value.is_null() {
+        return Ok(TiffCompression::None);
+    }
+    match CStr::from_ptr(value).to_str().map_err(WiError::from)?.to_lowercase().as_str() {
+        "none" => Ok(TiffCompression::None),
+        "lzw" => Ok(TiffCompression::Lzw),
+        "deflate" | "zip" => Ok(TiffCompression::Deflate),
+        "packbits" => Ok(TiffCompression::Packbits),
+        other => {
+            let message = format!("unknown tiff compression: {}", other);
+            Err(WiError::new(WiErrorCode::InvalidArgument, message))
+        }
+    }
+}
+
+/// The most pages read from a TIFF file, so that a crafted chain of
+/// directories can't keep the decoder busy.
+const MAX_TIFF_PAGES: usize = 65536;
+
+type TiffDecoder<'a> = tiff::decoder::Decoder<std::io::Cursor<&'a [u8]>>;
+
+/// A TIFF decoder, positioned at the first page.
+fn tiff_decoder<'a>(encoded: &'a [u8], limits: &WiDecodeLimits) -> Result<TiffDecoder<'a>, WiError> {
+    use std::convert::TryFrom;
+    let mut tiff_limits = tiff::decoder::Limits::default();
+    if let Some(max) = limits.max_alloc {
+        tiff_limits.decoding_buffer_size = usize::try_from(max).unwrap_or(usize::MAX);
+        tiff_limits.intermediate_buffer_size = tiff_limits.decoding_buffer_size;
+        tiff_limits.ifd_value_size = tiff_limits.ifd_value_size.min(tiff_limits.decoding_buffer_size);
+    }
+    Ok(tiff::decoder::Decoder::new(std::io::Cursor::new(encoded))?.with_limits(tiff_limits))
+}
+
+/// The number of pages (image file directories) of a TIFF or BigTIFF file.
+fn tiff_page_count(encoded: &[u8], limits: &WiDecodeLimits) -> Result<usize, WiError> {
+    let mut decoder = tiff_decoder(encoded, limits)?;
+    let mut count = 1;
+    while decoder.more_images() {
+        if count == MAX_TIFF_PAGES {
+            let message = format!("tiff files with more than {} pages aren't supported", MAX_TIFF_PAGES);
+            return Err(WiError::new(WiErrorCode::LimitExceeded, message));
+        }
+        decoder.next_image()?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Decompress one strip of a TIFF page, for the compressions `tiff_packed_page` handles.
+fn tiff_strip(compression: u32, strip: &[u8], len: usize) -> Result<Vec<u8>, WiError> {
+    use std::io::Read;
+    let corrupt = || WiError::new(WiErrorCode::DecodeFailed, "corrupt tiff strip");
+    let mut output = Vec::with_capacity(len);
+    match compression {
+        1 => output.extend_from_slice(strip),
+        5 => {
+            let mut decoder = weezl::decode::Decoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8);
+            decoder.into_vec(&mut output).decode(strip).status.map_err(|_| corrupt())?;
+        }
+        8 | 32946 => {
+            let mut decoder = flate2::read::ZlibDecoder::new(strip).take(len as u64);
+            decoder.read_to_end(&mut output).map_err(|_| corrupt())?;
+        }
+        32773 => {
+            let mut rest = strip;
+            while let Some((&header, tail)) = rest.split_first() {
+                rest = match header as i8 {
+                    -128 => tail,
+                    n @ 0..=127 => {
+                        let literal = tail.get(..n as usize + 1).ok_or_else(corrupt)?;
+                        output.extend_from_slice(literal);
+                        &tail[literal.len()..]
+                    }
+                    n => {
+                        let byte = *tail.first().ok_or_else(corrupt)?;
+                        output.resize(output.len() + (1 - n as isize) as usize, byte);
+                        &tail[1..]
+                    }
+                };
+                if output.len() >= len {
+                    break;
+                }
+            }
+        }
+        2..=4 => {
+            let message = "CCITT fax compression isn't supported in tiff files";
+            return Err(WiError::new(WiErrorCode::UnsupportedFormat, message));
+        }
+        other => {
+            let message = format!("unsupported tiff compression: {}", other);
+            return Err(WiError::new(WiErrorCode::UnsupportedFormat, message));
+        }
+    }
+    if output.len() < len {
+        return Err(WiError::new(WiErrorCode::DecodeFailed, "truncated tiff strip"));
+    }
+    output.truncate(len);
+    Ok(output)
+}
+
+/// Decode the current page of a TIFF decoder if it has 1, 2 or 4 bits per
+/// sample or a color palette, which the tiff crate can't unpack. Bilevel and
+/// gray pages become `"l8"` images, and palette pages `"rgb8"` ones.
+fn tiff_packed_page(decoder: &mut TiffDecoder, encoded: &[u8], limits: &WiDecodeLimits) -> Result<Option<DynamicImage>, WiError> {
+    use std::convert::TryFrom;
+    use tiff::tags::Tag;
+    let unsupported = |message: &str| Err(WiError::new(WiErrorCode::UnsupportedFormat, message.to_owned()));
+    let (width, height) = decoder.dimensions()?;
+    let samples = decoder.find_tag_unsigned::<u32>(Tag::SamplesPerPixel)?.unwrap_or(1);
+    // One value per sample, which can't differ here.
+    let bits = decoder
+        .find_tag_unsigned_vec::<u32>(Tag::BitsPerSample)?
+        .and_then(|x| x.first().copied())
+        .unwrap_or(1);
+    let photometric = decoder.find_tag_unsigned::<u32>(Tag::PhotometricInterpretation)?.unwrap_or(0);
+    if samples != 1 || (bits >= 8 && photometric != 3) {
+        return Ok(None);
+    }
+    if ![1, 2, 4, 8].contains(&bits) {
+        return unsupported("unsupported bits per sample in tiff palette");
+    }
+    if decoder.find_tag(Tag::TileOffsets)?.is_some() {
+        return unsupported("tiled tiff pages with less than 8 bits per sample aren't supported");
+    }
+    if decoder.find_tag_unsigned::<u32>(Tag::Predictor)?.unwrap_or(1) != 1 {
+        return unsupported("tiff predictors with less than 8 bits per sample aren't supported");
+    }
+    let compression = decoder.find_tag_unsigned::<u32>(Tag::Compression)?.unwrap_or(1);
+    let reversed = decoder.find_tag_unsigned::<u32>(Tag::FillOrder)?.unwrap_or(1) == 2;
+    let offsets = decoder.get_tag_u64_vec(Tag::StripOffsets)?;
+    let counts = decoder.get_tag_u64_vec(Tag::StripByteCounts)?;
+    let rows_per_strip = decoder.find_tag_unsigned::<u32>(Tag::RowsPerStrip)?.unwrap_or(height).min(height) as usize;
+    // Rows are padded to whole bytes.
+    let row_len = (width as usize * bits as usize).div_ceil(8);
+    let mut budget = limits.max_alloc;
+    limits.reserve(&mut budget, row_len as u64 * height as u64)?;
+    limits.reserve(&mut budget, width as u64 * height as u64 * if photometric == 3 {3} else {1})?;
+    let mut packed = Vec::with_capacity(row_len * height as usize);
+    for (offset, count) in offsets.iter().zip(counts.iter()) {
+        let rows = rows_per_strip.min(height as usize - packed.len() / row_len);
+        if rows == 0 {
+            break;
+        }
+        let strip = usize::try_from(*offset)
+            .ok()
+            .zip(usize::try_from(*count).ok())
+            .and_then(|(offset, count)| encoded.get(offset..offset.checked_add(count)?))
+            .ok_or_else(|| WiError::new(WiErrorCode::DecodeFailed, "truncated tiff page"))?;
+        packed.extend(tiff_strip(compression, strip, rows * row_len)?);
+    }
+    if packed.len() < row_len * height as usize {
+        return Err(WiError::new(WiErrorCode::DecodeFailed, "truncated tiff page"));
+    }
+    if reversed {
+        packed.iter_mut().for_each(|x| *x = x.reverse_bits());
+    }
+    let max = (1u32 << bits) - 1;
+    let values = packed.chunks_exact(row_len).flat_map(|row| {
+        (0..width as usize).map(move |x| {
+            let bit = x * bits as usize;
+            (row[bit / 8] as u32 >> (8 - bits as usize - bit % 8)) & max
+        })
+    });
+    let image = match photometric {
+        0 => image::GrayImage::from_raw(width, height, values.map(|x| (255 - x * 255 / max) as u8).collect())
+            .map(DynamicImage::ImageLuma8),
+        1 => image::GrayImage::from_raw(width, height, values.map(|x| (x * 255 / max) as u8).collect())
+            .map(DynamicImage::ImageLuma8),
+        3 => {
+            let map = decoder.get_tag_u16_vec(Tag::ColorMap)?;
+            let colors = max as usize + 1;
+            if map.len() != 3 * colors {
+                return Err(WiError::new(WiErrorCode::DecodeFailed, "invalid tiff color map"));
+            }
+            let rgb = values
+                .flat_map(|x| {
+                    let x = x as usize;
+                    [(map[x] >> 8) as u8, (map[colors + x] >> 8) as u8, (map[2 * colors + x] >> 8) as u8]
+                })
+                .collect();
+            image::RgbImage::from_raw(width, height, rgb).map(DynamicImage::ImageRgb8)
+        }
+        _ => return unsupported("unsupported photometric interpretation in tiff page"),
+    };
+    image.map(Some).ok_or_else(|| WiError::new(WiErrorCode::DecodeFailed, "truncated tiff page"))
+}
+
+/// Decode the pixels of the current page of a TIFF decoder.
+fn tiff_page_image(decoder: &mut TiffDecoder, encoded: &[u8], limits: &WiDecodeLimits) -> Result<DynamicImage, WiError> {
+    use image::ImageBuffer;
+    use tiff::decoder::DecodingResult;
+    use tiff::ColorType;
+    let (width, height) = decoder.dimensions()?;
+    limits.check(width, height)?;
+    if let Some(image) = tiff_packed_page(decoder, encoded, limits)? {
+        return Ok(image);
+    }
+    let color = decoder.colortype()?;
+    let image = match (color, decoder.read_image()?) {
+        (ColorType::Gray(8), DecodingResult::U8(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageLuma8),
+        (ColorType::Gray(16), DecodingResult::U16(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageLuma16),
+        (ColorType::GrayA(8), DecodingResult::U8(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageLumaA8),
+        (ColorType::GrayA(16), DecodingResult::U16(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageLumaA16),
+        (ColorType::RGB(8), DecodingResult::U8(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageRgb8),
+        (ColorType::RGB(16), DecodingResult::U16(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageRgb16),
+        (ColorType::RGB(32), DecodingResult::F32(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageRgb32F),
+        (ColorType::RGBA(8), DecodingResult::U8(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageRgba8),
+        (ColorType::RGBA(16), DecodingResult::U16(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageRgba16),
+        (ColorType::RGBA(32), DecodingResult::F32(x)) => ImageBuffer::from_raw(width, height, x).map(DynamicImage::ImageRgba32F),
+        (ColorType::CMYK(8), DecodingResult::U8(x)) => {
+            let rgb = x
+                .chunks_exact(4)
+                .flat_map(|x| {
+                    let ink = |c: u8| ((255 - c as u32) * (255 - x[3] as u32) / 255) as u8;
+                    [ink(x[0]), ink(x[1]), ink(x[2])]
+                })
+                .collect();
+            ImageBuffer::from_raw(width, height, rgb).map(DynamicImage::ImageRgb8)
+        }
+        (color, _) => {
+            let message = format!("unsupported tiff color type: {:?}", color);
+            return Err(WiError::new(WiErrorCode::UnsupportedFormat, message));
+        }
+    };
+    image.ok_or_else(|| WiError::new(WiErrorCode::DecodeFailed, "truncated tiff page"))
+}
+
+/// The XMP, IPTC and ICC metadata stored in the tags of the current page of a TIFF decoder.
+fn tiff_page_metadata(decoder: &mut TiffDecoder, little_endian: bool) -> ImageMetadata {
+    let mut block = |number: u16| -> Option<Vec<u8>> {
+        let value = decoder.find_tag(tiff::tags::Tag::Unknown(number)).ok()??;
+        match value.clone().into_u8_vec() {
+            Ok(x) => Some(x),
+            Err(_) => {
+                let longs = value.into_u32_vec().ok()?;
+                match little_endian {
+                    true => Some(longs.iter().flat_map(|x| x.to_le_bytes()).collect()),
+                    false => Some(longs.iter().flat_map(|x| x.to_be_bytes()).collect()),
+                }
+            }
+        }
+    };
+    let xmp = block(TIFF_XMP_TAG).map(|x| String::from_utf8_lossy(&x).into_owned());
+    let iptc = block(TIFF_IPTC_TAG);
+    let icc = block(TIFF_ICC_TAG);
+    let mut metadata = ImageMetadata::new(None, xmp, iptc);
+    metadata.icc = icc;
+    metadata
+}
+
+/// Decode one page of a TIFF file, with its metadata.
+///
+/// EXIF data is only read for the first page, since the other ones rarely
+/// have any; they get their XMP, IPTC and ICC data.
+fn decode_tiff_page(encoded: &[u8], index: c_int, limits: &WiDecodeLimits) -> WiImage {
+    use std::convert::TryFrom;
+    let go = || -> Result<(DynamicImage, ImageMetadata), WiError> {
+        let mut decoder = tiff_decoder(encoded, limits)?;
+        let out_of_bounds = || {
+            let message = format!("page {} is out of bounds", index);
+            WiError::new(WiErrorCode::OutOfBounds, message)
+        };
+        let index = usize::try_from(index).map_err(|_| out_of_bounds())?;
+        if index >= MAX_TIFF_PAGES {
+            return Err(out_of_bounds());
+        }
+        if index > 0 {
+            decoder.seek_to_image(index).map_err(|x| match x {
+                tiff::TiffError::FormatError(tiff::TiffFormatError::ImageFileDirectoryNotFound) => out_of_bounds(),
+                x => WiError::from(x),
+            })?;
+        }
+        let image = tiff_page_image(&mut decoder, encoded, limits)?;
+        let metadata = match index {
+            0 => ImageMetadata::read(encoded, limits),
+            _ => tiff_page_metadata(&mut decoder, encoded.starts_with(b"II")),
+        };
+        Ok((image, metadata))
+    };
+    match go() {
+        Ok((image, metadata)) => WiImage(Ok(image), metadata),
+        Err(x) => WiImage(Err(x), ImageMetadata::default()),
+    }
+}
+
+/// Write one image as the next page of a TIFF file.
+fn write_tiff_page<W, C>(
+    encoder: &mut tiff::encoder::TiffEncoder<W>,
+    width: u32,
+    height: u32,
+    compression: TiffCompression,
+    data: &[C::Inner],
+) -> tiff::TiffResult<()>
+where
+    W: std::io::Write + std::io::Seek,
+    C: tiff::encoder::colortype::ColorType,
+    [C::Inner]: tiff::encoder::TiffValue,
+{
+    use tiff::encoder::compression::{Deflate, Lzw, Packbits, Uncompressed};
+    match compression {
+        TiffCompression::None => encoder.write_image_with_compression::<C, _>(width, height, Uncompressed, data),
+        TiffCompression::Lzw => encoder.write_image_with_compression::<C, _>(width, height, Lzw, data),
+        TiffCompression::Deflate => {
+            encoder.write_image_with_compression::<C, _>(width, height, Deflate::default(), data)
+        }
+        TiffCompression::Packbits => encoder.write_image_with_compression::<C, _>(width, height, Packbits, data),
+    }
+}
+
+/// Encode images as the pages of a TIFF file, in order.
+///
+/// Gray and color images keep their sample type, except that gray images
+/// with an alpha channel are written as RGBA.
+fn encode_tiff_pages(pages: &[&DynamicImage], compression: TiffCompression) -> Result<Vec<u8>, WiError> {
+    use tiff::encoder::colortype;
+    if pages.is_empty() {
+        return Err(WiError::new(WiErrorCode::InvalidArgument, "no pages to encode"));
+    }
+    let mut output = std::io::Cursor::new(Vec::new());
+    let mut encoder = tiff::encoder::TiffEncoder::new(&mut output)
+        .map_err(|x| WiError::new(WiErrorCode::EncodeFailed, x.to_string()))?;
+    for page in pages {
+        let (width, height) = (page.width(), page.height());
+        let result = match page {
+            DynamicImage::ImageLuma8(x) => {
+                write_tiff_page::<_, colortype::Gray8>(&mut encoder, width, height, compression, x)
+            }
+            DynamicImage::ImageRgb8(x) => {
+                write_tiff_page::<_, colortype::RGB8>(&mut encoder, width, height, compression, x)
+            }
+            DynamicImage::ImageLuma16(x) => {
+                write_tiff_page::<_, colortype::Gray16>(&mut encoder, width, height, compression, x)
+            }
+            DynamicImage::ImageRgb16(x) => {
+                write_tiff_page::<_, colortype::RGB16>(&mut encoder, width, height, compression, x)
+            }
+            DynamicImage::ImageRgba16(x) => {
+                write_tiff_page::<_, colortype::RGBA16>(&mut encoder, width, height, compression, x)
+            }
+            DynamicImage::ImageLumaA16(_) => {
+                let data = page.to_rgba16();
+                write_tiff_page::<_, colortype::RGBA16>(&mut encoder, width, height, compression, &data)
+            }
+            DynamicImage::ImageRgb32F(x) => {
+                write_tiff_page::<_, colortype::RGB32Float>(&mut encoder, width, height, compression, x)
+            }
+            DynamicImage::ImageRgba32F(x) => {
+                write_tiff_page::<_, colortype::RGBA32Float>(&mut encoder, width, height, compression, x)
+            }
+            _ => {
+                let data = page.to_rgba8();
+                write_tiff_page::<_, colortype::RGBA8>(&mut encoder, width, height, compression, &data)
+            }
+        };
+        result.map_err(|x| WiError::new(WiErrorCode::EncodeFailed, x.to_string()))?;
+    }
+    Ok(output.into_inner())
+}
+
+/// Encode an array of `count` images as the pages of a TIFF file.
+///
+/// `compression` is "none", "lzw", "deflate" or "packbits" (case-insensitive),
+/// and NULL means "none".
+unsafe fn encode_tiff_images(images: *const *const WiImage, count: size_t, compression: *const c_char) -> Result<Vec<u8>, WiError> {
+    let compression = parse_tiff_compression(compression)?;
+    if images.is_null() {
+        return Err(WiError::new(WiErrorCode::InvalidArgument, "`images` is NULL"));
+    }
+    let pages = std::slice::from_raw_parts(images, count)
+        .iter()
+        .enumerate()
+        .map(|(index, x)| match x.as_ref() {
+            Some(x) => x.0.as_ref().map_err(|x| x.clone()),
+            None => Err(WiError::new(WiErrorCode::InvalidArgument, format!("`images[{}]` is NULL", index))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    encode_tiff_pages(&pages, compression)
+}
+
+/// Like `encode_tiff_images`, for grayscale images.
+unsafe fn encode_tiff_grayimgs(images: *const *const WiGrayImage, count: size_t, compression: *const c_char) -> Result<Vec<u8>, WiError> {
+    let compression = parse_tiff_compression(compression)?;
+    if images.is_null() {
+        return Err(WiError::new(WiErrorCode::InvalidArgument, "`images` is NULL"));
+    }
+    let pages = std::slice::from_raw_parts(images, count)
+        .iter()
+        .enumerate()
+        .map(|(index, x)| match x.as_ref() {
+            Some(x) => x.0.as_ref().map(|x| DynamicImage::ImageLuma8(x.clone())).map_err(|x| x.clone()),
+            None => Err(WiError::new(WiErrorCode::InvalidArgument, format!("`images[{}]` is NULL", index))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    encode_tiff_pages(&pages.iter().collect::<Vec<_>>(), compression)
+}
+
+/// The number of pages of a TIFF file, or -1 on error.
+///
+/// Files with more than 65536 pages give a `LimitExceeded` error. Uses the
+/// default decode limits (see `wi_set_default_decode_limits`).
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_page_count(path: *const c_char) -> c_int {
+    catch_panic_or(-1, || {
+        wi_tiff_page_count_with_limits(path, std::ptr::null())
+    })
+}
+
+/// Same as `wi_tiff_page_count`, but reads the directories of the pages
+/// within the given limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_page_count_with_limits(path: *const c_char, limits: *const WiDecodeLimits) -> c_int {
+    catch_panic_or(-1, || {
+        let limits = decode_limits(limits);
+        if path.is_null() {
+            return null_argument("path", -1);
+        }
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| std::fs::read(path).map_err(WiError::from))
+            .and_then(|encoded| tiff_page_count(&encoded, &limits));
+        match result {
+            Ok(x) => x as c_int,
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// The number of pages of a TIFF file in an in-memory byte buffer, or -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_page_count_from_memory(data: *const u8, len: size_t) -> c_int {
+    catch_panic_or(-1, || {
+        wi_tiff_page_count_from_memory_with_limits(data, len, std::ptr::null())
+    })
+}
+
+/// Same as `wi_tiff_page_count_from_memory`, but reads the directories of the
+/// pages within the given limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_page_count_from_memory_with_limits(
+    data: *const u8,
+    len: size_t,
+    limits: *const WiDecodeLimits,
+) -> c_int {
+    catch_panic_or(-1, || {
+        let limits = decode_limits(limits);
+        if data.is_null() {
+            return null_argument("data", -1);
+        }
+        match tiff_page_count(std::slice::from_raw_parts(data, len), &limits) {
+            Ok(x) => x as c_int,
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+/// Decode the page at `index` (starting from 0) of a TIFF file, with its metadata.
+///
+/// `wi_img_open` only decodes the first page. Uses the default decode limits
+/// (see `wi_set_default_decode_limits`).
+///
+/// Bilevel and 2 or 4-bit gray pages are decoded as `"l8"` images, and palette
+/// pages as `"rgb8"` ones. Pages compressed with CCITT G3 or G4 (as written by
+/// fax software) give an `UnsupportedFormat` error, since the tiff decoder
+/// doesn't implement them.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_open_page(path: *const c_char, index: c_int) -> *mut WiImage {
+    catch_panic(|| {
+        wi_tiff_open_page_with_limits(path, index, std::ptr::null())
+    })
+}
+
+/// Same as `wi_tiff_open_page`, but checks the page against the given limits,
+/// or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_open_page_with_limits(
+    path: *const c_char,
+    index: c_int,
+    limits: *const WiDecodeLimits,
+) -> *mut WiImage {
+    catch_panic(|| {
+        let limits = decode_limits(limits);
+        if path.is_null() {
+            return null_argument("path", std::ptr::null_mut());
+        }
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| std::fs::read(path).map_err(WiError::from));
+        let result = match result {
+            Ok(encoded) => decode_tiff_page(&encoded, index, &limits),
+            Err(x) => WiImage(Err(x), ImageMetadata::default()),
+        };
+        Box::into_raw(Box::new(result))
+    })
+}
+
+/// Decode the page at `index` (starting from 0) of a TIFF file in an in-memory byte buffer.
+///
+/// The buffer is only read during the call, and may be freed afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_decode_page_from_memory(data: *const u8, len: size_t, index: c_int) -> *mut WiImage {
+    catch_panic(|| {
+        wi_tiff_decode_page_from_memory_with_limits(data, len, index, std::ptr::null())
+    })
+}
+
+/// Same as `wi_tiff_decode_page_from_memory`, but checks the page against the
+/// given limits, or the default ones if `limits` is NULL.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_decode_page_from_memory_with_limits(
+    data: *const u8,
+    len: size_t,
+    index: c_int,
+    limits: *const WiDecodeLimits,
+) -> *mut WiImage {
+    catch_panic(|| {
+        let limits = decode_limits(limits);
+        if data.is_null() {
+            return null_argument("data", std::ptr::null_mut());
+        }
+        let result = decode_tiff_page(std::slice::from_raw_parts(data, len), index, &limits);
+        Box::into_raw(Box::new(result))
+    })
+}
+
+/// Encode an array of `count` images as the pages of a TIFF file, into a
+/// library-owned byte buffer.
+///
+/// `compression` is "none", "lzw", "deflate" or "packbits" (case-insensitive),
+/// and NULL means "none". Metadata isn't written.
+///
+/// The returned value must be released with `wi_bytes_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_encode_pages(
+    images: *const *const WiImage,
+    count: size_t,
+    compression: *const c_char,
+) -> *mut WiBytes {
+    catch_panic(|| {
+        let result = encode_tiff_images(images, count, compression);
+        Box::into_raw(Box::new(WiBytes(result)))
+    })
+}
+
+/// Like `wi_tiff_encode_pages`, for an array of grayscale images.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_encode_grayimg_pages(
+    images: *const *const WiGrayImage,
+    count: size_t,
+    compression: *const c_char,
+) -> *mut WiBytes {
+    catch_panic(|| {
+        let result = encode_tiff_grayimgs(images, count, compression);
+        Box::into_raw(Box::new(WiBytes(result)))
+    })
+}
+
+/// Save an array of `count` images as the pages of a TIFF file at `path`
+/// (see `wi_tiff_encode_pages`).
+///
+/// Returns 1 on success, and 0 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_save_pages(
+    path: *const c_char,
+    images: *const *const WiImage,
+    count: size_t,
+    compression: *const c_char,
+) -> c_int {
+    catch_panic_or(0, || {
+        if path.is_null() {
+            return null_argument("path", 0);
+        }
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| {
+                let output = encode_tiff_images(images, count, compression)?;
+                std::fs::write(path, output).map_err(WiError::from)
+            });
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// Like `wi_tiff_save_pages`, for an array of grayscale images.
+#[no_mangle]
+pub unsafe extern "C" fn wi_tiff_save_grayimg_pages(
+    path: *const c_char,
+    images: *const *const WiGrayImage,
+    count: size_t,
+    compression: *const c_char,
+) -> c_int {
+    catch_panic_or(0, || {
+        if path.is_null() {
+            return null_argument("path", 0);
+        }
+        let result = CStr::from_ptr(path)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(|path| {
+                let output = encode_tiff_grayimgs(images, count, compression)?;
+                std::fs::write(path, output).map_err(WiError::from)
+            });
+        match result {
+            Ok(_) => 1,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// BYTES - METHODS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Pointer to the first byte of the buffer, or NULL for a failed or NULL handle.
+///
+/// The pointer stays valid until the handle is passed to `wi_bytes_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_bytes_data(ctx: *const WiBytes) -> *const u8 {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null());
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.as_ptr(),
+            Err(x) => x.report(std::ptr::null()),
+        }
+    })
+}
+
+/// Length of the buffer in bytes, or 0 for a failed or NULL handle.
+#[no_mangle]
+pub unsafe extern "C" fn wi_bytes_len(ctx: *const WiBytes) -> size_t {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.len(),
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// Copy the buffer into caller-owned memory of `capacity` bytes.
+///
+/// Returns the number of bytes written, or -1 if the handle is invalid or
+/// `capacity` is smaller than `wi_bytes_len`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_bytes_copy(ctx: *const WiBytes, output: *mut u8, capacity: size_t) -> isize {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        if output.is_null() {
+            return null_argument("output", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                if x.len() <= capacity {
+                    std::ptr::copy_nonoverlapping(x.as_ptr(), output, x.len());
+                    x.len() as isize
+                } else {
+                    let message = format!("buffer of {} bytes is too small for {} bytes", capacity, x.len());
+                    WiError::new(WiErrorCode::OutOfBounds, message).report(-1)
+                }
+            }
+            Err(x) => x.report(-1),
+        }
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// ENCODE-OPTIONS - METHODS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Encoder settings used by `wi_img_save_with_options` and `wi_img_encode_with_options`.
+///
+/// Created with `wi_encode_options_new` and released with `wi_encode_options_free`.
+/// Settings that don't apply to the chosen output format are ignored.
+#[derive(Clone)]
+pub struct WiEncodeOptions {
+    jpeg_quality: u8,
+    png_compression: image::codecs::png::CompressionType,
+    png_filter: image::codecs::png::FilterType,
+    webp_quality: u8,
+    webp_lossless: bool,
+    avif_quality: u8,
+    avif_speed: u8,
+}
+
+impl Default for WiEncodeOptions {
+    fn default() -> Self {
+        WiEncodeOptions {
+            jpeg_quality: 75,
+            png_compression: image::codecs::png::CompressionType::default(),
+            png_filter: image::codecs::png::FilterType::default(),
+            webp_quality: 80,
+            webp_lossless: false,
+            avif_quality: 80,
+            avif_speed: 4,
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_new() -> *mut WiEncodeOptions {
+    catch_panic_or(std::ptr::null_mut(), || {
+        let result = Box::new(WiEncodeOptions::default());
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_clone(ctx: *const WiEncodeOptions) -> *mut WiEncodeOptions {
+    catch_panic_or(std::ptr::null_mut(), || {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = Box::new((&*ctx).clone());
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_free(ctx: *mut WiEncodeOptions) {
+    catch_panic_or((), || {
+        if !ctx.is_null() {
+            let value = Box::from_raw(ctx);
+            std::mem::drop(value);
+        }
+    })
+}
+
+/// Set the JPEG quality, from 1 (smallest) to 100 (best). Defaults to 75.
+///
+/// Returns 1 on success and 0 for an out of range value.
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_set_jpeg_quality(ctx: *mut WiEncodeOptions, quality: u8) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if !(1..=100).contains(&quality) {
+            let message = format!("invalid jpeg quality: {}", quality);
+            return WiError::new(WiErrorCode::InvalidArgument, message).report(0);
+        }
+        (&mut *ctx).jpeg_quality = quality;
+        1
+    })
+}
+
+/// Set the PNG compression level: "fast" (the default), "default" or "best".
+///
+/// Returns 1 on success and 0 for an unknown value.
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_set_png_compression(ctx: *mut WiEncodeOptions, value: *const c_char) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if value.is_null() {
+            return null_argument("value", 0);
+        }
+        let value = CStr::from_ptr(value)
+            .to_str()
+            .ok()
+            .and_then(|x| match x.to_lowercase().as_str() {
+                "fast" => Some(image::codecs::png::CompressionType::Fast),
+                "default" => Some(image::codecs::png::CompressionType::Default),
+                "best" => Some(image::codecs::png::CompressionType::Best),
+                _ => None,
+            });
+        match value {
+            Some(value) => {
+                (&mut *ctx).png_compression = value;
+                1
+            }
+            None => WiError::new(WiErrorCode::InvalidArgument, "invalid png compression").report(0),
+        }
+    })
+}
+
+/// Set the PNG filter type: "none", "sub", "up", "avg", "paeth" or "adaptive" (the default).
+///
+/// Returns 1 on success and 0 for an unknown value.
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_set_png_filter(ctx: *mut WiEncodeOptions, value: *const c_char) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if value.is_null() {
+            return null_argument("value", 0);
+        }
+        let value = CStr::from_ptr(value)
+            .to_str()
+            .ok()
+            .and_then(|x| match x.to_lowercase().as_str() {
+                "none" => Some(image::codecs::png::FilterType::NoFilter),
+                "sub" => Some(image::codecs::png::FilterType::Sub),
+                "up" => Some(image::codecs::png::FilterType::Up),
+                "avg" => Some(image::codecs::png::FilterType::Avg),
+                "paeth" => Some(image::codecs::png::FilterType::Paeth),
+                "adaptive" => Some(image::codecs::png::FilterType::Adaptive),
+                _ => None,
+            });
+        match value {
+            Some(value) => {
+                (&mut *ctx).png_filter = value;
+                1
+            }
+            None => WiError::new(WiErrorCode::InvalidArgument, "invalid png filter").report(0),
+        }
+    })
+}
+
+/// Set the lossy WebP quality, from 0 (smallest) to 100 (best). Defaults to 80.
+///
+/// Returns 1 on success and 0 for an out of range value.
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_set_webp_quality(ctx: *mut WiEncodeOptions, quality: u8) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if quality > 100 {
+            let message = format!("invalid webp quality: {}", quality);
+            return WiError::new(WiErrorCode::InvalidArgument, message).report(0);
+        }
+        (&mut *ctx).webp_quality = quality;
+        1
+    })
+}
+
+/// Enable (1) or disable (0) lossless WebP encoding. Lossy encoding is the default.
+///
+/// The WebP quality setting is ignored for lossless output. Lossy output uses
+/// libwebp and fails with `UnsupportedFormat` if the crate was built without
+/// its `webp` feature, while lossless output is always available.
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_set_webp_lossless(ctx: *mut WiEncodeOptions, lossless: c_int) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        (&mut *ctx).webp_lossless = lossless != 0;
+        1
+    })
+}
+
+/// Set the AVIF quality, from 1 (smallest) to 100 (best). Defaults to 80.
+///
+/// Returns 1 on success and 0 for an out of range value.
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_set_avif_quality(ctx: *mut WiEncodeOptions, quality: u8) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if !(1..=100).contains(&quality) {
+            let message = format!("invalid avif quality: {}", quality);
+            return WiError::new(WiErrorCode::InvalidArgument, message).report(0);
+        }
+        (&mut *ctx).avif_quality = quality;
+        1
+    })
+}
+
+/// Set the AVIF encoding speed, from 1 (slowest, smallest) to 10 (fastest). Defaults to 4.
+///
+/// Returns 1 on success and 0 for an out of range value.
+#[no_mangle]
+pub unsafe extern "C" fn wi_encode_options_set_avif_speed(ctx: *mut WiEncodeOptions, speed: u8) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        if !(1..=10).contains(&speed) {
+            let message = format!("invalid avif speed: {}", speed);
+            return WiError::new(WiErrorCode::InvalidArgument, message).report(0);
+        }
+        (&mut *ctx).avif_speed = speed;
+        1
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// DECODE-LIMITS - METHODS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Limits checked against the image header before an image is decoded, so
+/// that untrusted files can't exhaust memory. Exceeding one is reported as
+/// `LimitExceeded`.
+///
+/// Created with `wi_decode_limits_new` and released with `wi_decode_limits_free`.
+/// Decoding functions without a limits argument use the defaults set with
+/// `wi_set_default_decode_limits`.
+#[derive(Clone, Debug)]
+pub struct WiDecodeLimits {
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    max_pixels: Option<u64>,
+    max_alloc: Option<u64>,
+}
+
+impl WiDecodeLimits {
+    /// No dimension limits, and the 512 MiB allocation limit of the `image` crate.
+    const BUILT_IN: WiDecodeLimits = WiDecodeLimits {
+        max_width: None,
+        max_height: None,
+        max_pixels: None,
+        max_alloc: Some(512 * 1024 * 1024),
+    };
+    /// Check the dimensions of an image against the limits.
+    fn check(&self, width: u32, height: u32) -> Result<(), WiError> {
+        let exceeded = |message: String| Err(WiError::new(WiErrorCode::LimitExceeded, message));
+        let pixels = width as u64 * height as u64;
+        match (self.max_width, self.max_height, self.max_pixels) {
+            (Some(max), _, _) if width > max => exceeded(format!("width of {} exceeds the limit of {}", width, max)),
+            (_, Some(max), _) if height > max => exceeded(format!("height of {} exceeds the limit of {}", height, max)),
+            (_, _, Some(max)) if pixels > max => exceeded(format!("{} pixels exceed the limit of {}", pixels, max)),
+            _ => Ok(()),
+        }
+    }
+    /// Account for an allocation of `amount` bytes out of `budget`, the bytes still available.
+    fn reserve(&self, budget: &mut Option<u64>, amount: u64) -> Result<(), WiError> {
+        match budget {
+            Some(x) if *x < amount => {
+                let message = format!("decoding needs more than the limit of {} bytes", self.max_alloc.unwrap_or(0));
+                Err(WiError::new(WiErrorCode::LimitExceeded, message))
+            }
+            Some(x) => {
+                *x -= amount;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+    fn image_limits(&self) -> image::io::Limits {
+        let mut limits = image::io::Limits::default();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        limits.max_alloc = self.max_alloc;
+        limits
+    }
+}
+
+impl Default for WiDecodeLimits {
+    fn default() -> Self {
+        WiDecodeLimits::BUILT_IN
+    }
+}
+
+static DEFAULT_DECODE_LIMITS: std::sync::RwLock<WiDecodeLimits> = std::sync::RwLock::new(WiDecodeLimits::BUILT_IN);
+
+/// The limits set with `wi_set_default_decode_limits`.
+fn default_decode_limits() -> WiDecodeLimits {
+    match DEFAULT_DECODE_LIMITS.read() {
+        Ok(x) => x.clone(),
+        Err(x) => x.into_inner().clone(),
+    }
+}
+
+/// The given limits, or the defaults if `limits` is NULL.
+unsafe fn decode_limits(limits: *const WiDecodeLimits) -> WiDecodeLimits {
+    limits.as_ref().cloned().unwrap_or_else(default_decode_limits)
+}
+
+/// Create a copy of the current default limits.
+#[no_mangle]
+pub unsafe extern "C" fn wi_decode_limits_new() -> *mut WiDecodeLimits {
+    catch_panic_or(std::ptr::null_mut(), || {
+        let result = Box::new(default_decode_limits());
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_decode_limits_clone(ctx: *const WiDecodeLimits) -> *mut WiDecodeLimits {
+    catch_panic_or(std::ptr::null_mut(), || {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = Box::new((&*ctx).clone());
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_decode_limits_free(ctx: *mut WiDecodeLimits) {
+    catch_panic_or((), || {
+        if !ctx.is_null() {
+            let value = Box::from_raw(ctx);
+            std::mem::drop(value);
+        }
+    })
+}
+
+/// Set the maximum image width, 0 meaning no limit (the built-in default).
+///
+/// Returns 1 on success and 0 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn wi_decode_limits_set_max_width(ctx: *mut WiDecodeLimits, value: u32) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        (&mut *ctx).max_width = Some(value).filter(|x| *x > 0);
+        1
+    })
+}
+
+/// Set the maximum image height, 0 meaning no limit (the built-in default).
+///
+/// Returns 1 on success and 0 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn wi_decode_limits_set_max_height(ctx: *mut WiDecodeLimits, value: u32) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        (&mut *ctx).max_height = Some(value).filter(|x| *x > 0);
+        1
+    })
+}
+
+/// Set the maximum number of pixels (width times height, and for animations
+/// of each frame), 0 meaning no limit (the built-in default).
+///
+/// Returns 1 on success and 0 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn wi_decode_limits_set_max_pixels(ctx: *mut WiDecodeLimits, value: u64) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        (&mut *ctx).max_pixels = Some(value).filter(|x| *x > 0);
+        1
+    })
+}
+
+/// Set the maximum number of bytes the decoded pixels may take, 0 meaning no
+/// limit. Defaults to 512 MiB.
+///
+/// Returns 1 on success and 0 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn wi_decode_limits_set_max_alloc(ctx: *mut WiDecodeLimits, value: u64) -> c_int {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        (&mut *ctx).max_alloc = Some(value).filter(|x| *x > 0);
+        1
+    })
+}
+
+/// Set the limits used by every decoding function without a limits argument,
+/// for all threads. A NULL `limits` restores the built-in defaults.
+///
+/// The limits are copied, and may be freed afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wi_set_default_decode_limits(limits: *const WiDecodeLimits) {
+    catch_panic_or((), || {
+        let limits = limits.as_ref().cloned().unwrap_or_default();
+        match DEFAULT_DECODE_LIMITS.write() {
+            Ok(mut x) => *x = limits,
+            Err(x) => *x.into_inner() = limits,
+        }
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE-VIEW - HELPER TYPES
+///////////////////////////////////////////////////////////////////////////////
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RgbaPixel {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+#[repr(C)]
+pub struct LumaPixel {
+    pub l: u8
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Rgba16Pixel {
+    pub r: u16,
+    pub g: u16,
+    pub b: u16,
+    pub a: u16,
+}
+
+/// Read a pixel as 16-bit RGBA, scaling 8-bit and float images as needed.
+fn get_rgba16_pixel(image: &DynamicImage, cx: u32, cy: u32) -> image::Rgba<u16> {
+    let from_f32 = |x: f32| (x.clamp(0.0, 1.0) * 65535.0).round() as u16;
+    match image {
+        DynamicImage::ImageLuma16(x) => x.get_pixel(cx, cy).to_rgba(),
+        DynamicImage::ImageLumaA16(x) => x.get_pixel(cx, cy).to_rgba(),
+        DynamicImage::ImageRgb16(x) => x.get_pixel(cx, cy).to_rgba(),
+        DynamicImage::ImageRgba16(x) => *x.get_pixel(cx, cy),
+        DynamicImage::ImageRgb32F(x) => image::Rgba(x.get_pixel(cx, cy).to_rgba().0.map(from_f32)),
+        DynamicImage::ImageRgba32F(x) => image::Rgba(x.get_pixel(cx, cy).0.map(from_f32)),
+        other => image::Rgba(other.get_pixel(cx, cy).0.map(|x| x as u16 * 257)),
+    }
+}
+
+/// Write a 16-bit RGBA pixel, converting it to the image's color type.
+fn put_rgba16_pixel(image: &mut DynamicImage, cx: u32, cy: u32, px: image::Rgba<u16>) {
+    let to_f32 = |x: u16| x as f32 / 65535.0;
+    match image {
+        DynamicImage::ImageLuma16(x) => x.put_pixel(cx, cy, px.to_luma()),
+        DynamicImage::ImageLumaA16(x) => x.put_pixel(cx, cy, px.to_luma_alpha()),
+        DynamicImage::ImageRgb16(x) => x.put_pixel(cx, cy, px.to_rgb()),
+        DynamicImage::ImageRgba16(x) => x.put_pixel(cx, cy, px),
+        DynamicImage::ImageRgb32F(x) => x.put_pixel(cx, cy, image::Rgba(px.0.map(to_f32)).to_rgb()),
+        DynamicImage::ImageRgba32F(x) => x.put_pixel(cx, cy, image::Rgba(px.0.map(to_f32))),
+        other => other.put_pixel(cx, cy, image::Rgba(px.0.map(|x| ((x as u32 + 128) / 257) as u8))),
+    }
+}
+
+/// The pixel layout of a raw buffer passed across the boundary, parsed from
+/// names such as `"rgba8"` or `"l8"`.
+///
+/// `U32` holds a single native-endian 32-bit sample per pixel, such as the
+/// labels of a `WiGrayImageU32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PixelLayout {
+    L8,
+    La8,
+    Rgb8,
+    Rgba8,
+    Bgra8,
+    U32,
+}
+
+impl PixelLayout {
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelLayout::L8 => 1,
+            PixelLayout::La8 => 2,
+            PixelLayout::Rgb8 => 3,
+            PixelLayout::Rgba8 => 4,
+            PixelLayout::Bgra8 => 4,
+            PixelLayout::U32 => 4,
+        }
+    }
+}
+
+fn parse_pixel_layout(layout: &str) -> Result<PixelLayout, WiError> {
+    match layout.to_lowercase().as_str() {
+        "l8" | "luma8" | "gray8" => Ok(PixelLayout::L8),
+        "la8" | "lumaa8" | "graya8" => Ok(PixelLayout::La8),
+        "rgb8" => Ok(PixelLayout::Rgb8),
+        "rgba8" => Ok(PixelLayout::Rgba8),
+        "bgra8" => Ok(PixelLayout::Bgra8),
+        "u32" => Ok(PixelLayout::U32),
+        other => {
+            let message = format!("unknown pixel layout: {}", other);
+            Err(WiError::new(WiErrorCode::InvalidArgument, message))
+        }
+    }
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE-VIEW - METHODS
+///////////////////////////////////////////////////////////////////////////////
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_width(ctx: *const WiImage) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                x.width() as c_int
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_height(ctx: *const WiImage) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                x.height() as c_int
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_width(ctx: *const WiGrayImage) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                x.width() as c_int
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_height(ctx: *const WiGrayImage) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                x.height() as c_int
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_u32_width(ctx: *const WiGrayImageU32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                x.width() as c_int
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_u32_height(ctx: *const WiGrayImageU32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                x.height() as c_int
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_f32_width(ctx: *const WiGrayImageF32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                x.width() as c_int
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_f32_height(ctx: *const WiGrayImageF32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                x.height() as c_int
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_get_rgba_pixel(ctx: *const WiImage, px: *mut RgbaPixel, cx: u32, cy: u32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                if x.in_bounds(cx, cy) {
+                    let [r, g, b, a] = x.get_pixel(cx, cy).0;
+                    *px = match (&*ctx).1.bgr {
+                        true => RgbaPixel {r: b, g, b: r, a},
+                        false => RgbaPixel {r, g, b, a},
+                    };
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_set_rgba_pixel(ctx: *mut WiImage, cx: u32, cy: u32, px: RgbaPixel) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        let bgr = (&*ctx).1.bgr;
+        match &mut (&mut *ctx).0 {
+            Ok(ref mut x) => {
+                if x.in_bounds(cx, cy) {
+                    let value = match bgr {
+                        true => image::Rgba([px.b, px.g, px.r, px.a]),
+                        false => image::Rgba([px.r, px.g, px.b, px.a]),
+                    };
+                    x.put_pixel(cx, cy, value);
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_get_pixel(ctx: *const WiGrayImage, px: *mut u8, cx: u32, cy: u32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                if x.in_bounds(cx, cy) {
+                    let [l] = x.get_pixel(cx, cy).0;
+                    *px = l;
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_set_pixel(ctx: *mut WiGrayImage, cx: u32, cy: u32, px: u8) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &mut (&mut *ctx).0 {
+            Ok(ref mut x) => {
+                if x.in_bounds(cx, cy) {
+                    let value = image::Luma([px]);
+                    x.put_pixel(cx, cy, value);
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_u32_get_pixel(ctx: *const WiGrayImageU32, px: *mut u32, cx: u32, cy: u32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                if x.in_bounds(cx, cy) {
+                    let [l] = x.get_pixel(cx, cy).0;
+                    *px = l;
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_u32_set_pixel(ctx: *mut WiGrayImage, cx: u32, cy: u32, px: u8) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &mut (&mut *ctx).0 {
+            Ok(ref mut x) => {
+                if x.in_bounds(cx, cy) {
+                    let value = image::Luma([px]);
+                    x.put_pixel(cx, cy, value);
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+/// Read a pixel as 16-bit RGBA. 8-bit images are scaled up, so 255 reads as 65535.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_get_rgba16_pixel(ctx: *const WiImage, px: *mut Rgba16Pixel, cx: u32, cy: u32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                if x.in_bounds(cx, cy) {
+                    let [r, g, b, a] = get_rgba16_pixel(x, cx, cy).0;
+                    *px = match (&*ctx).1.bgr {
+                        true => Rgba16Pixel {r: b, g, b: r, a},
+                        false => Rgba16Pixel {r, g, b, a},
+                    };
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+/// Write a 16-bit RGBA pixel, converted to the color type of the image.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_set_rgba16_pixel(ctx: *mut WiImage, cx: u32, cy: u32, px: Rgba16Pixel) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        let bgr = (&*ctx).1.bgr;
+        match &mut (&mut *ctx).0 {
+            Ok(ref mut x) => {
+                if x.in_bounds(cx, cy) {
+                    let value = match bgr {
+                        true => image::Rgba([px.b, px.g, px.r, px.a]),
+                        false => image::Rgba([px.r, px.g, px.b, px.a]),
+                    };
+                    put_rgba16_pixel(x, cx, cy, value);
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_f32_get_pixel(ctx: *const WiGrayImageF32, px: *mut f32, cx: u32, cy: u32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => {
+                if x.in_bounds(cx, cy) {
+                    let [l] = x.get_pixel(cx, cy).0;
+                    *px = l;
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_f32_set_pixel(ctx: *mut WiGrayImageF32, cx: u32, cy: u32, px: f32) -> c_int {
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &mut (&mut *ctx).0 {
+            Ok(ref mut x) => {
+                if x.in_bounds(cx, cy) {
+                    let value = image::Luma([px]);
+                    x.put_pixel(cx, cy, value);
+                    1
+                } else {
+                    out_of_bounds(cx, cy)
+                }
+            }
+            Err(x) => x.report(-1)
+        }
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE-VIEW - RAW BUFFERS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Check that a buffer of `len` bytes holds `height` rows of `width` pixels,
+/// `stride` bytes apart, and return the number of bytes in a packed row.
+fn check_raw_buffer(
+    len: usize,
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: usize,
+) -> Result<usize, WiError> {
+    let row_len = (width as usize)
+        .checked_mul(bytes_per_pixel)
+        .ok_or_else(|| WiError::new(WiErrorCode::InvalidArgument, "image width is too large"))?;
+    if stride < row_len {
+        let message = format!("stride of {} bytes is smaller than a row of {} bytes", stride, row_len);
+        return Err(WiError::new(WiErrorCode::InvalidArgument, message));
+    }
+    let required = match height {
+        0 => Some(0),
+        _ => stride
+            .checked_mul(height as usize - 1)
+            .and_then(|x| x.checked_add(row_len)),
+    };
+    match required {
+        Some(required) if required <= len => Ok(row_len),
+        Some(required) => {
+            let message = format!("buffer of {} bytes is too small for {} bytes", len, required);
+            Err(WiError::new(WiErrorCode::OutOfBounds, message))
+        }
+        None => Err(WiError::new(WiErrorCode::InvalidArgument, "image height is too large")),
+    }
+}
+
+/// Copy the rows of a strided buffer into a packed one.
+unsafe fn pack_raw_rows(data: *const u8, height: u32, stride: usize, row_len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(row_len * height as usize);
+    for y in 0..height as usize {
+        let row = std::slice::from_raw_parts(data.add(y * stride), row_len);
+        output.extend_from_slice(row);
+    }
+    output
+}
+
+/// Move the rows of a strided buffer to the front, so it's packed in place.
+fn pack_raw_rows_in_place(buffer: &mut Vec<u8>, height: u32, stride: usize, row_len: usize) {
+    if stride != row_len {
+        for y in 1..height as usize {
+            buffer.copy_within(y * stride..y * stride + row_len, y * row_len);
+        }
+    }
+    buffer.truncate(row_len * height as usize);
+}
+
+fn dynamic_image_from_raw(
+    layout: PixelLayout,
+    width: u32,
+    height: u32,
+    mut buffer: Vec<u8>,
+) -> Result<DynamicImage, WiError> {
+    let mismatch = "packed buffer doesn't match the image size";
+    match layout {
+        PixelLayout::L8 => Ok(DynamicImage::ImageLuma8(image::ImageBuffer::from_raw(width, height, buffer).expect(mismatch))),
+        PixelLayout::La8 => Ok(DynamicImage::ImageLumaA8(image::ImageBuffer::from_raw(width, height, buffer).expect(mismatch))),
+        PixelLayout::Rgb8 => Ok(DynamicImage::ImageRgb8(image::ImageBuffer::from_raw(width, height, buffer).expect(mismatch))),
+        PixelLayout::Rgba8 => Ok(DynamicImage::ImageRgba8(image::ImageBuffer::from_raw(width, height, buffer).expect(mismatch))),
+        PixelLayout::Bgra8 => {
+            buffer.chunks_exact_mut(4).for_each(|px| px.swap(0, 2));
+            Ok(DynamicImage::ImageRgba8(image::ImageBuffer::from_raw(width, height, buffer).expect(mismatch)))
+        }
+        PixelLayout::U32 => Err(WiError::new(WiErrorCode::InvalidArgument, "the u32 layout is not supported for images")),
+    }
+}
+
+/// Take back a buffer allocated by `wi_raw_buffer_alloc`.
+unsafe fn adopt_raw_buffer(data: *mut u8, len: usize) -> Vec<u8> {
+    Vec::from_raw_parts(data, len, len)
+}
+
+/// Allocate a zeroed buffer of `len` bytes that can be handed over to
+/// `wi_img_from_raw_adopt` or `wi_grayimg_from_raw_adopt` without copying.
+///
+/// Buffers that are not adopted must be released with `wi_raw_buffer_free`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_raw_buffer_alloc(len: size_t) -> *mut u8 {
+    catch_panic_or(std::ptr::null_mut(), || {
+        let buffer = vec![0u8; len].into_boxed_slice();
+        Box::into_raw(buffer) as *mut u8
+    })
+}
+
+/// Release a buffer allocated by `wi_raw_buffer_alloc`, with the same `len`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_raw_buffer_free(data: *mut u8, len: size_t) {
+    catch_panic_or((), || {
+        if !data.is_null() {
+            std::mem::drop(adopt_raw_buffer(data, len));
+        }
+    })
+}
+
+/// Create an image by copying `height` rows of `width` pixels from `data`.
+///
+/// Rows start `stride` bytes apart, and `layout` is one of `"l8"`, `"la8"`,
+/// `"rgb8"`, `"rgba8"` or `"bgra8"` (stored as RGBA8). The buffer remains
+/// owned by the caller.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_from_raw(
+    data: *const u8,
+    len: size_t,
+    width: u32,
+    height: u32,
+    stride: size_t,
+    layout: *const c_char,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if data.is_null() {
+            return null_argument("data", std::ptr::null_mut());
+        }
+        if layout.is_null() {
+            return null_argument("layout", std::ptr::null_mut());
+        }
+        let result = CStr::from_ptr(layout)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_pixel_layout)
+            .and_then(|layout| {
+                let row_len = check_raw_buffer(len, width, height, stride, layout.bytes_per_pixel())?;
+                let buffer = pack_raw_rows(data, height, stride, row_len);
+                dynamic_image_from_raw(layout, width, height, buffer)
+            });
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+/// Create an image that takes ownership of a buffer from `wi_raw_buffer_alloc`.
+///
+/// The arguments are the same as for `wi_img_from_raw`, with `len` being the
+/// allocated size. Rows are packed in place when `stride` is larger than a row,
+/// so no pixels are copied for packed buffers. The buffer is taken over even
+/// when an error handle is returned, and must not be used or freed afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_from_raw_adopt(
+    data: *mut u8,
+    len: size_t,
+    width: u32,
+    height: u32,
+    stride: size_t,
+    layout: *const c_char,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if data.is_null() {
+            return null_argument("data", std::ptr::null_mut());
+        }
+        let mut buffer = adopt_raw_buffer(data, len);
+        if layout.is_null() {
+            return null_argument("layout", std::ptr::null_mut());
+        }
+        let result = CStr::from_ptr(layout)
+            .to_str()
+            .map_err(WiError::from)
+            .and_then(parse_pixel_layout)
+            .and_then(|layout| {
+                let row_len = check_raw_buffer(len, width, height, stride, layout.bytes_per_pixel())?;
+                pack_raw_rows_in_place(&mut buffer, height, stride, row_len);
+                dynamic_image_from_raw(layout, width, height, buffer)
+            });
+        let result = Box::new(WiImage(result, ImageMetadata::default()));
+        Box::into_raw(result)
+    })
+}
+
+/// Create a grayscale image by copying `height` rows of `width` bytes from `data`,
+/// `stride` bytes apart. The buffer remains owned by the caller.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_from_raw(
+    data: *const u8,
+    len: size_t,
+    width: u32,
+    height: u32,
+    stride: size_t,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if data.is_null() {
+            return null_argument("data", std::ptr::null_mut());
+        }
+        let result = check_raw_buffer(len, width, height, stride, 1).map(|row_len| {
+            let buffer = pack_raw_rows(data, height, stride, row_len);
+            image::GrayImage::from_raw(width, height, buffer).expect("packed buffer doesn't match the image size")
+        });
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Create a grayscale image that takes ownership of a buffer from `wi_raw_buffer_alloc`.
+///
+/// See `wi_img_from_raw_adopt` for the ownership rules.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_from_raw_adopt(
+    data: *mut u8,
+    len: size_t,
+    width: u32,
+    height: u32,
+    stride: size_t,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if data.is_null() {
+            return null_argument("data", std::ptr::null_mut());
+        }
+        let mut buffer = adopt_raw_buffer(data, len);
+        let result = check_raw_buffer(len, width, height, stride, 1).map(|row_len| {
+            pack_raw_rows_in_place(&mut buffer, height, stride, row_len);
+            image::GrayImage::from_raw(width, height, buffer).expect("packed buffer doesn't match the image size")
+        });
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// A read-only pointer to the image's packed pixel buffer, or NULL on error.
+///
+/// The layout follows `wi_img_color`, with multi-byte samples in native byte
+/// order. The pointer stays valid until the image is modified or freed.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_raw_data(ctx: *const WiImage) -> *const u8 {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null());
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.as_bytes().as_ptr(),
+            Err(x) => x.report(std::ptr::null()),
+        }
+    })
+}
+
+/// The size in bytes of the buffer returned by `wi_img_raw_data`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_raw_len(ctx: *const WiImage) -> size_t {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.as_bytes().len(),
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// The distance in bytes between rows of the buffer returned by `wi_img_raw_data`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_raw_stride(ctx: *const WiImage) -> size_t {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.width() as usize * x.color().bytes_per_pixel() as usize,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// A read-only pointer to the grayscale image's packed pixel buffer, or NULL on error.
+///
+/// The pointer stays valid until the image is modified or freed.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_raw_data(ctx: *const WiGrayImage) -> *const u8 {
+    catch_panic_or(std::ptr::null(), || {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null());
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.as_raw().as_ptr(),
+            Err(x) => x.report(std::ptr::null()),
+        }
+    })
+}
+
+/// The size in bytes of the buffer returned by `wi_grayimg_raw_data`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_raw_len(ctx: *const WiGrayImage) -> size_t {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.as_raw().len(),
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+/// The distance in bytes between rows of the buffer returned by `wi_grayimg_raw_data`.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_raw_stride(ctx: *const WiGrayImage) -> size_t {
+    catch_panic_or(0, || {
+        if ctx.is_null() {
+            return null_argument("ctx", 0);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => x.width() as usize,
+            Err(x) => x.report(0),
+        }
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGE-VIEW - REGIONS
+///////////////////////////////////////////////////////////////////////////////
+
+/// Copy the `width` by `height` region at (`cx`, `cy`) of an image of the given
+/// `size` into `output`, converting each pixel to `layout`.
+///
+/// `pixel` returns the RGBA value of a pixel along with its single-channel
+/// value for the `U32` layout. A `stride` of 0 means packed rows.
+#[allow(clippy::too_many_arguments)]
+unsafe fn copy_region<F: Fn(u32, u32) -> ([u8; 4], u32)>(
+    size: (u32, u32),
+    cx: u32,
+    cy: u32,
+    width: u32,
+    height: u32,
+    layout: *const c_char,
+    output: *mut u8,
+    capacity: usize,
+    stride: usize,
+    pixel: F,
+) -> Result<(), WiError> {
+    let layout = parse_pixel_layout(CStr::from_ptr(layout).to_str()?)?;
+    let in_bounds = |start: u32, len: u32, max: u32| start.checked_add(len).is_some_and(|end| end <= max);
+    if !in_bounds(cx, width, size.0) || !in_bounds(cy, height, size.1) {
+        let message = format!(
+            "region of {}x{} at ({}, {}) is out of bounds for a {}x{} image",
+            width, height, cx, cy, size.0, size.1,
+        );
+        return Err(WiError::new(WiErrorCode::OutOfBounds, message));
+    }
+    let bytes_per_pixel = layout.bytes_per_pixel();
+    let stride = match stride {
+        0 => width as usize * bytes_per_pixel,
+        x => x,
+    };
+    let row_len = check_raw_buffer(capacity, width, height, stride, bytes_per_pixel)?;
+    for y in 0..height {
+        let row = std::slice::from_raw_parts_mut(output.add(y as usize * stride), row_len);
+        for (x, out) in row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+            let (rgba, wide) = pixel(cx + x as u32, cy + y);
+            match layout {
+                PixelLayout::L8 => out[0] = image::Rgba(rgba).to_luma()[0],
+                PixelLayout::La8 => {
+                    out[0] = image::Rgba(rgba).to_luma()[0];
+                    out[1] = rgba[3];
+                }
+                PixelLayout::Rgb8 => out.copy_from_slice(&rgba[..3]),
+                PixelLayout::Rgba8 => out.copy_from_slice(&rgba),
+                PixelLayout::Bgra8 => out.copy_from_slice(&[rgba[2], rgba[1], rgba[0], rgba[3]]),
+                PixelLayout::U32 => out.copy_from_slice(&wide.to_ne_bytes()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Convert the result of a copy into the code returned across the boundary.
+fn copy_result(result: Result<(), WiError>) -> WiErrorCode {
+    match result {
+        Ok(()) => WiErrorCode::Ok,
+        Err(x) => x.report(x.code),
+    }
+}
+
+/// Copy a rectangular region of the image into `output`, converted to `layout`.
+///
+/// `layout` is one of `"l8"`, `"la8"`, `"rgb8"`, `"rgba8"`, `"bgra8"` or `"u32"`
+/// (the luma value widened to 32 bits). Rows are written `stride` bytes apart, or
+/// packed if `stride` is 0, and `capacity` is the size of `output` in bytes.
+///
+/// Returns `Ok`, or the code of the error, which is also recorded as the last error.
+/// A region outside of the image gives `OutOfBounds`, as does a buffer that's too small.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_copy_region(
+    ctx: *const WiImage,
+    cx: u32,
+    cy: u32,
+    width: u32,
+    height: u32,
+    layout: *const c_char,
+    output: *mut u8,
+    capacity: size_t,
+    stride: size_t,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiErrorCode::InvalidArgument);
+        }
+        if layout.is_null() {
+            return null_argument("layout", WiErrorCode::InvalidArgument);
+        }
+        if output.is_null() {
+            return null_argument("output", WiErrorCode::InvalidArgument);
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => copy_region(x.dimensions(), cx, cy, width, height, layout, output, capacity, stride, |x1, y1| {
+                let mut px = x.get_pixel(x1, y1);
+                if (&*ctx).1.bgr {
+                    px.0.swap(0, 2);
+                }
+                (px.0, px.to_luma()[0] as u32)
+            }),
+            Err(x) => Err(x.clone()),
+        };
+        copy_result(result)
+    })
+}
+
+/// Copy row `cy` of the image into `output`, converted to `layout`.
+///
+/// See `wi_img_copy_region` for the layouts and return values.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_copy_row(
+    ctx: *const WiImage,
+    cy: u32,
+    layout: *const c_char,
+    output: *mut u8,
+    capacity: size_t,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiErrorCode::InvalidArgument);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => wi_img_copy_region(ctx, 0, cy, x.width(), 1, layout, output, capacity, 0),
+            Err(x) => x.report(x.code),
+        }
+    })
+}
+
+/// Copy a rectangular region of the grayscale image into `output`, converted to `layout`.
+///
+/// See `wi_img_copy_region` for the arguments and return values.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_copy_region(
+    ctx: *const WiGrayImage,
+    cx: u32,
+    cy: u32,
+    width: u32,
+    height: u32,
+    layout: *const c_char,
+    output: *mut u8,
+    capacity: size_t,
+    stride: size_t,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiErrorCode::InvalidArgument);
+        }
+        if layout.is_null() {
+            return null_argument("layout", WiErrorCode::InvalidArgument);
+        }
+        if output.is_null() {
+            return null_argument("output", WiErrorCode::InvalidArgument);
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => copy_region(x.dimensions(), cx, cy, width, height, layout, output, capacity, stride, |x1, y1| {
+                let [l] = x.get_pixel(x1, y1).0;
+                ([l, l, l, 255], l as u32)
+            }),
+            Err(x) => Err(x.clone()),
+        };
+        copy_result(result)
+    })
+}
+
+/// Copy row `cy` of the grayscale image into `output`, converted to `layout`.
+///
+/// See `wi_img_copy_region` for the layouts and return values.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_copy_row(
+    ctx: *const WiGrayImage,
+    cy: u32,
+    layout: *const c_char,
+    output: *mut u8,
+    capacity: size_t,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiErrorCode::InvalidArgument);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => wi_grayimg_copy_region(ctx, 0, cy, x.width(), 1, layout, output, capacity, 0),
+            Err(x) => x.report(x.code),
+        }
+    })
+}
+
+/// Copy a rectangular region of the label image into `output`, converted to `layout`.
+///
+/// Labels are saturated to 255 for the 8-bit layouts, and kept as is for `"u32"`.
+/// See `wi_img_copy_region` for the other arguments and return values.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_u32_copy_region(
+    ctx: *const WiGrayImageU32,
+    cx: u32,
+    cy: u32,
+    width: u32,
+    height: u32,
+    layout: *const c_char,
+    output: *mut u8,
+    capacity: size_t,
+    stride: size_t,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiErrorCode::InvalidArgument);
+        }
+        if layout.is_null() {
+            return null_argument("layout", WiErrorCode::InvalidArgument);
+        }
+        if output.is_null() {
+            return null_argument("output", WiErrorCode::InvalidArgument);
+        }
+        let result = match &(&*ctx).0 {
+            Ok(ref x) => copy_region(x.dimensions(), cx, cy, width, height, layout, output, capacity, stride, |x1, y1| {
+                let [value] = x.get_pixel(x1, y1).0;
+                let l = value.min(255) as u8;
+                ([l, l, l, 255], value)
+            }),
+            Err(x) => Err(x.clone()),
+        };
+        copy_result(result)
+    })
+}
+
+/// Copy row `cy` of the label image into `output`, converted to `layout`.
+///
+/// See `wi_grayimg_u32_copy_region` for the layouts and return values.
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_u32_copy_row(
+    ctx: *const WiGrayImageU32,
+    cy: u32,
+    layout: *const c_char,
+    output: *mut u8,
+    capacity: size_t,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if ctx.is_null() {
+            return null_argument("ctx", WiErrorCode::InvalidArgument);
+        }
+        match &(&*ctx).0 {
+            Ok(ref x) => wi_grayimg_u32_copy_region(ctx, 0, cy, x.width(), 1, layout, output, capacity, 0),
+            Err(x) => x.report(x.code),
+        }
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGEPROC - CONTRAST
+///////////////////////////////////////////////////////////////////////////////
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_contrast_adaptive_threshold(
+    ctx: *const WiGrayImage,
+    block_radius: u32,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::contrast::adaptive_threshold(x, block_radius)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_contrast_equalize_histogram(
+    ctx: *const WiGrayImage,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::contrast::equalize_histogram(x)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_contrast_match_histogram(
+    ctx: *const WiGrayImage,
+    target: *const WiGrayImage,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if target.is_null() {
+            return null_argument("target", std::ptr::null_mut());
+        }
+        match (&(&*ctx).0, &(&*target).0) {
+            (Ok(x), Ok(y)) => {
+                let result = imageproc::contrast::match_histogram(x, y);
+                let result = Box::new(WiGrayImage(Ok(result)));
+                Box::into_raw(result)
+            },
+            (Err(x), _) | (_, Err(x)) => {
+                let result = Box::new(WiGrayImage(Err(x.clone())));
+                Box::into_raw(result)
+            }
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_contrast_otsu_level(
     ctx: *const WiGrayImage,
 ) -> c_int {
-    if ctx.is_null() {
-        return -1;
-    }
-    match &(&*ctx).0 {
-        Ok(x) => imageproc::contrast::otsu_level(x) as c_int,
-        Err(_) => -1,
-    }
+    catch_panic_or(-1, || {
+        if ctx.is_null() {
+            return null_argument("ctx", -1);
+        }
+        match &(&*ctx).0 {
+            Ok(x) => imageproc::contrast::otsu_level(x) as c_int,
+            Err(x) => x.report(-1),
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_contrast_stretch_contrast(
+pub unsafe extern "C" fn wi_grayimg_contrast_stretch_contrast(
     ctx: *const WiGrayImage,
     lower: u8,
     upper: u8,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::contrast::stretch_contrast(x, lower, upper)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::contrast::stretch_contrast(x, lower, upper)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_contrast_threshold(
+pub unsafe extern "C" fn wi_grayimg_contrast_threshold(
     ctx: *const WiGrayImage,
     thresh: u8,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::contrast::threshold(x, thresh)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::contrast::threshold(x, thresh)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
 }
 
 
@@ -1092,207 +7312,812 @@ pub unsafe extern fn wi_grayimg_contrast_threshold(
 
 
 ///////////////////////////////////////////////////////////////////////////////
-// IMAGEPROC - DISTANCE-TRANSFORM
+// IMAGEPROC - DISTANCE-TRANSFORM
+///////////////////////////////////////////////////////////////////////////////
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_distance_transform(
+    ctx: *const WiGrayImage,
+    norm: *const c_char,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if norm.is_null() {
+            return null_argument("norm", std::ptr::null_mut());
+        }
+        let value = CStr::from_ptr(norm)
+            .to_str()
+            .ok()
+            .and_then(|x| match x {
+                "L1" => Some(imageproc::distance_transform::Norm::L1),
+                "LInf" => Some(imageproc::distance_transform::Norm::LInf),
+                _ => None,
+            });
+        if let Some(value) = value {
+            let result = match &(&*ctx).0 {
+                Ok(x) => Ok(imageproc::distance_transform::distance_transform(x, value)),
+                Err(ref x) => Err(x.clone()),
+            };
+            let result = Box::new(WiGrayImage(result));
+            Box::into_raw(result)
+        } else {
+            WiError::new(WiErrorCode::InvalidArgument, "invalid norm").report(std::ptr::null_mut())
+        }
+    })
+}
+
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGEPROC - DRAWING
+///////////////////////////////////////////////////////////////////////////////
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGEPROC - EDGES
+///////////////////////////////////////////////////////////////////////////////
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_edges_canny(
+    ctx: *const WiGrayImage,
+    low_threshold: f32, 
+    high_threshold: f32,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::edges::canny(x, low_threshold, high_threshold)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGEPROC - FILTER
+///////////////////////////////////////////////////////////////////////////////
+
+/// Check that a gaussian standard deviation is usable, as imageproc panics otherwise.
+fn check_sigma(sigma: f32) -> Result<(), WiError> {
+    if sigma.is_nan() || sigma <= 0.0 {
+        let message = format!("sigma must be greater than 0, got {}", sigma);
+        return Err(WiError::new(WiErrorCode::InvalidArgument, message));
+    }
+    Ok(())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_box_filter(
+    ctx: *const WiGrayImage,
+    x_radius: u32, 
+    y_radius: u32,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::filter::box_filter(x, x_radius, y_radius)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Apply `wi_grayimg_box_filter` to each color channel of the image, and to
+/// alpha unless `keep_alpha` is non-zero. The result is 8-bit.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_box_filter(
+    ctx: *const WiImage,
+    x_radius: u32,
+    y_radius: u32,
+    keep_alpha: c_int,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => filter_channels(x, keep_alpha != 0, |channel| imageproc::filter::box_filter(channel, x_radius, y_radius)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_filter3x3(
+    ctx: *const WiGrayImage,
+    value: *const f32,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if value.is_null() {
+            return null_argument("value", std::ptr::null_mut());
+        }
+        let value = [
+            *value.offset(0),
+            *value.offset(1),
+            *value.offset(2),
+            *value.offset(3),
+            *value.offset(4),
+            *value.offset(5),
+            *value.offset(6),
+            *value.offset(7),
+            *value.offset(8),
+        ];
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::filter::filter3x3(x, &value)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_filter_gaussian_blur_f32(
+    ctx: *const WiGrayImage,
+    sigma: f32
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => check_sigma(sigma).map(|_| imageproc::filter::gaussian_blur_f32(x, sigma)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Apply `wi_grayimg_filter_gaussian_blur_f32` to each color channel of the image,
+/// and to alpha unless `keep_alpha` is non-zero. The result is 8-bit.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_filter_gaussian_blur_f32(
+    ctx: *const WiImage,
+    sigma: f32,
+    keep_alpha: c_int,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => check_sigma(sigma).and_then(|_| {
+                filter_channels(x, keep_alpha != 0, |channel| imageproc::filter::gaussian_blur_f32(channel, sigma))
+            }),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_f32_filter_gaussian_blur(
+    ctx: *const WiGrayImageF32,
+    sigma: f32
+) -> *mut WiGrayImageF32 {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => check_sigma(sigma).map(|_| imageproc::filter::gaussian_blur_f32(x, sigma)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImageF32(result));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_f32_filter3x3(
+    ctx: *const WiGrayImageF32,
+    value: *const f32,
+) -> *mut WiGrayImageF32 {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if value.is_null() {
+            return null_argument("value", std::ptr::null_mut());
+        }
+        let value = std::slice::from_raw_parts(value, 9);
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::filter::filter3x3::<_, f32, f32>(x, value)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImageF32(result));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_filter_median_filter(
+    ctx: *const WiGrayImage,
+    x_radius: u32, 
+    y_radius: u32,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::filter::median_filter(x, x_radius, y_radius)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Apply `wi_grayimg_filter_median_filter` to each color channel of the image,
+/// and to alpha unless `keep_alpha` is non-zero. The result is 8-bit.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_filter_median_filter(
+    ctx: *const WiImage,
+    x_radius: u32,
+    y_radius: u32,
+    keep_alpha: c_int,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => filter_channels(x, keep_alpha != 0, |channel| imageproc::filter::median_filter(channel, x_radius, y_radius)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_filter_sharpen3x3(
+    ctx: *const WiGrayImage,
+    low_threshold: f32, 
+    high_threshold: f32,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::filter::sharpen3x3(x)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Apply `wi_grayimg_filter_sharpen3x3` to each color channel of the image,
+/// and to alpha unless `keep_alpha` is non-zero. The result is 8-bit.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_filter_sharpen3x3(
+    ctx: *const WiImage,
+    keep_alpha: c_int,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => filter_channels(x, keep_alpha != 0, imageproc::filter::sharpen3x3),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wi_grayimg_filter_sharpen_gaussian(
+    ctx: *const WiGrayImage,
+    sigma: f32,
+    amount: f32,
+) -> *mut WiGrayImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => check_sigma(sigma).map(|_| imageproc::filter::sharpen_gaussian(x, sigma, amount)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Apply `wi_grayimg_filter_sharpen_gaussian` to each color channel of the image,
+/// and to alpha unless `keep_alpha` is non-zero. The result is 8-bit.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_filter_sharpen_gaussian(
+    ctx: *const WiImage,
+    sigma: f32,
+    amount: f32,
+    keep_alpha: c_int,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => check_sigma(sigma).and_then(|_| {
+                filter_channels(x, keep_alpha != 0, |channel| imageproc::filter::sharpen_gaussian(channel, sigma, amount))
+            }),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+
+///////////////////////////////////////////////////////////////////////////////
+// IMAGEPROC - GEOMETRIC-TRANSFORMATIONS
 ///////////////////////////////////////////////////////////////////////////////
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_distance_transform(
+pub unsafe extern "C" fn wi_grayimg_geometric_transformations_translate(
     ctx: *const WiGrayImage,
-    norm: *const c_char,
+    t1: i32,
+    t2: i32,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if norm.is_null() {
-        return std::ptr::null_mut();
-    }
-    let value = CStr::from_ptr(norm)
-        .to_str()
-        .ok()
-        .and_then(|x| match x {
-            "L1" => Some(imageproc::distance_transform::Norm::L1),
-            "LInf" => Some(imageproc::distance_transform::Norm::LInf),
-            _ => None,
-        });
-    if let Some(value) = value {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
         let result = match &(&*ctx).0 {
-            Ok(x) => Ok(imageproc::distance_transform::distance_transform(x, value)),
+            Ok(x) => Ok(imageproc::geometric_transformations::translate(x, (t1, t2))),
             Err(ref x) => Err(x.clone()),
         };
         let result = Box::new(WiGrayImage(result));
         Box::into_raw(result)
-    } else {
-        std::ptr::null_mut()
-    }
+    })
 }
 
-
-
-
-///////////////////////////////////////////////////////////////////////////////
-// IMAGEPROC - DRAWING
-///////////////////////////////////////////////////////////////////////////////
-
-///////////////////////////////////////////////////////////////////////////////
-// IMAGEPROC - EDGES
-///////////////////////////////////////////////////////////////////////////////
-
+/// Rotate the grayscale image clockwise by `degrees` about its center, filling
+/// uncovered pixels with `background`. See `wi_img_rotate` for the other arguments.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_edges_canny(
+pub unsafe extern "C" fn wi_grayimg_rotate(
     ctx: *const WiGrayImage,
-    low_threshold: f32, 
-    high_threshold: f32,
+    degrees: f32,
+    interpolation: *const c_char,
+    background: u8,
+    expand: c_int,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::edges::canny(x, low_threshold, high_threshold)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if interpolation.is_null() {
+            return null_argument("interpolation", std::ptr::null_mut());
+        }
+        let go = |x: &image::GrayImage| -> Result<image::GrayImage, WiError> {
+            let interpolation = parse_interpolation(CStr::from_ptr(interpolation).to_str()?)?;
+            if !degrees.is_finite() {
+                let message = format!("invalid rotation angle: {}", degrees);
+                return Err(WiError::new(WiErrorCode::InvalidArgument, message));
+            }
+            let (projection, width, height) = rotation_projection(x.width(), x.height(), degrees, expand != 0);
+            Ok(warp_gray_image(x, &projection, interpolation, background, width, height))
+        };
+        let result = match &(&*ctx).0 {
+            Ok(x) => go(x),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
 }
 
-///////////////////////////////////////////////////////////////////////////////
-// IMAGEPROC - FILTER
-///////////////////////////////////////////////////////////////////////////////
+/// The output size of a warp: the requested one, or the size of the source
+/// if both sides are 0.
+fn warp_output_size(width: u32, height: u32, source: (u32, u32)) -> Result<(u32, u32), WiError> {
+    match (width, height) {
+        (0, 0) => Ok(source),
+        (0, _) | (_, 0) => {
+            let message = format!("invalid warp output size: {}x{}", width, height);
+            Err(WiError::new(WiErrorCode::InvalidArgument, message))
+        }
+        size => Ok(size),
+    }
+}
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_box_filter(
+/// Warp a grayscale image by a matrix of `len` floats: 6 for an affine
+/// transformation, and 9 for a projective one.
+unsafe fn warp_grayimg(
     ctx: *const WiGrayImage,
-    x_radius: u32, 
-    y_radius: u32,
+    matrix: *const f32,
+    len: usize,
+    interpolation: *const c_char,
+    background: u8,
+    width: u32,
+    height: u32,
 ) -> *mut WiGrayImage {
     if ctx.is_null() {
-        return std::ptr::null_mut();
+        return null_argument("ctx", std::ptr::null_mut());
+    }
+    if matrix.is_null() {
+        return null_argument("matrix", std::ptr::null_mut());
     }
+    if interpolation.is_null() {
+        return null_argument("interpolation", std::ptr::null_mut());
+    }
+    let go = |x: &image::GrayImage| -> Result<image::GrayImage, WiError> {
+        let interpolation = parse_interpolation(CStr::from_ptr(interpolation).to_str()?)?;
+        let projection = parse_warp_matrix(matrix, len)?;
+        let (width, height) = warp_output_size(width, height, x.dimensions())?;
+        Ok(warp_gray_image(x, &projection, interpolation, background, width, height))
+    };
     let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::filter::box_filter(x, x_radius, y_radius)),
+        Ok(x) => go(x),
         Err(ref x) => Err(x.clone()),
     };
     let result = Box::new(WiGrayImage(result));
     Box::into_raw(result)
 }
 
+/// Apply an affine transformation to the grayscale image.
+/// See `wi_img_warp_affine` for the arguments.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_filter3x3(
+pub unsafe extern "C" fn wi_grayimg_warp_affine(
     ctx: *const WiGrayImage,
-    value: *const f32,
+    matrix: *const f32,
+    interpolation: *const c_char,
+    background: u8,
+    width: u32,
+    height: u32,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if value.is_null() {
-        return std::ptr::null_mut();
-    }
-    let value = [
-        *value.offset(0),
-        *value.offset(1),
-        *value.offset(2),
-        *value.offset(3),
-        *value.offset(4),
-        *value.offset(5),
-        *value.offset(6),
-        *value.offset(7),
-        *value.offset(8),
-    ];
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::filter::filter3x3(x, &value)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    catch_panic(|| {
+        warp_grayimg(ctx, matrix, 6, interpolation, background, width, height)
+    })
 }
 
+/// Apply a projective transformation (homography) to the grayscale image.
+/// See `wi_img_warp_perspective` for the arguments.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_filter_gaussian_blur_f32(
+pub unsafe extern "C" fn wi_grayimg_warp_perspective(
     ctx: *const WiGrayImage,
-    sigma: f32
+    matrix: *const f32,
+    interpolation: *const c_char,
+    background: u8,
+    width: u32,
+    height: u32,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::filter::gaussian_blur_f32(x, sigma)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    catch_panic(|| {
+        warp_grayimg(ctx, matrix, 9, interpolation, background, width, height)
+    })
 }
 
+/// Translate the image by (`t1`, `t2`) pixels. Uncovered pixels are black, and
+/// transparent for images with alpha. The result is 8-bit.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_filter_median_filter(
-    ctx: *const WiGrayImage,
-    x_radius: u32, 
-    y_radius: u32,
-) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
+pub unsafe extern "C" fn wi_img_geometric_transformations_translate(
+    ctx: *const WiImage,
+    t1: i32,
+    t2: i32,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(map_rgb8_or_rgba8(
+                x,
+                |rgb| imageproc::geometric_transformations::translate(rgb, (t1, t2)),
+                |rgba| imageproc::geometric_transformations::translate(rgba, (t1, t2)),
+            )),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
+}
+
+fn parse_interpolation(interpolation: &str) -> Result<imageproc::geometric_transformations::Interpolation, WiError> {
+    use imageproc::geometric_transformations::Interpolation;
+    match interpolation.to_lowercase().as_str() {
+        "nearest" => Ok(Interpolation::Nearest),
+        "bilinear" => Ok(Interpolation::Bilinear),
+        "bicubic" => Ok(Interpolation::Bicubic),
+        other => {
+            let message = format!("unknown interpolation: {}", other);
+            Err(WiError::new(WiErrorCode::InvalidArgument, message))
+        }
     }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::filter::median_filter(x, x_radius, y_radius)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
 }
 
+/// Warp the image into a `width` by `height` canvas, filling pixels outside of
+/// the source with `background`. The result is `"rgba:8"` if the image has alpha
+/// or `background` isn't opaque, and `"rgb:8"` otherwise.
+fn warp_image(
+    image: &DynamicImage,
+    projection: &imageproc::geometric_transformations::Projection,
+    interpolation: imageproc::geometric_transformations::Interpolation,
+    background: RgbaPixel,
+    width: u32,
+    height: u32,
+) -> DynamicImage {
+    let RgbaPixel { r, g, b, a } = background;
+    if image.color().has_alpha() || a != 255 {
+        let mut output = image::RgbaImage::new(width, height);
+        let default = image::Rgba([r, g, b, a]);
+        imageproc::geometric_transformations::warp_into(&image.to_rgba8(), projection, interpolation, default, &mut output);
+        DynamicImage::ImageRgba8(output)
+    } else {
+        let mut output = image::RgbImage::new(width, height);
+        let default = image::Rgb([r, g, b]);
+        imageproc::geometric_transformations::warp_into(&image.to_rgb8(), projection, interpolation, default, &mut output);
+        DynamicImage::ImageRgb8(output)
+    }
+}
 
-#[no_mangle]
-pub unsafe extern fn wi_grayimg_filter_sharpen3x3(
-    ctx: *const WiGrayImage,
-    low_threshold: f32, 
-    high_threshold: f32,
-) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
+/// Warp the grayscale image into a `width` by `height` canvas, filling pixels
+/// outside of the source with `background`.
+fn warp_gray_image(
+    image: &image::GrayImage,
+    projection: &imageproc::geometric_transformations::Projection,
+    interpolation: imageproc::geometric_transformations::Interpolation,
+    background: u8,
+    width: u32,
+    height: u32,
+) -> image::GrayImage {
+    let mut output = image::GrayImage::new(width, height);
+    let default = image::Luma([background]);
+    imageproc::geometric_transformations::warp_into(image, projection, interpolation, default, &mut output);
+    output
+}
+
+/// Build a projection from a row-major matrix of 6 (affine) or 9 (projective) floats.
+unsafe fn parse_warp_matrix(
+    matrix: *const f32,
+    len: usize,
+) -> Result<imageproc::geometric_transformations::Projection, WiError> {
+    let values = std::slice::from_raw_parts(matrix, len);
+    let mut transform = [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+    transform[..len].copy_from_slice(values);
+    if transform.iter().any(|x| !x.is_finite()) {
+        return Err(WiError::new(WiErrorCode::InvalidArgument, "warp matrix contains non-finite values"));
     }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::filter::sharpen3x3(x)),
-        Err(ref x) => Err(x.clone()),
+    imageproc::geometric_transformations::Projection::from_matrix(transform)
+        .ok_or_else(|| WiError::new(WiErrorCode::InvalidArgument, "warp matrix is not invertible"))
+}
+
+/// The projection that rotates a `width` by `height` image clockwise about its
+/// center, and the size of the output. With `expand`, the output grows to fit
+/// the whole rotated image.
+fn rotation_projection(
+    width: u32,
+    height: u32,
+    degrees: f32,
+    expand: bool,
+) -> (imageproc::geometric_transformations::Projection, u32, u32) {
+    use imageproc::geometric_transformations::Projection;
+    let theta = degrees.to_radians();
+    let (out_width, out_height) = if expand {
+        let (sin, cos) = theta.sin_cos();
+        let (w, h) = (width as f32, height as f32);
+        // Shave off float error, so that e.g. a 90 degree turn doesn't grow by a pixel.
+        let fit = |x: f32| (x - 1e-3).ceil().max(0.0) as u32;
+        (fit(w * cos.abs() + h * sin.abs()), fit(w * sin.abs() + h * cos.abs()))
+    } else {
+        (width, height)
     };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    let projection = Projection::translate(out_width as f32 / 2.0, out_height as f32 / 2.0)
+        * Projection::rotate(theta)
+        * Projection::translate(-(width as f32) / 2.0, -(height as f32) / 2.0);
+    (projection, out_width, out_height)
 }
 
+/// Rotate the image clockwise by `degrees` about its center.
+///
+/// `interpolation` is one of `"nearest"`, `"bilinear"` or `"bicubic"`. Pixels
+/// outside of the source are filled with `background`; give it an alpha of 0
+/// for a transparent fill. When `expand` is non-zero the canvas grows to fit
+/// the whole rotated image, otherwise it keeps its size and corners are cut off.
+///
+/// The result is `"rgba:8"` if the image has alpha or `background` isn't
+/// opaque, and `"rgb:8"` otherwise. Its EXIF orientation is reset to 1.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_filter_sharpen_gaussian(
-    ctx: *const WiGrayImage,
-    sigma: f32,
-    amount: f32,
-) -> *mut WiGrayImage {
+pub unsafe extern "C" fn wi_img_rotate(
+    ctx: *const WiImage,
+    degrees: f32,
+    interpolation: *const c_char,
+    background: RgbaPixel,
+    expand: c_int,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if interpolation.is_null() {
+            return null_argument("interpolation", std::ptr::null_mut());
+        }
+        let go = |x: &DynamicImage| -> Result<DynamicImage, WiError> {
+            let interpolation = parse_interpolation(CStr::from_ptr(interpolation).to_str()?)?;
+            if !degrees.is_finite() {
+                let message = format!("invalid rotation angle: {}", degrees);
+                return Err(WiError::new(WiErrorCode::InvalidArgument, message));
+            }
+            let (projection, width, height) = rotation_projection(x.width(), x.height(), degrees, expand != 0);
+            Ok(warp_image(x, &projection, interpolation, background, width, height))
+        };
+        let result = match &(&*ctx).0 {
+            Ok(x) => go(x),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.reoriented()));
+        Box::into_raw(result)
+    })
+}
+
+/// Warp an image by a matrix of `len` floats: 6 for an affine transformation,
+/// and 9 for a projective one.
+unsafe fn warp_img(
+    ctx: *const WiImage,
+    matrix: *const f32,
+    len: usize,
+    interpolation: *const c_char,
+    background: RgbaPixel,
+    width: u32,
+    height: u32,
+) -> *mut WiImage {
     if ctx.is_null() {
-        return std::ptr::null_mut();
+        return null_argument("ctx", std::ptr::null_mut());
+    }
+    if matrix.is_null() {
+        return null_argument("matrix", std::ptr::null_mut());
     }
+    if interpolation.is_null() {
+        return null_argument("interpolation", std::ptr::null_mut());
+    }
+    let go = |x: &DynamicImage| -> Result<DynamicImage, WiError> {
+        let interpolation = parse_interpolation(CStr::from_ptr(interpolation).to_str()?)?;
+        let projection = parse_warp_matrix(matrix, len)?;
+        let (width, height) = warp_output_size(width, height, x.dimensions())?;
+        Ok(warp_image(x, &projection, interpolation, background, width, height))
+    };
     let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::filter::sharpen_gaussian(x, sigma, amount)),
+        Ok(x) => go(x),
         Err(ref x) => Err(x.clone()),
     };
-    let result = Box::new(WiGrayImage(result));
+    let result = Box::new(WiImage(result, (&*ctx).1.reoriented()));
     Box::into_raw(result)
 }
 
+/// Apply an affine transformation to the image.
+///
+/// `matrix` points to 6 floats, the first two rows of a row-major 3x3 matrix
+/// that maps source coordinates to output coordinates. The output is `width`
+/// by `height` pixels, or the size of the source if both are 0; only one of
+/// them being 0 is an `InvalidArgument` error. See `wi_img_rotate` for
+/// `interpolation`, `background`, the color type and the EXIF orientation of
+/// the result. A matrix that isn't invertible gives an `InvalidArgument` error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_warp_affine(
+    ctx: *const WiImage,
+    matrix: *const f32,
+    interpolation: *const c_char,
+    background: RgbaPixel,
+    width: u32,
+    height: u32,
+) -> *mut WiImage {
+    catch_panic(|| {
+        warp_img(ctx, matrix, 6, interpolation, background, width, height)
+    })
+}
 
-///////////////////////////////////////////////////////////////////////////////
-// IMAGEPROC - GEOMETRIC-TRANSFORMATIONS
-///////////////////////////////////////////////////////////////////////////////
-
+/// Apply a projective transformation (homography) to the image.
+///
+/// `matrix` points to 9 floats, a row-major 3x3 matrix that maps source
+/// coordinates to output coordinates, such as the one computed by
+/// `wi_homography_from_points`. See `wi_img_warp_affine` for the other arguments.
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_geometric_transformations_translate(
-    ctx: *const WiGrayImage,
-    t1: i32,
-    t2: i32,
-) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
+pub unsafe extern "C" fn wi_img_warp_perspective(
+    ctx: *const WiImage,
+    matrix: *const f32,
+    interpolation: *const c_char,
+    background: RgbaPixel,
+    width: u32,
+    height: u32,
+) -> *mut WiImage {
+    catch_panic(|| {
+        warp_img(ctx, matrix, 9, interpolation, background, width, height)
+    })
+}
+
+/// Solve `a * x = b` for a small square system, with partial pivoting.
+fn solve_linear_system<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    for col in 0..N {
+        let pivot = (col..N).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+        let pivot_row = a[col];
+        for row in col + 1..N {
+            let factor = a[row][col] / pivot_row[col];
+            for (value, pivot) in a[row].iter_mut().zip(pivot_row.iter()).skip(col) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[col];
+        }
     }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::geometric_transformations::translate(x, (t1, t2))),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let sum: f64 = (row + 1..N).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+/// Compute the homography that maps four source points onto four destination points.
+///
+/// `from` and `to` each point to 8 floats, the x and y coordinates of the four
+/// points. The row-major 3x3 matrix is written to the 9 floats at `matrix`,
+/// ready for `wi_img_warp_perspective`. Returns `Ok`, or `InvalidArgument` if
+/// three of the points are collinear; errors are also recorded as the last error.
+#[no_mangle]
+pub unsafe extern "C" fn wi_homography_from_points(
+    from: *const f32,
+    to: *const f32,
+    matrix: *mut f32,
+) -> WiErrorCode {
+    catch_panic_or(WiErrorCode::Internal, || {
+        if from.is_null() {
+            return null_argument("from", WiErrorCode::InvalidArgument);
+        }
+        if to.is_null() {
+            return null_argument("to", WiErrorCode::InvalidArgument);
+        }
+        if matrix.is_null() {
+            return null_argument("matrix", WiErrorCode::InvalidArgument);
+        }
+        let from = std::slice::from_raw_parts(from, 8);
+        let to = std::slice::from_raw_parts(to, 8);
+        let mut a = [[0.0f64; 8]; 8];
+        let mut b = [0.0f64; 8];
+        for i in 0..4 {
+            let (x, y) = (from[2 * i] as f64, from[2 * i + 1] as f64);
+            let (u, v) = (to[2 * i] as f64, to[2 * i + 1] as f64);
+            a[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y];
+            a[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y];
+            b[2 * i] = u;
+            b[2 * i + 1] = v;
+        }
+        match solve_linear_system(a, b) {
+            Some(h) if h.iter().all(|x| x.is_finite()) => {
+                let output = std::slice::from_raw_parts_mut(matrix, 9);
+                for (out, value) in output.iter_mut().zip(h.iter().chain(&[1.0])) {
+                    *out = *value as f32;
+                }
+                WiErrorCode::Ok
+            }
+            _ => {
+                let error = WiError::new(WiErrorCode::InvalidArgument, "points don't define a homography");
+                error.report(error.code)
+            }
+        }
+    })
 }
 
 
@@ -1336,131 +8161,139 @@ pub unsafe extern fn wi_grayimg_geometric_transformations_translate(
 ///////////////////////////////////////////////////////////////////////////////
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_morphology_close(
+pub unsafe extern "C" fn wi_grayimg_morphology_close(
     ctx: *const WiGrayImage,
     norm: *const c_char,
     k: u8,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if norm.is_null() {
-        return std::ptr::null_mut();
-    }
-    let norm = CStr::from_ptr(norm)
-        .to_str()
-        .ok()
-        .and_then(|x| match x {
-            "L1" => Some(imageproc::distance_transform::Norm::L1),
-            "LInf" => Some(imageproc::distance_transform::Norm::LInf),
-            _ => None,
-        });
-    if let Some(norm) = norm {
-        let result = match &(&*ctx).0 {
-            Ok(x) => Ok(imageproc::morphology::close(x, norm, k)),
-            Err(ref x) => Err(x.clone()),
-        };
-        let result = Box::new(WiGrayImage(result));
-        Box::into_raw(result)
-    } else {
-        std::ptr::null_mut()
-    }
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if norm.is_null() {
+            return null_argument("norm", std::ptr::null_mut());
+        }
+        let norm = CStr::from_ptr(norm)
+            .to_str()
+            .ok()
+            .and_then(|x| match x {
+                "L1" => Some(imageproc::distance_transform::Norm::L1),
+                "LInf" => Some(imageproc::distance_transform::Norm::LInf),
+                _ => None,
+            });
+        if let Some(norm) = norm {
+            let result = match &(&*ctx).0 {
+                Ok(x) => Ok(imageproc::morphology::close(x, norm, k)),
+                Err(ref x) => Err(x.clone()),
+            };
+            let result = Box::new(WiGrayImage(result));
+            Box::into_raw(result)
+        } else {
+            WiError::new(WiErrorCode::InvalidArgument, "invalid norm").report(std::ptr::null_mut())
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_morphology_dilate(
+pub unsafe extern "C" fn wi_grayimg_morphology_dilate(
     ctx: *const WiGrayImage,
     norm: *const c_char,
     k: u8,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if norm.is_null() {
-        return std::ptr::null_mut();
-    }
-    let norm = CStr::from_ptr(norm)
-        .to_str()
-        .ok()
-        .and_then(|x| match x {
-            "L1" => Some(imageproc::distance_transform::Norm::L1),
-            "LInf" => Some(imageproc::distance_transform::Norm::LInf),
-            _ => None,
-        });
-    if let Some(norm) = norm {
-        let result = match &(&*ctx).0 {
-            Ok(x) => Ok(imageproc::morphology::dilate(x, norm, k)),
-            Err(ref x) => Err(x.clone()),
-        };
-        let result = Box::new(WiGrayImage(result));
-        Box::into_raw(result)
-    } else {
-        std::ptr::null_mut()
-    }
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if norm.is_null() {
+            return null_argument("norm", std::ptr::null_mut());
+        }
+        let norm = CStr::from_ptr(norm)
+            .to_str()
+            .ok()
+            .and_then(|x| match x {
+                "L1" => Some(imageproc::distance_transform::Norm::L1),
+                "LInf" => Some(imageproc::distance_transform::Norm::LInf),
+                _ => None,
+            });
+        if let Some(norm) = norm {
+            let result = match &(&*ctx).0 {
+                Ok(x) => Ok(imageproc::morphology::dilate(x, norm, k)),
+                Err(ref x) => Err(x.clone()),
+            };
+            let result = Box::new(WiGrayImage(result));
+            Box::into_raw(result)
+        } else {
+            WiError::new(WiErrorCode::InvalidArgument, "invalid norm").report(std::ptr::null_mut())
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_morphology_erode(
+pub unsafe extern "C" fn wi_grayimg_morphology_erode(
     ctx: *const WiGrayImage,
     norm: *const c_char,
     k: u8,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if norm.is_null() {
-        return std::ptr::null_mut();
-    }
-    let norm = CStr::from_ptr(norm)
-        .to_str()
-        .ok()
-        .and_then(|x| match x {
-            "L1" => Some(imageproc::distance_transform::Norm::L1),
-            "LInf" => Some(imageproc::distance_transform::Norm::LInf),
-            _ => None,
-        });
-    if let Some(norm) = norm {
-        let result = match &(&*ctx).0 {
-            Ok(x) => Ok(imageproc::morphology::erode(x, norm, k)),
-            Err(ref x) => Err(x.clone()),
-        };
-        let result = Box::new(WiGrayImage(result));
-        Box::into_raw(result)
-    } else {
-        std::ptr::null_mut()
-    }
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if norm.is_null() {
+            return null_argument("norm", std::ptr::null_mut());
+        }
+        let norm = CStr::from_ptr(norm)
+            .to_str()
+            .ok()
+            .and_then(|x| match x {
+                "L1" => Some(imageproc::distance_transform::Norm::L1),
+                "LInf" => Some(imageproc::distance_transform::Norm::LInf),
+                _ => None,
+            });
+        if let Some(norm) = norm {
+            let result = match &(&*ctx).0 {
+                Ok(x) => Ok(imageproc::morphology::erode(x, norm, k)),
+                Err(ref x) => Err(x.clone()),
+            };
+            let result = Box::new(WiGrayImage(result));
+            Box::into_raw(result)
+        } else {
+            WiError::new(WiErrorCode::InvalidArgument, "invalid norm").report(std::ptr::null_mut())
+        }
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_morphology_open(
+pub unsafe extern "C" fn wi_grayimg_morphology_open(
     ctx: *const WiGrayImage,
     norm: *const c_char,
     k: u8,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if norm.is_null() {
-        return std::ptr::null_mut();
-    }
-    let norm = CStr::from_ptr(norm)
-        .to_str()
-        .ok()
-        .and_then(|x| match x {
-            "L1" => Some(imageproc::distance_transform::Norm::L1),
-            "LInf" => Some(imageproc::distance_transform::Norm::LInf),
-            _ => None,
-        });
-    if let Some(norm) = norm {
-        let result = match &(&*ctx).0 {
-            Ok(x) => Ok(imageproc::morphology::open(x, norm, k)),
-            Err(ref x) => Err(x.clone()),
-        };
-        let result = Box::new(WiGrayImage(result));
-        Box::into_raw(result)
-    } else {
-        std::ptr::null_mut()
-    }
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if norm.is_null() {
+            return null_argument("norm", std::ptr::null_mut());
+        }
+        let norm = CStr::from_ptr(norm)
+            .to_str()
+            .ok()
+            .and_then(|x| match x {
+                "L1" => Some(imageproc::distance_transform::Norm::L1),
+                "LInf" => Some(imageproc::distance_transform::Norm::LInf),
+                _ => None,
+            });
+        if let Some(norm) = norm {
+            let result = match &(&*ctx).0 {
+                Ok(x) => Ok(imageproc::morphology::open(x, norm, k)),
+                Err(ref x) => Err(x.clone()),
+            };
+            let result = Box::new(WiGrayImage(result));
+            Box::into_raw(result)
+        } else {
+            WiError::new(WiErrorCode::InvalidArgument, "invalid norm").report(std::ptr::null_mut())
+        }
+    })
 }
 
 
@@ -1470,38 +8303,42 @@ pub unsafe extern fn wi_grayimg_morphology_open(
 ///////////////////////////////////////////////////////////////////////////////
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_gaussian_noise(
+pub unsafe extern "C" fn wi_grayimg_gaussian_noise(
     ctx: *const WiGrayImage,
     mean: f64, 
     stddev: f64, 
     seed: u64,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::noise::gaussian_noise(x, mean, stddev, seed)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::noise::gaussian_noise(x, mean, stddev, seed)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
 }
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_salt_and_pepper_noise(
+pub unsafe extern "C" fn wi_grayimg_salt_and_pepper_noise(
     ctx: *const WiGrayImage,
     rate: f64, 
     seed: u64,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::noise::salt_and_pepper_noise(x, rate, seed)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::noise::salt_and_pepper_noise(x, rate, seed)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
 }
 
 
@@ -1522,36 +8359,38 @@ pub unsafe extern fn wi_grayimg_salt_and_pepper_noise(
 ///////////////////////////////////////////////////////////////////////////////
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_region_labelling_connected_components(
+pub unsafe extern "C" fn wi_grayimg_region_labelling_connected_components(
     ctx: *const WiGrayImage,
     conn: *const c_char,
     background: u8,
 ) -> *mut WiGrayImageU32 {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    if conn.is_null() {
-        return std::ptr::null_mut();
-    }
-    let conn = CStr::from_ptr(conn)
-        .to_str()
-        .ok()
-        .and_then(|x| match x {
-            "four" => Some(imageproc::region_labelling::Connectivity::Four),
-            "eight" => Some(imageproc::region_labelling::Connectivity::Eight),
-            _ => None,
-        });
-    if let Some(conn) = conn {
-        let bg = image::Luma([background]);
-        let result = match &(&*ctx).0 {
-            Ok(x) => Ok(imageproc::region_labelling::connected_components(x, conn, bg)),
-            Err(ref x) => Err(x.clone()),
-        };
-        let result = Box::new(WiGrayImageU32(result));
-        Box::into_raw(result)
-    } else {
-        std::ptr::null_mut()
-    }
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        if conn.is_null() {
+            return null_argument("conn", std::ptr::null_mut());
+        }
+        let conn = CStr::from_ptr(conn)
+            .to_str()
+            .ok()
+            .and_then(|x| match x {
+                "four" => Some(imageproc::region_labelling::Connectivity::Four),
+                "eight" => Some(imageproc::region_labelling::Connectivity::Eight),
+                _ => None,
+            });
+        if let Some(conn) = conn {
+            let bg = image::Luma([background]);
+            let result = match &(&*ctx).0 {
+                Ok(x) => Ok(imageproc::region_labelling::connected_components(x, conn, bg)),
+                Err(ref x) => Err(x.clone()),
+            };
+            let result = Box::new(WiGrayImageU32(result));
+            Box::into_raw(result)
+        } else {
+            WiError::new(WiErrorCode::InvalidArgument, "invalid connectivity").report(std::ptr::null_mut())
+        }
+    })
 }
 
 
@@ -1561,19 +8400,63 @@ pub unsafe extern fn wi_grayimg_region_labelling_connected_components(
 ///////////////////////////////////////////////////////////////////////////////
 
 #[no_mangle]
-pub unsafe extern fn wi_grayimg_seam_carving_shrink_width(
+pub unsafe extern "C" fn wi_grayimg_seam_carving_shrink_width(
     ctx: *const WiGrayImage,
     target_width: u32,
 ) -> *mut WiGrayImage {
-    if ctx.is_null() {
-        return std::ptr::null_mut();
-    }
-    let result = match &(&*ctx).0 {
-        Ok(x) => Ok(imageproc::seam_carving::shrink_width(x, target_width)),
-        Err(ref x) => Err(x.clone()),
-    };
-    let result = Box::new(WiGrayImage(result));
-    Box::into_raw(result)
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) => Ok(imageproc::seam_carving::shrink_width(x, target_width)),
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiGrayImage(result));
+        Box::into_raw(result)
+    })
+}
+
+/// Shrink the image to `target_width` by removing low energy seams.
+///
+/// Seams are found from the RGB channels only and removed from every channel,
+/// alpha included, so the channels stay aligned. The result is 8-bit.
+#[no_mangle]
+pub unsafe extern "C" fn wi_img_seam_carving_shrink_width(
+    ctx: *const WiImage,
+    target_width: u32,
+) -> *mut WiImage {
+    catch_panic(|| {
+        if ctx.is_null() {
+            return null_argument("ctx", std::ptr::null_mut());
+        }
+        let result = match &(&*ctx).0 {
+            Ok(x) if target_width > x.width() => {
+                let message = format!("target width {} is larger than the image width {}", target_width, x.width());
+                Err(WiError::new(WiErrorCode::InvalidArgument, message))
+            }
+            Ok(x) => {
+                let shrink_rgba = |rgba: &image::RgbaImage| {
+                    let mut rgba = rgba.clone();
+                    let mut rgb = DynamicImage::ImageRgba8(rgba.clone()).to_rgb8();
+                    for _ in target_width..rgba.width() {
+                        let seam = imageproc::seam_carving::find_vertical_seam(&rgb);
+                        rgb = imageproc::seam_carving::remove_vertical_seam(&rgb, &seam);
+                        rgba = imageproc::seam_carving::remove_vertical_seam(&rgba, &seam);
+                    }
+                    rgba
+                };
+                Ok(map_rgb8_or_rgba8(
+                    x,
+                    |rgb| imageproc::seam_carving::shrink_width(rgb, target_width),
+                    shrink_rgba,
+                ))
+            }
+            Err(ref x) => Err(x.clone()),
+        };
+        let result = Box::new(WiImage(result, (&*ctx).1.clone()));
+        Box::into_raw(result)
+    })
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -1598,3 +8481,1459 @@ pub unsafe extern fn wi_grayimg_seam_carving_shrink_width(
 
 
 
+
+
+
+///////////////////////////////////////////////////////////////////////////////
+// TESTS
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Held by the tests that decode with the default limits, so that they
+    /// never run while `decode_limits_are_checked_before_decoding` swaps them.
+    static DEFAULT_LIMITS: std::sync::RwLock<()> = std::sync::RwLock::new(());
+
+    fn default_limits_guard() -> std::sync::RwLockReadGuard<'static, ()> {
+        DEFAULT_LIMITS.read().unwrap_or_else(|x| x.into_inner())
+    }
+
+    /// Read a returned string back the way a C caller would.
+    unsafe fn read(ptr: *const c_char) -> String {
+        assert!(!ptr.is_null());
+        CStr::from_ptr(ptr).to_str().expect("invalid UTF-8").to_owned()
+    }
+
+    /// Allocate and drop some strings, so a dangling pointer would likely be clobbered.
+    fn churn() {
+        let garbage: Vec<CString> = (0..64)
+            .map(|i| CString::new(format!("garbage {:>64}", i)).unwrap())
+            .collect();
+        drop(garbage);
+    }
+
+    #[test]
+    fn img_err_msg_outlives_the_call() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let path = CString::new("/nonexistent/input.png").unwrap();
+            let img = wi_img_open(path.as_ptr());
+            assert_eq!(wi_img_is_err(img), 1);
+            assert_eq!(wi_img_get_err_code(img), WiErrorCode::Io);
+            let first = wi_img_get_err_msg(img);
+            let expected = read(first);
+            assert!(!expected.is_empty());
+            churn();
+            assert_eq!(wi_img_get_err_msg(img), first);
+            assert_eq!(read(first), expected);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn img_err_msg_is_null_for_valid_images() {
+        unsafe {
+            let img = wi_new_rgb8_img(2, 2);
+            assert!(wi_img_get_err_msg(img).is_null());
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn grayimg_err_msg_outlives_the_call() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let data = b"definitely not an image";
+            let img = wi_img_decode_from_memory(data.as_ptr(), data.len());
+            let gray = wi_img_to_luma(img);
+            wi_img_free(img);
+            assert_eq!(wi_grayimg_is_err(gray), 1);
+            let msg = wi_grayimg_get_err_msg(gray);
+            let expected = read(msg);
+            assert!(!expected.is_empty());
+            churn();
+            assert_eq!(read(msg), expected);
+            wi_grayimg_free(gray);
+        }
+    }
+
+    #[test]
+    fn grayimg_u32_err_msg_outlives_the_call() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let path = CString::new("/nonexistent/input.png").unwrap();
+            let img = wi_img_open(path.as_ptr());
+            let gray = wi_img_to_luma(img);
+            let conn = CString::new("eight").unwrap();
+            let labels = wi_grayimg_region_labelling_connected_components(gray, conn.as_ptr(), 0);
+            wi_img_free(img);
+            wi_grayimg_free(gray);
+            assert_eq!(wi_grayimg_u32_is_err(labels), 1);
+            let msg = wi_grayimg_u32_get_err_msg(labels);
+            let expected = read(msg);
+            churn();
+            assert_eq!(read(msg), expected);
+            wi_grayimg_u32_free(labels);
+        }
+    }
+
+    #[test]
+    fn bytes_err_msg_outlives_the_call() {
+        unsafe {
+            let img = wi_new_rgb8_img(2, 2);
+            let format = CString::new("bogus").unwrap();
+            let bytes = wi_img_encode(img, format.as_ptr());
+            wi_img_free(img);
+            assert_eq!(wi_bytes_get_err_code(bytes), WiErrorCode::UnsupportedFormat);
+            let msg = wi_bytes_get_err_msg(bytes);
+            churn();
+            assert_eq!(read(msg), "unknown image format: bogus");
+            wi_bytes_free(bytes);
+        }
+    }
+
+    #[test]
+    fn last_error_message_outlives_the_call() {
+        unsafe {
+            wi_clear_last_error();
+            assert!(wi_last_error_message().is_null());
+            assert!(wi_img_grayscale(std::ptr::null()).is_null());
+            let msg = wi_last_error_message();
+            churn();
+            assert_eq!(wi_last_error_code(), WiErrorCode::InvalidArgument);
+            assert_eq!(read(msg), "`ctx` is NULL");
+            wi_clear_last_error();
+        }
+    }
+
+    #[test]
+    fn panics_become_error_handles() {
+        unsafe {
+            let img: *mut WiImage = catch_panic(|| panic!("boom"));
+            assert_eq!(wi_img_get_err_code(img), WiErrorCode::Internal);
+            assert_eq!(read(wi_img_get_err_msg(img)), "panic: boom");
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn panics_become_last_errors() {
+        unsafe {
+            let value = catch_panic_or(-1, || panic!("missing {}", "entry"));
+            assert_eq!(value, -1);
+            assert_eq!(wi_last_error_code(), WiErrorCode::Internal);
+            assert_eq!(read(wi_last_error_message()), "panic: missing entry");
+            wi_clear_last_error();
+        }
+    }
+
+    #[test]
+    fn img_color_strings_are_static() {
+        unsafe {
+            let rgba = wi_new_rgba8_img(2, 2);
+            let color = wi_img_color(rgba);
+            wi_img_free(rgba);
+            churn();
+            assert_eq!(read(color), "rgba:8");
+
+            let luma = wi_new_luma8_img(2, 2);
+            assert_eq!(read(wi_img_color(luma)), "gray:8");
+            wi_img_free(luma);
+
+            let rgb = wi_new_rgb8_img(2, 2);
+            assert_eq!(read(wi_img_color(rgb)), "rgb:8");
+            wi_img_free(rgb);
+        }
+    }
+
+    #[test]
+    fn raw_buffers_round_trip_with_stride() {
+        unsafe {
+            // Two rows of two RGB pixels, padded to 8 bytes per row.
+            let data: [u8; 16] = [1, 2, 3, 4, 5, 6, 0, 0, 7, 8, 9, 10, 11, 12, 0, 0];
+            let layout = CString::new("rgb8").unwrap();
+            let img = wi_img_from_raw(data.as_ptr(), data.len(), 2, 2, 8, layout.as_ptr());
+            assert_eq!(wi_img_is_ok(img), 1);
+            assert_eq!(wi_img_raw_stride(img), 6);
+            let raw = std::slice::from_raw_parts(wi_img_raw_data(img), wi_img_raw_len(img));
+            assert_eq!(raw, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+            wi_img_free(img);
+
+            let buffer = wi_raw_buffer_alloc(data.len());
+            std::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+            let gray = wi_grayimg_from_raw_adopt(buffer, data.len(), 6, 2, 8);
+            assert_eq!(wi_grayimg_raw_stride(gray), 6);
+            let raw = std::slice::from_raw_parts(wi_grayimg_raw_data(gray), wi_grayimg_raw_len(gray));
+            assert_eq!(raw, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+            wi_grayimg_free(gray);
+        }
+    }
+
+    #[test]
+    fn raw_buffers_reject_short_input() {
+        unsafe {
+            let data = [0u8; 10];
+            let img = wi_grayimg_from_raw(data.as_ptr(), data.len(), 4, 3, 4);
+            assert_eq!(wi_grayimg_get_err_code(img), WiErrorCode::OutOfBounds);
+            wi_grayimg_free(img);
+            let img = wi_grayimg_from_raw(data.as_ptr(), data.len(), 4, 2, 3);
+            assert_eq!(wi_grayimg_get_err_code(img), WiErrorCode::InvalidArgument);
+            wi_grayimg_free(img);
+        }
+    }
+
+    #[test]
+    fn regions_convert_layouts_and_check_bounds() {
+        unsafe {
+            let img = wi_new_rgba8_img(3, 2);
+            wi_img_set_rgba_pixel(img, 1, 1, RgbaPixel { r: 10, g: 20, b: 30, a: 40 });
+            wi_img_set_rgba_pixel(img, 2, 1, RgbaPixel { r: 50, g: 60, b: 70, a: 80 });
+            let rgb8 = CString::new("rgb8").unwrap();
+            let mut output = [0u8; 6];
+            let code = wi_img_copy_region(img, 1, 1, 2, 1, rgb8.as_ptr(), output.as_mut_ptr(), output.len(), 0);
+            assert_eq!(code, WiErrorCode::Ok);
+            assert_eq!(output, [10, 20, 30, 50, 60, 70]);
+
+            let code = wi_img_copy_region(img, 2, 1, 2, 1, rgb8.as_ptr(), output.as_mut_ptr(), output.len(), 0);
+            assert_eq!(code, WiErrorCode::OutOfBounds);
+            assert_eq!(wi_last_error_code(), WiErrorCode::OutOfBounds);
+            let code = wi_img_copy_row(img, 1, rgb8.as_ptr(), output.as_mut_ptr(), output.len());
+            assert_eq!(code, WiErrorCode::OutOfBounds);
+            wi_img_free(img);
+
+            let gray = wi_grayimg_from_raw([7u8, 8, 9].as_ptr(), 3, 3, 1, 3);
+            let u32_layout = CString::new("u32").unwrap();
+            let mut wide = [0u32; 3];
+            let code = wi_grayimg_copy_row(gray, 0, u32_layout.as_ptr(), wide.as_mut_ptr() as *mut u8, 12);
+            assert_eq!(code, WiErrorCode::Ok);
+            assert_eq!(wide, [7, 8, 9]);
+            wi_grayimg_free(gray);
+            wi_clear_last_error();
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_pixels_keep_their_precision() {
+        unsafe {
+            let img = wi_new_rgb16_img(2, 1);
+            let px = Rgba16Pixel { r: 1000, g: 2000, b: 65535, a: 65535 };
+            assert_eq!(wi_img_set_rgba16_pixel(img, 1, 0, px), 1);
+            let mut out = Rgba16Pixel { r: 0, g: 0, b: 0, a: 0 };
+            assert_eq!(wi_img_get_rgba16_pixel(img, &mut out, 1, 0), 1);
+            assert_eq!((out.r, out.g, out.b, out.a), (1000, 2000, 65535, 65535));
+
+            let gray = wi_img_to_luma_f32(img);
+            let mut value = 0.0f32;
+            assert_eq!(wi_grayimg_f32_get_pixel(gray, &mut value, 0, 0), 1);
+            assert_eq!(value, 0.0);
+            assert_eq!(wi_grayimg_f32_set_pixel(gray, 0, 0, 2.0), 1);
+            let gray8 = wi_grayimg_f32_to_grayimg(gray);
+            let mut l = 0u8;
+            assert_eq!(wi_grayimg_get_pixel(gray8, &mut l, 0, 0), 1);
+            assert_eq!(l, 255);
+            wi_grayimg_free(gray8);
+            wi_grayimg_f32_free(gray);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn f32_gaussian_blur_rejects_invalid_sigma() {
+        unsafe {
+            let gray = wi_new_grayimg_f32(4, 4);
+            let blurred = wi_grayimg_f32_filter_gaussian_blur(gray, 0.0);
+            assert_eq!(wi_grayimg_f32_get_err_code(blurred), WiErrorCode::InvalidArgument);
+            wi_grayimg_f32_free(blurred);
+            let blurred = wi_grayimg_f32_filter_gaussian_blur(gray, 1.5);
+            assert_eq!(wi_grayimg_f32_is_ok(blurred), 1);
+            wi_grayimg_f32_free(blurred);
+            wi_grayimg_f32_free(gray);
+        }
+    }
+
+    #[test]
+    fn convert_covers_every_color_type() {
+        unsafe {
+            let img = wi_new_rgba8_img(2, 2);
+            for (name, color) in [
+                ("l8", WiColorType::L8),
+                ("graya:8", WiColorType::La8),
+                ("rgb8", WiColorType::Rgb8),
+                ("rgba:8", WiColorType::Rgba8),
+                ("luma16", WiColorType::L16),
+                ("la16", WiColorType::La16),
+                ("rgb16", WiColorType::Rgb16),
+                ("rgba16", WiColorType::Rgba16),
+                ("rgb:32f", WiColorType::Rgb32F),
+                ("rgba32f", WiColorType::Rgba32F),
+                ("bgr8", WiColorType::Bgr8),
+                ("bgra:8", WiColorType::Bgra8),
+            ] {
+                let name = CString::new(name).unwrap();
+                let converted = wi_img_convert(img, name.as_ptr());
+                assert_eq!(wi_img_color_type(converted), color);
+                assert_eq!(read(wi_img_color(converted)), read(wi_color_type_name(color as c_int)));
+                wi_img_free(converted);
+            }
+            for junk in ["cmyk8", "8", "a8", "llll8", "lumagray8", "gray:l:8", "rgb:8:"].iter() {
+                let name = CString::new(*junk).unwrap();
+                let converted = wi_img_convert(img, name.as_ptr());
+                assert_eq!(wi_img_get_err_code(converted), WiErrorCode::InvalidArgument, "{}", junk);
+                wi_img_free(converted);
+            }
+            let converted = wi_img_convert_to_color_type(img, WiColorType::Rgb16 as c_int);
+            assert_eq!(wi_img_color_type(converted), WiColorType::Rgb16);
+            wi_img_free(converted);
+            let names: Vec<_> = (1..=4).chain(11..=12).map(|x| read(wi_color_type_name(x))).collect();
+            assert_eq!(names, ["gray:8", "graya:8", "rgb:8", "rgba:8", "bgr:8", "bgra:8"]);
+            let converted = wi_img_convert_to_color_type(img, 42);
+            assert_eq!(wi_img_get_err_code(converted), WiErrorCode::InvalidArgument);
+            wi_img_free(converted);
+            assert!(wi_color_type_name(42).is_null());
+            assert_eq!(wi_last_error_code(), WiErrorCode::InvalidArgument);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn bgra8_layout_swaps_channels() {
+        unsafe {
+            let layout = CString::new("bgra8").unwrap();
+            let data = [1u8, 2, 3, 4];
+            let img = wi_img_from_raw(data.as_ptr(), 4, 1, 1, 4, layout.as_ptr());
+            let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            wi_img_get_rgba_pixel(img, &mut px, 0, 0);
+            assert_eq!((px.r, px.g, px.b, px.a), (3, 2, 1, 4));
+            let mut output = [0u8; 4];
+            let code = wi_img_copy_row(img, 0, layout.as_ptr(), output.as_mut_ptr(), 4);
+            assert_eq!(code, WiErrorCode::Ok);
+            assert_eq!(output, data);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn bgr_conversions_store_swapped_channels() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let img = wi_new_rgba8_img(1, 1);
+            wi_img_set_rgba_pixel(img, 0, 0, RgbaPixel { r: 10, g: 20, b: 30, a: 40 });
+            let bgra8 = CString::new("bgra8").unwrap();
+            let bgra = wi_img_convert(img, bgra8.as_ptr());
+            assert_eq!(wi_img_color_type(bgra), WiColorType::Bgra8);
+            assert_eq!(read(wi_img_color(bgra)), "bgra:8");
+            let raw = std::slice::from_raw_parts(wi_img_raw_data(bgra), wi_img_raw_len(bgra));
+            assert_eq!(raw, [30, 20, 10, 40]);
+            let bgr8 = CString::new("bgr8").unwrap();
+            let bgr = wi_img_convert(img, bgr8.as_ptr());
+            assert_eq!(read(wi_img_color(bgr)), "bgr:8");
+            let raw = std::slice::from_raw_parts(wi_img_raw_data(bgr), wi_img_raw_len(bgr));
+            assert_eq!(raw, [30, 20, 10]);
+
+            // Everything else still sees the colors in RGB order.
+            let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            wi_img_get_rgba_pixel(bgra, &mut px, 0, 0);
+            assert_eq!((px.r, px.g, px.b, px.a), (10, 20, 30, 40));
+            let rgba8 = CString::new("rgba8").unwrap();
+            let mut output = [0u8; 4];
+            wi_img_copy_row(bgra, 0, rgba8.as_ptr(), output.as_mut_ptr(), 4);
+            assert_eq!(output, [10, 20, 30, 40]);
+            let back = wi_img_convert(bgra, rgba8.as_ptr());
+            assert_eq!(wi_img_color_type(back), WiColorType::Rgba8);
+            let raw = std::slice::from_raw_parts(wi_img_raw_data(back), wi_img_raw_len(back));
+            assert_eq!(raw, [10, 20, 30, 40]);
+            let png = CString::new("png").unwrap();
+            let bytes = wi_img_encode(bgr, png.as_ptr());
+            let decoded = wi_img_decode_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes));
+            wi_img_get_rgba_pixel(decoded, &mut px, 0, 0);
+            assert_eq!((px.r, px.g, px.b, px.a), (10, 20, 30, 255));
+            wi_img_free(decoded);
+            wi_bytes_free(bytes);
+            wi_img_free(back);
+            wi_img_free(bgr);
+            wi_img_free(bgra);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn color_spaces_round_trip() {
+        unsafe {
+            let img = wi_new_rgba8_img(2, 1);
+            wi_img_set_rgba_pixel(img, 0, 0, RgbaPixel { r: 200, g: 30, b: 90, a: 255 });
+            wi_img_set_rgba_pixel(img, 1, 0, RgbaPixel { r: 12, g: 160, b: 250, a: 128 });
+            let rgba8 = CString::new("rgba8").unwrap();
+            for space in ["linear-rgb", "hsv", "hsl", "lab", "ycbcr"] {
+                let space = CString::new(space).unwrap();
+                let mut planes = [std::ptr::null_mut(); 4];
+                let [c0, c1, c2, alpha] = &mut planes;
+                let code = wi_img_to_color_space(img, space.as_ptr(), c0, c1, c2, alpha);
+                assert_eq!(code, WiErrorCode::Ok);
+                let back = wi_img_from_color_space(space.as_ptr(), *c0, *c1, *c2, *alpha);
+                let back8 = wi_img_convert(back, rgba8.as_ptr());
+                let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+                wi_img_get_rgba_pixel(back8, &mut px, 1, 0);
+                assert_eq!((px.r, px.g, px.b, px.a), (12, 160, 250, 128));
+                wi_img_free(back8);
+                wi_img_free(back);
+                planes.iter().for_each(|x| wi_grayimg_f32_free(*x));
+            }
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn lab_of_white_is_100_lightness() {
+        let [l, a, b] = srgb_to_color_space(ColorSpace::Lab, [1.0, 1.0, 1.0]);
+        assert!((l - 100.0).abs() < 0.01 && a.abs() < 0.01 && b.abs() < 0.01);
+        let [r, g, b] = srgb_to_color_space(ColorSpace::LinearRgb, [0.5, 0.5, 0.5]);
+        assert!((r - 0.214).abs() < 0.001 && r == g && g == b);
+    }
+
+    #[test]
+    fn color_space_planes_must_match() {
+        unsafe {
+            let small = wi_new_grayimg_f32(2, 2);
+            let large = wi_new_grayimg_f32(3, 2);
+            let space = CString::new("hsv").unwrap();
+            let img = wi_img_from_color_space(space.as_ptr(), small, small, large, std::ptr::null());
+            assert_eq!(wi_img_get_err_code(img), WiErrorCode::DimensionMismatch);
+            wi_img_free(img);
+            wi_grayimg_f32_free(small);
+            wi_grayimg_f32_free(large);
+        }
+    }
+
+    #[test]
+    fn split_and_merge_channels_round_trip() {
+        unsafe {
+            let img = wi_new_rgba8_img(2, 1);
+            wi_img_set_rgba_pixel(img, 1, 0, RgbaPixel { r: 1, g: 2, b: 3, a: 4 });
+            let mut channels = [std::ptr::null_mut(); 4];
+            let [r, g, b, a] = &mut channels;
+            assert_eq!(wi_img_split_channels(img, r, g, b, a), WiErrorCode::Ok);
+            let mut l = 0u8;
+            wi_grayimg_get_pixel(*b, &mut l, 1, 0);
+            assert_eq!(l, 3);
+            let merged = wi_img_merge_channels(*r, *g, *b, *a);
+            let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            wi_img_get_rgba_pixel(merged, &mut px, 1, 0);
+            assert_eq!((px.r, px.g, px.b, px.a), (1, 2, 3, 4));
+            assert_eq!(read(wi_img_color(merged)), "rgba:8");
+            wi_img_free(merged);
+
+            let tiny = wi_new_luma8_img(1, 1);
+            let small = wi_img_to_luma(tiny);
+            wi_img_free(tiny);
+            let merged = wi_img_merge_channels(*r, *g, small, std::ptr::null());
+            assert_eq!(wi_img_get_err_code(merged), WiErrorCode::DimensionMismatch);
+            wi_img_free(merged);
+            wi_grayimg_free(small);
+            channels.iter().for_each(|x| wi_grayimg_free(*x));
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn color_filters_keep_alpha_on_request() {
+        unsafe {
+            let img = wi_new_rgba8_img(5, 5);
+            wi_img_set_rgba_pixel(img, 2, 2, RgbaPixel { r: 255, g: 0, b: 0, a: 200 });
+            let blurred = wi_img_filter_gaussian_blur_f32(img, 1.0, 1);
+            let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            wi_img_get_rgba_pixel(blurred, &mut px, 2, 1);
+            assert!(px.r > 0 && px.g == 0);
+            assert_eq!(px.a, 0);
+            wi_img_free(blurred);
+
+            let blurred = wi_img_filter_gaussian_blur_f32(img, 1.0, 0);
+            wi_img_get_rgba_pixel(blurred, &mut px, 2, 1);
+            assert!(px.a > 0);
+            wi_img_free(blurred);
+
+            let invalid = wi_img_filter_gaussian_blur_f32(img, 0.0, 0);
+            assert_eq!(wi_img_get_err_code(invalid), WiErrorCode::InvalidArgument);
+            wi_img_free(invalid);
+
+            let carved = wi_img_seam_carving_shrink_width(img, 3);
+            assert_eq!(wi_img_width(carved), 3);
+            assert_eq!(read(wi_img_color(carved)), "rgba:8");
+            wi_img_free(carved);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn rotate_expands_the_canvas_and_fills_the_background() {
+        unsafe {
+            let img = wi_new_rgb8_img(40, 20);
+            let nearest = CString::new("nearest").unwrap();
+            let clear = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            let rotated = wi_img_rotate(img, 90.0, nearest.as_ptr(), clear, 1);
+            assert_eq!((wi_img_width(rotated), wi_img_height(rotated)), (20, 40));
+            wi_img_free(rotated);
+
+            let bicubic = CString::new("bicubic").unwrap();
+            let rotated = wi_img_rotate(img, 45.0, bicubic.as_ptr(), clear, 0);
+            assert_eq!((wi_img_width(rotated), wi_img_height(rotated)), (40, 20));
+            assert_eq!(read(wi_img_color(rotated)), "rgba:8");
+            let mut px = RgbaPixel { r: 1, g: 1, b: 1, a: 1 };
+            wi_img_get_rgba_pixel(rotated, &mut px, 0, 0);
+            assert_eq!(px.a, 0);
+            wi_img_free(rotated);
+
+            let bogus = CString::new("lanczos").unwrap();
+            let rotated = wi_img_rotate(img, 45.0, bogus.as_ptr(), clear, 0);
+            assert_eq!(wi_img_get_err_code(rotated), WiErrorCode::InvalidArgument);
+            wi_img_free(rotated);
+
+            let gray = wi_img_to_luma(img);
+            let bilinear = CString::new("bilinear").unwrap();
+            let rotated = wi_grayimg_rotate(gray, 30.0, bilinear.as_ptr(), 255, 1);
+            assert!(wi_grayimg_width(rotated) > 40 && wi_grayimg_height(rotated) > 20);
+            wi_grayimg_free(rotated);
+            wi_grayimg_free(gray);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn warps_follow_the_matrix() {
+        unsafe {
+            let img = wi_new_rgb8_img(4, 4);
+            wi_img_set_rgba_pixel(img, 1, 1, RgbaPixel { r: 255, g: 255, b: 255, a: 255 });
+            let nearest = CString::new("nearest").unwrap();
+            let black = RgbaPixel { r: 0, g: 0, b: 0, a: 255 };
+            let shift = [1.0f32, 0.0, 2.0, 0.0, 1.0, 1.0];
+            let warped = wi_img_warp_affine(img, shift.as_ptr(), nearest.as_ptr(), black, 0, 0);
+            let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            wi_img_get_rgba_pixel(warped, &mut px, 3, 2);
+            assert_eq!((px.r, px.a), (255, 255));
+            assert_eq!(read(wi_img_color(warped)), "rgb:8");
+            wi_img_free(warped);
+
+            let singular = [0.0f32; 6];
+            let warped = wi_img_warp_affine(img, singular.as_ptr(), nearest.as_ptr(), black, 0, 0);
+            assert_eq!(wi_img_get_err_code(warped), WiErrorCode::InvalidArgument);
+            wi_img_free(warped);
+            let warped = wi_img_warp_affine(img, shift.as_ptr(), nearest.as_ptr(), black, 0, 3);
+            assert_eq!(wi_img_get_err_code(warped), WiErrorCode::InvalidArgument);
+            wi_img_free(warped);
+
+            let from = [0.0f32, 0.0, 4.0, 0.0, 4.0, 4.0, 0.0, 4.0];
+            let to = [0.0f32, 0.0, 8.0, 0.0, 8.0, 8.0, 0.0, 8.0];
+            let mut matrix = [0.0f32; 9];
+            let code = wi_homography_from_points(from.as_ptr(), to.as_ptr(), matrix.as_mut_ptr());
+            assert_eq!(code, WiErrorCode::Ok);
+            let expected = [2.0f32, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 1.0];
+            assert!(matrix.iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-4));
+
+            let gray = wi_img_to_luma(img);
+            let warped = wi_grayimg_warp_perspective(gray, matrix.as_ptr(), nearest.as_ptr(), 0, 8, 8);
+            assert_eq!((wi_grayimg_width(warped), wi_grayimg_height(warped)), (8, 8));
+            let mut l = 0u8;
+            wi_grayimg_get_pixel(warped, &mut l, 2, 2);
+            assert_eq!(l, 255);
+            wi_grayimg_free(warped);
+            let warped = wi_grayimg_warp_affine(gray, shift.as_ptr(), nearest.as_ptr(), 0, 5, 0);
+            assert_eq!(wi_grayimg_get_err_code(warped), WiErrorCode::InvalidArgument);
+            wi_grayimg_free(warped);
+            wi_grayimg_free(gray);
+
+            let collinear = [0.0f32, 0.0, 1.0, 1.0, 2.0, 2.0, 3.0, 3.0];
+            let code = wi_homography_from_points(collinear.as_ptr(), to.as_ptr(), matrix.as_mut_ptr());
+            assert_eq!(code, WiErrorCode::InvalidArgument);
+            wi_clear_last_error();
+            wi_img_free(img);
+        }
+    }
+
+    /// Encode a 3x2 JPEG carrying an EXIF APP1 segment with the given orientation.
+    fn jpeg_with_orientation(orientation: u16) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        DynamicImage::new_rgb8(3, 2)
+            .write_to(&mut std::io::Cursor::new(&mut jpeg), image::ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0".to_vec();
+        tiff.extend_from_slice(&[0x12, 0x01, 3, 0, 1, 0, 0, 0]);
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0; 6]);
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&(2 + 6 + tiff.len() as u16).to_be_bytes());
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(&tiff);
+        jpeg.splice(2..2, app1);
+        jpeg
+    }
+
+    #[test]
+    fn exif_orientation_is_applied_on_request() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let jpeg = jpeg_with_orientation(6);
+            let img = wi_img_decode_from_memory(jpeg.as_ptr(), jpeg.len());
+            assert_eq!(wi_img_exif_orientation(img), 6);
+            assert_eq!((wi_img_width(img), wi_img_height(img)), (3, 2));
+            let thumb = wi_img_thumbnail(img, 2, 2);
+            assert_eq!(wi_img_exif_orientation(thumb), 6);
+            wi_img_free(thumb);
+            let ops: [unsafe extern "C" fn(*const WiImage) -> *mut WiImage; 5] =
+                [wi_img_rotate90, wi_img_rotate180, wi_img_rotate270, wi_img_fliph, wi_img_flipv];
+            for op in ops.iter() {
+                let turned = op(img);
+                assert_eq!(wi_img_exif_orientation(turned), 1);
+                let jpeg = CString::new("jpeg").unwrap();
+                let bytes = wi_img_encode(turned, jpeg.as_ptr());
+                let decoded = wi_img_decode_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes));
+                assert_eq!(wi_img_exif_orientation(decoded), 1);
+                wi_img_free(decoded);
+                wi_bytes_free(bytes);
+                wi_img_free(turned);
+            }
+            let nearest = CString::new("nearest").unwrap();
+            let black = RgbaPixel { r: 0, g: 0, b: 0, a: 255 };
+            let turned = wi_img_rotate(img, 30.0, nearest.as_ptr(), black, 1);
+            assert_eq!(wi_img_exif_orientation(turned), 1);
+            wi_img_free(turned);
+            wi_img_free(img);
+
+            let img = wi_img_decode_from_memory_oriented(jpeg.as_ptr(), jpeg.len(), std::ptr::null());
+            assert_eq!((wi_img_width(img), wi_img_height(img)), (2, 3));
+            assert_eq!(wi_img_exif_orientation(img), 1);
+            let again = wi_img_apply_exif_orientation(img);
+            assert_eq!((wi_img_width(again), wi_img_height(again)), (2, 3));
+            wi_img_free(again);
+            wi_img_free(img);
+
+            let img = wi_new_rgb8_img(3, 2);
+            assert_eq!(wi_img_exif_orientation(img), 0);
+            wi_img_free(img);
+        }
+    }
+
+    /// Look up a metadata value, as a C caller would.
+    unsafe fn metadata(img: *const WiImage, key: &str) -> Option<String> {
+        let key = CString::new(key).unwrap();
+        let value = wi_img_metadata_get(img, key.as_ptr());
+        (!value.is_null()).then(|| read(value))
+    }
+
+    #[test]
+    fn metadata_survives_encoding_and_can_be_stripped() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let latitude = exif::Field {
+                tag: exif::Tag::GPSLatitude,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Rational(vec![(12, 1).into(), (30, 1).into(), (0, 1).into()]),
+            };
+            let model = exif::Field {
+                tag: exif::Tag::Model,
+                ifd_num: exif::In::PRIMARY,
+                value: exif::Value::Ascii(vec![b"Pinhole".to_vec()]),
+            };
+            let exif = write_exif(&[&latitude, &model], false).unwrap();
+            let img = wi_new_rgb8_img(4, 4);
+            (&mut *img).1 = ImageMetadata::new(exif, None, None);
+            let set = |key: &str, value: &str| {
+                let key = CString::new(key).unwrap();
+                let value = CString::new(value).unwrap();
+                wi_img_metadata_set(img, key.as_ptr(), value.as_ptr())
+            };
+            assert_eq!(set("Exif.Copyright", "Jane Doe"), 1);
+            assert_eq!(set("Iptc.Keywords", "café"), 1);
+            assert_eq!(set("Xmp.dc.creator", "Jane <Doe>"), 1);
+            assert_eq!(set("Xmp.exif.GPSLatitude", "12,30N"), 1);
+            assert_eq!(set("Exif.GPSLatitude", "north"), 0);
+            assert_eq!(wi_last_error_code(), WiErrorCode::InvalidArgument);
+            assert_eq!(wi_img_metadata_count(img), 6);
+
+            let jpeg = CString::new("jpeg").unwrap();
+            let bytes = wi_img_encode(img, jpeg.as_ptr());
+            let decoded = wi_img_decode_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes));
+            assert_eq!(metadata(decoded, "Exif.Model").as_deref(), Some("Pinhole"));
+            assert!(metadata(decoded, "Exif.GPSLatitude").is_some());
+            assert_eq!(metadata(decoded, "Iptc.Keywords").as_deref(), Some("café"));
+            assert_eq!(metadata(decoded, "Xmp.dc.creator").as_deref(), Some("Jane <Doe>"));
+            assert_eq!(read(wi_img_metadata_key(decoded, 0)), "Exif.Model");
+            assert!(wi_img_metadata_key(decoded, 6).is_null());
+            wi_bytes_free(bytes);
+
+            let gps = CString::new("Exif.GPS*").unwrap();
+            assert_eq!(wi_img_metadata_remove(decoded, gps.as_ptr()), 1);
+            let gps = CString::new("Xmp.exif.GPS*").unwrap();
+            assert_eq!(wi_img_metadata_remove(decoded, gps.as_ptr()), 1);
+            let png = CString::new("png").unwrap();
+            let bytes = wi_img_encode(decoded, png.as_ptr());
+            let stripped = wi_img_decode_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes));
+            assert_eq!(metadata(stripped, "Exif.GPSLatitude"), None);
+            assert_eq!(metadata(stripped, "Xmp.exif.GPSLatitude"), None);
+            assert_eq!(metadata(stripped, "Exif.Copyright").as_deref(), Some("Jane Doe"));
+            assert_eq!(metadata(stripped, "Xmp.dc.creator").as_deref(), Some("Jane <Doe>"));
+            let all = CString::new("*").unwrap();
+            assert_eq!(wi_img_metadata_remove(stripped, all.as_ptr()), 3);
+            assert_eq!(wi_img_metadata_count(stripped), 0);
+            wi_bytes_free(bytes);
+            wi_img_free(stripped);
+            wi_img_free(decoded);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn xmp_packets_are_parsed_and_edited() {
+        let wrap = |body: &str| {
+            format!(
+                concat!(
+                    "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+                    "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">{}</rdf:RDF>",
+                    "</x:xmpmeta>",
+                ),
+                body,
+            )
+        };
+        let values = |packet: &str| {
+            parse_xmp(packet)
+                .unwrap()
+                .properties
+                .into_iter()
+                .map(|x| (x.name, x.values))
+                .collect::<Vec<_>>()
+        };
+        let packet = wrap(concat!(
+            "<rdf:Description xmlns:dc=\"http://purl.org/dc/elements/1.1/\" dc:format=\"a &amp; b\">",
+            "<!-- <dc:rights>none</dc:rights> -->",
+            "<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\"><![CDATA[<raw> & text]]></rdf:li></rdf:Alt></dc:title>",
+            "<dc:subject><rdf:Bag><rdf:li>one</rdf:li><rdf:li>t&#233;o</rdf:li></rdf:Bag></dc:subject>",
+            "<xmpMM:DerivedFrom><rdf:Description xmpMM:DocumentID=\"id\"/></xmpMM:DerivedFrom>",
+            "</rdf:Description>",
+            "<rdf:Description xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"><xmp:Label>red</xmp:Label></rdf:Description>",
+        ));
+        let strings = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        assert_eq!(values(&packet), vec![
+            ("dc:format".to_owned(), strings(&["a & b"])),
+            ("dc:title".to_owned(), strings(&["<raw> & text"])),
+            ("dc:subject".to_owned(), strings(&["one", "téo"])),
+            ("xmpMM:DerivedFrom".to_owned(), Vec::new()),
+            ("xmp:Label".to_owned(), strings(&["red"])),
+        ]);
+        let removed = xmp_remove(&packet, |x| x.starts_with("dc:"));
+        assert!(!removed.contains("dc:format=") && !removed.contains("<dc:t") && !removed.contains("<dc:s"));
+        assert!(removed.contains("<!-- <dc:rights>"));
+        assert_eq!(values(&removed).len(), 2);
+
+        let empty = wrap("<rdf:Description rdf:about=\"\"/>");
+        assert!(values(&empty).is_empty());
+        let set = xmp_set(&empty, "xmp:Rating", "5").unwrap();
+        let set = xmp_set(&set, "dc:creator", "Jane & John").unwrap();
+        assert!(set.contains("xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\""));
+        assert!(set.contains("xmlns:dc=\"http://purl.org/dc/elements/1.1/\""));
+        assert_eq!(values(&set), vec![
+            ("xmp:Rating".to_owned(), strings(&["5"])),
+            ("dc:creator".to_owned(), strings(&["Jane & John"])),
+        ]);
+        let code = |x: Result<String, WiError>| x.err().map(|x| x.code);
+        assert_eq!(code(xmp_set(&set, "foo:bar", "1")), Some(WiErrorCode::InvalidArgument));
+        assert_eq!(code(xmp_set(&wrap(""), "dc:format", "1")), Some(WiErrorCode::InvalidArgument));
+        assert_eq!(code(xmp_set("<a><b></a>", "dc:format", "1")), Some(WiErrorCode::InvalidArgument));
+    }
+
+    #[test]
+    fn oversized_jpeg_metadata_is_split_or_left_out() {
+        let hex = |x: [u8; 16]| x.iter().map(|x| format!("{:02x}", x)).collect::<String>();
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(&[b'a'; 1000])), "cabe45dcc9ae5b66ba86600cca6b8ba8");
+
+        // An XMP packet of about 200 KB, and an EXIF block too large for any segment.
+        let label = "x".repeat(200_000);
+        let xmp = xmp_set(XMP_TEMPLATE, "xmp:Label", &label).unwrap();
+        let mut exif = b"II*\0\x08\0\0\0\0\0".to_vec();
+        exif.resize(70_000, 0);
+        let metadata = ImageMetadata::new(Some(exif), Some(xmp.clone()), Some(b"\x1C\x02\x00\x00\x02\x00\x04".to_vec()));
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(8, 8));
+        let format = OutputFormat::Image(image::ImageOutputFormat::Jpeg(75));
+        let jpeg = encode_with_metadata(&image, &metadata, format, &WiEncodeOptions::default()).unwrap();
+        let extensions = jpeg_app_segments(&jpeg)
+            .into_iter()
+            .filter(|x| x.1.starts_with(JPEG_XMP_EXTENSION_HEADER))
+            .count();
+        assert_eq!(extensions, 4);
+        assert!(image::load_from_memory(&jpeg).is_ok());
+        let read = ImageMetadata::read(&jpeg, &WiDecodeLimits::default());
+        assert_eq!(read.xmp.as_deref(), Some(xmp.as_str()));
+        assert!(read.exif.is_none());
+        assert!(read.iptc.is_some());
+
+        // Properties left in the main packet are kept next to the extended ones.
+        let guid = hex(md5(xmp.as_bytes())).to_uppercase();
+        let main = xmp_set(XMP_TEMPLATE, "xmpNote:HasExtendedXMP", &guid).unwrap();
+        let main = xmp_set(&main, "xmp:Rating", "5").unwrap();
+        let chunks = xmp
+            .as_bytes()
+            .chunks(JPEG_XMP_EXTENSION_CHUNK)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut x = guid.as_bytes().to_vec();
+                x.extend_from_slice(&(xmp.len() as u32).to_be_bytes());
+                x.extend_from_slice(&((index * JPEG_XMP_EXTENSION_CHUNK) as u32).to_be_bytes());
+                x.extend_from_slice(chunk);
+                x
+            })
+            .collect::<Vec<_>>();
+        let chunks = chunks.iter().map(|x| x.as_slice()).collect::<Vec<_>>();
+        let merged = merge_extended_xmp(main.clone(), &chunks);
+        let names = parse_xmp(&merged).unwrap().properties.into_iter().map(|x| x.name).collect::<Vec<_>>();
+        assert_eq!(names, ["xmp:Rating", "xmp:Label"]);
+        // A missing chunk leaves the main packet alone.
+        assert_eq!(merge_extended_xmp(main.clone(), &chunks[1..]), main);
+    }
+
+    /// Build an ICC profile with the sRGB primaries and a plain gamma curve.
+    fn gamma_icc_profile(gamma: f32) -> Vec<u8> {
+        let xyz = |x: [f32; 3]| {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for x in x.iter() {
+                tag.extend_from_slice(&((x * 65536.0).round() as i32).to_be_bytes());
+            }
+            tag
+        };
+        let mut curve = b"curv\0\0\0\0\0\0\0\x01".to_vec();
+        curve.extend_from_slice(&((gamma * 256.0).round() as u16).to_be_bytes());
+        curve.extend_from_slice(&[0, 0]);
+        let tags: Vec<(&[u8], Vec<u8>)> = vec![
+            (b"wtpt", xyz([0.9642, 1.0, 0.8249])),
+            (b"rXYZ", xyz([0.4361, 0.2225, 0.0139])),
+            (b"gXYZ", xyz([0.3851, 0.7169, 0.0971])),
+            (b"bXYZ", xyz([0.1431, 0.0606, 0.7141])),
+            (b"rTRC", curve.clone()),
+            (b"gTRC", curve.clone()),
+            (b"bTRC", curve),
+        ];
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = Vec::new();
+        let data_start = 128 + 4 + 12 * tags.len();
+        for (signature, tag) in &tags {
+            table.extend_from_slice(signature);
+            table.extend_from_slice(&((data_start + data.len()) as u32).to_be_bytes());
+            table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+            data.extend_from_slice(tag);
+        }
+        let mut profile = vec![0; 128];
+        let size = (128 + table.len() + data.len()) as u32;
+        profile[0..4].copy_from_slice(&size.to_be_bytes());
+        profile[8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+        profile[12..24].copy_from_slice(b"mntrRGB XYZ ");
+        profile[36..40].copy_from_slice(b"acsp");
+        profile[68..80].copy_from_slice(&xyz([0.9642, 1.0, 0.8249])[8..]);
+        profile.extend(table);
+        profile.extend(data);
+        profile
+    }
+
+    #[test]
+    fn icc_profiles_are_embedded_and_converted() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let profile = gamma_icc_profile(1.0);
+            let img = wi_new_rgb8_img(2, 2);
+            for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                wi_img_set_rgba_pixel(img, *x, *y, RgbaPixel { r: 64, g: 64, b: 64, a: 255 });
+            }
+            assert_eq!(wi_img_set_icc_profile(img, profile.as_ptr(), profile.len()), 1);
+            let garbage = [0u8; 16];
+            assert_eq!(wi_img_set_icc_profile(img, garbage.as_ptr(), garbage.len()), 0);
+            assert_eq!(wi_last_error_code(), WiErrorCode::InvalidArgument);
+
+            for format in ["png", "jpeg"].iter() {
+                let format = CString::new(*format).unwrap();
+                let bytes = wi_img_encode(img, format.as_ptr());
+                let decoded = wi_img_decode_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes));
+                assert_eq!(wi_img_icc_profile_len(decoded), profile.len());
+                let data = std::slice::from_raw_parts(wi_img_icc_profile_data(decoded), profile.len());
+                assert_eq!(data, &profile[..]);
+                wi_img_free(decoded);
+                wi_bytes_free(bytes);
+            }
+
+            let converted = wi_img_convert_to_srgb(img, std::ptr::null());
+            assert!(wi_img_icc_profile_data(converted).is_null());
+            let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            wi_img_get_rgba_pixel(converted, &mut px, 1, 1);
+            // Linear 25% gray is a lot lighter in sRGB.
+            assert!(px.r > 120 && px.r == px.g && px.g == px.b, "{}", px.r);
+            wi_img_free(converted);
+            wi_img_free(img);
+        }
+    }
+
+    unsafe extern "C" fn halve_frame(frame: *const WiImage, _user_data: *mut c_void) -> *mut WiImage {
+        wi_img_thumbnail_exact(frame, 2, 2)
+    }
+
+    unsafe extern "C" fn same_frame(frame: *const WiImage, _user_data: *mut c_void) -> *mut WiImage {
+        frame as *mut WiImage
+    }
+
+    #[test]
+    fn animations_round_trip_through_gif_and_apng() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let animation = wi_animation_new(3);
+            for (delay, r) in [(100, 255), (250, 0)].iter() {
+                let frame = wi_new_rgba8_img(4, 4);
+                for (x, y) in (0..4).flat_map(|x| (0..4).map(move |y| (x, y))) {
+                    wi_img_set_rgba_pixel(frame, x, y, RgbaPixel { r: *r, g: 0, b: 255 - *r, a: 255 });
+                }
+                assert_eq!(wi_animation_push_frame(animation, frame, *delay), 1);
+                wi_img_free(frame);
+            }
+            let other_size = wi_new_rgba8_img(3, 3);
+            assert_eq!(wi_animation_push_frame(animation, other_size, 10), 0);
+            assert_eq!(wi_last_error_code(), WiErrorCode::DimensionMismatch);
+            wi_img_free(other_size);
+
+            let same = wi_animation_map_frames(animation, Some(same_frame), std::ptr::null_mut());
+            assert_eq!(wi_animation_is_err(same), 1);
+            assert_eq!(wi_animation_get_err_code(same), WiErrorCode::InvalidArgument);
+            wi_animation_free(same);
+
+            let halved = wi_animation_map_frames(animation, Some(halve_frame), std::ptr::null_mut());
+            assert_eq!(wi_animation_width(halved), 2);
+            for format in ["gif", "apng"].iter() {
+                let format = CString::new(*format).unwrap();
+                let bytes = wi_animation_encode(halved, format.as_ptr());
+                assert_eq!(wi_bytes_is_ok(bytes), 1);
+                let decoded = wi_animation_decode_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes));
+                assert_eq!(wi_animation_frame_count(decoded), 2);
+                assert_eq!((wi_animation_width(decoded), wi_animation_height(decoded)), (2, 2));
+                assert_eq!(wi_animation_loop_count(decoded), 3);
+                assert_eq!(wi_animation_frame_delay(decoded, 1), 250);
+                let frame = wi_animation_frame(decoded, 0);
+                let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+                wi_img_get_rgba_pixel(frame, &mut px, 1, 1);
+                assert!(px.r > 200 && px.b < 50);
+                wi_img_free(frame);
+                wi_animation_free(decoded);
+                wi_bytes_free(bytes);
+            }
+            wi_animation_free(halved);
+            wi_animation_free(animation);
+        }
+    }
+
+    #[test]
+    fn tiff_pages_are_written_and_read_one_by_one() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let color = wi_new_rgb8_img(3, 2);
+            wi_img_set_rgba_pixel(color, 2, 1, RgbaPixel { r: 10, g: 20, b: 30, a: 255 });
+            let gray = wi_img_to_luma(color);
+            let pages = [color as *const WiImage, color];
+            let lzw = CString::new("lzw").unwrap();
+            let bytes = wi_tiff_encode_pages(pages.as_ptr(), pages.len(), lzw.as_ptr());
+            assert_eq!(wi_tiff_page_count_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes)), 2);
+            let page = wi_tiff_decode_page_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes), 1);
+            let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            wi_img_get_rgba_pixel(page, &mut px, 2, 1);
+            assert_eq!((px.r, px.g, px.b), (10, 20, 30));
+            wi_img_free(page);
+            let page = wi_tiff_decode_page_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes), 2);
+            assert_eq!(wi_img_get_err_code(page), WiErrorCode::OutOfBounds);
+            wi_img_free(page);
+            let page = wi_tiff_decode_page_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes), -1);
+            assert_eq!(wi_img_get_err_code(page), WiErrorCode::OutOfBounds);
+            wi_img_free(page);
+
+            // Point the second directory back at itself.
+            let mut looped = std::slice::from_raw_parts(wi_bytes_data(bytes), wi_bytes_len(bytes)).to_vec();
+            let read_u32 = |buf: &[u8], at: usize| u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+            let next_offset = |buf: &[u8], ifd: usize| ifd + 2 + 12 * u16::from_le_bytes([buf[ifd], buf[ifd + 1]]) as usize;
+            let second = read_u32(&looped, next_offset(&looped, read_u32(&looped, 4) as usize));
+            let next = next_offset(&looped, second as usize);
+            looped[next..next + 4].copy_from_slice(&second.to_le_bytes());
+            assert_eq!(wi_tiff_page_count_from_memory(looped.as_ptr(), looped.len()), -1);
+            assert_eq!(wi_last_error_code(), WiErrorCode::DecodeFailed);
+            assert_eq!(wi_tiff_page_count_from_memory(b"GIF89a".as_ptr(), 6), -1);
+            assert_eq!(wi_last_error_code(), WiErrorCode::UnsupportedFormat);
+            wi_clear_last_error();
+            wi_bytes_free(bytes);
+
+            let gray_pages = [gray as *const WiGrayImage, gray, gray];
+            let packbits = CString::new("packbits").unwrap();
+            let bytes = wi_tiff_encode_grayimg_pages(gray_pages.as_ptr(), gray_pages.len(), packbits.as_ptr());
+            assert_eq!(wi_tiff_page_count_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes)), 3);
+            let page = wi_tiff_decode_page_from_memory(wi_bytes_data(bytes), wi_bytes_len(bytes), 2);
+            assert_eq!(wi_img_color_type(page), WiColorType::L8);
+            wi_img_free(page);
+            wi_bytes_free(bytes);
+
+            let unknown = CString::new("jbig").unwrap();
+            let bytes = wi_tiff_encode_pages(pages.as_ptr(), pages.len(), unknown.as_ptr());
+            assert_eq!(wi_bytes_get_err_code(bytes), WiErrorCode::InvalidArgument);
+            wi_bytes_free(bytes);
+            wi_grayimg_free(gray);
+            wi_img_free(color);
+        }
+    }
+
+    /// A TIFF entry: its tag, type (SHORT or LONG) and values.
+    type TiffEntry = (u16, u16, Vec<u32>);
+
+    /// Build a little-endian TIFF file, whose pages are given as their entries and strips.
+    fn tiff_file(pages: &[(Vec<TiffEntry>, Vec<Vec<u8>>)]) -> Vec<u8> {
+        let mut file = b"II*\0\0\0\0\0".to_vec();
+        let mut next = 4;
+        for (entries, strips) in pages {
+            let mut entries = entries.clone();
+            let mut offsets = Vec::new();
+            for strip in strips {
+                offsets.push(file.len() as u32);
+                file.extend_from_slice(strip);
+            }
+            entries.push((273, 4, offsets));
+            entries.push((279, 4, strips.iter().map(|x| x.len() as u32).collect()));
+            entries.sort_by_key(|x| x.0);
+            let mut fields = Vec::new();
+            for (tag, kind, values) in &entries {
+                let mut data = Vec::new();
+                for value in values {
+                    match kind {
+                        3 => data.extend_from_slice(&(*value as u16).to_le_bytes()),
+                        _ => data.extend_from_slice(&value.to_le_bytes()),
+                    }
+                }
+                if data.len() > 4 {
+                    let offset = file.len() as u32;
+                    file.extend_from_slice(&data);
+                    data = offset.to_le_bytes().to_vec();
+                }
+                data.resize(4, 0);
+                fields.push((*tag, *kind, values.len() as u32, data));
+            }
+            let ifd = file.len() as u32;
+            file[next..next + 4].copy_from_slice(&ifd.to_le_bytes());
+            file.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+            for (tag, kind, count, data) in fields {
+                file.extend_from_slice(&tag.to_le_bytes());
+                file.extend_from_slice(&kind.to_le_bytes());
+                file.extend_from_slice(&count.to_le_bytes());
+                file.extend_from_slice(&data);
+            }
+            next = file.len();
+            file.extend_from_slice(&[0; 4]);
+        }
+        file
+    }
+
+    #[test]
+    fn bilevel_and_palette_tiff_pages_are_unpacked() {
+        let _limits = default_limits_guard();
+        unsafe {
+            // 10x3 pixels in two strips, with rows padded to 2 bytes, and 1 meaning black.
+            let bilevel = vec![(256, 3, vec![10]), (257, 3, vec![3]), (258, 3, vec![1]), (262, 3, vec![0]), (278, 3, vec![2])];
+            let bilevel_strips = vec![vec![0xA0, 0x40, 0x00, 0x00], vec![0xFF, 0xC0]];
+            // 3x1 pixels of 2 bits, compressed with PackBits, indexing black, red and blue.
+            let mut colors = vec![0, 0xFFFF, 0, 0];
+            colors.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0xFFFF]);
+            let palette = vec![
+                (256, 3, vec![3]),
+                (257, 3, vec![1]),
+                (258, 3, vec![2]),
+                (259, 3, vec![32773]),
+                (262, 3, vec![3]),
+                (320, 3, colors),
+            ];
+            let mut fax = bilevel.clone();
+            fax.push((259, 3, vec![4]));
+            let mut lzw = bilevel.clone();
+            lzw.push((259, 3, vec![5]));
+            lzw[4].2 = vec![3];
+            let mut encoder = weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8);
+            let lzw_strip = encoder.encode(&bilevel_strips.concat()).unwrap();
+            let file = tiff_file(&[
+                (bilevel, bilevel_strips.clone()),
+                (palette, vec![vec![0x00, 0x1C]]),
+                (fax, bilevel_strips),
+                (lzw, vec![lzw_strip]),
+            ]);
+            assert_eq!(wi_tiff_page_count_from_memory(file.as_ptr(), file.len()), 4);
+
+            for index in [0, 3].iter() {
+                let page = wi_tiff_decode_page_from_memory(file.as_ptr(), file.len(), *index);
+                assert_eq!(wi_img_color_type(page), WiColorType::L8);
+                assert_eq!((wi_img_width(page), wi_img_height(page)), (10, 3));
+                let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+                let mut gray = |x, y| {
+                    wi_img_get_rgba_pixel(page, &mut px, x, y);
+                    px.r
+                };
+                assert_eq!([gray(0, 0), gray(1, 0), gray(2, 0), gray(8, 0), gray(9, 0)], [0, 255, 0, 255, 0]);
+                assert_eq!([gray(0, 1), gray(9, 1), gray(0, 2), gray(9, 2)], [255, 255, 0, 0]);
+                wi_img_free(page);
+            }
+
+            let page = wi_tiff_decode_page_from_memory(file.as_ptr(), file.len(), 1);
+            assert_eq!(wi_img_color_type(page), WiColorType::Rgb8);
+            let mut px = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+            let mut rgb = |x| {
+                wi_img_get_rgba_pixel(page, &mut px, x, 0);
+                (px.r, px.g, px.b)
+            };
+            assert_eq!([rgb(0), rgb(1), rgb(2)], [(0, 0, 0), (255, 0, 0), (0, 0, 255)]);
+            wi_img_free(page);
+
+            let page = wi_tiff_decode_page_from_memory(file.as_ptr(), file.len(), 2);
+            assert_eq!(wi_img_get_err_code(page), WiErrorCode::UnsupportedFormat);
+            wi_img_free(page);
+        }
+    }
+
+    #[test]
+    fn decode_limits_are_checked_before_decoding() {
+        let _limits = DEFAULT_LIMITS.write().unwrap_or_else(|x| x.into_inner());
+        unsafe {
+            let img = wi_new_luma8_img(6000, 2);
+            let png = CString::new("png").unwrap();
+            let bytes = wi_img_encode(img, png.as_ptr());
+            let (data, len) = (wi_bytes_data(bytes), wi_bytes_len(bytes));
+
+            let limits = wi_decode_limits_new();
+            wi_decode_limits_set_max_pixels(limits, 10_000);
+            let decoded = wi_img_decode_from_memory_with_limits(data, len, std::ptr::null(), limits);
+            assert_eq!(wi_img_get_err_code(decoded), WiErrorCode::LimitExceeded);
+            wi_img_free(decoded);
+            wi_decode_limits_set_max_pixels(limits, 0);
+            wi_decode_limits_set_max_alloc(limits, 1_000);
+            let decoded = wi_img_decode_from_memory_with_limits(data, len, std::ptr::null(), limits);
+            assert_eq!(wi_img_get_err_code(decoded), WiErrorCode::LimitExceeded);
+            wi_img_free(decoded);
+            wi_decode_limits_free(limits);
+
+            let limits = wi_decode_limits_new();
+            wi_decode_limits_set_max_pixels(limits, 10_000);
+            let decoded = wi_img_decode_from_memory_oriented_with_limits(data, len, std::ptr::null(), limits);
+            assert_eq!(wi_img_get_err_code(decoded), WiErrorCode::LimitExceeded);
+            wi_img_free(decoded);
+            let animation = wi_animation_decode_from_memory_with_limits(data, len, limits);
+            assert_eq!(wi_animation_get_err_code(animation), WiErrorCode::LimitExceeded);
+            wi_animation_free(animation);
+            let pages = [img as *const WiImage];
+            let tiff = wi_tiff_encode_pages(pages.as_ptr(), 1, std::ptr::null());
+            let (tiff_data, tiff_len) = (wi_bytes_data(tiff), wi_bytes_len(tiff));
+            assert_eq!(wi_tiff_page_count_from_memory_with_limits(tiff_data, tiff_len, limits), 1);
+            let page = wi_tiff_decode_page_from_memory_with_limits(tiff_data, tiff_len, 0, limits);
+            assert_eq!(wi_img_get_err_code(page), WiErrorCode::LimitExceeded);
+            wi_img_free(page);
+            wi_bytes_free(tiff);
+
+            // Profiles larger than the allocation limit are dropped.
+            let small = wi_new_rgb8_img(3, 2);
+            let profile = gamma_icc_profile(1.0);
+            assert_eq!(wi_img_set_icc_profile(small, profile.as_ptr(), profile.len()), 1);
+            let encoded = wi_img_encode(small, png.as_ptr());
+            wi_decode_limits_set_max_pixels(limits, 0);
+            wi_decode_limits_set_max_alloc(limits, profile.len() as u64 - 1);
+            let decoded =
+                wi_img_decode_from_memory_with_limits(wi_bytes_data(encoded), wi_bytes_len(encoded), std::ptr::null(), limits);
+            assert_eq!(wi_img_width(decoded), 3);
+            assert_eq!(wi_img_icc_profile_len(decoded), 0);
+            wi_img_free(decoded);
+            let decoded = wi_img_decode_from_memory(wi_bytes_data(encoded), wi_bytes_len(encoded));
+            assert_eq!(wi_img_icc_profile_len(decoded), profile.len());
+            wi_img_free(decoded);
+            wi_bytes_free(encoded);
+            wi_img_free(small);
+            wi_decode_limits_free(limits);
+
+            let limits = wi_decode_limits_new();
+            wi_decode_limits_set_max_width(limits, 5_000);
+            wi_set_default_decode_limits(limits);
+            let decoded = wi_img_decode_from_memory(data, len);
+            assert_eq!(wi_img_get_err_code(decoded), WiErrorCode::LimitExceeded);
+            wi_img_free(decoded);
+            wi_set_default_decode_limits(std::ptr::null());
+            wi_decode_limits_free(limits);
+
+            let decoded = wi_img_decode_from_memory(data, len);
+            assert_eq!(wi_img_width(decoded), 6000);
+            wi_img_free(decoded);
+            wi_bytes_free(bytes);
+            wi_img_free(img);
+        }
+    }
+
+    unsafe fn gradient_img(width: u32, height: u32) -> *mut WiImage {
+        let img = wi_new_rgb8_img(width, height);
+        for x in 0..width {
+            for y in 0..height {
+                let px = RgbaPixel { r: (x * 255 / width) as u8, g: (y * 255 / height) as u8, b: 128, a: 255 };
+                wi_img_set_rgba_pixel(img, x, y, px);
+            }
+        }
+        img
+    }
+
+    /// The mean absolute difference of the RGB channels of two images of the same size.
+    unsafe fn mean_difference(a: *const WiImage, b: *const WiImage) -> f64 {
+        assert_eq!((wi_img_width(a), wi_img_height(a)), (wi_img_width(b), wi_img_height(b)));
+        let mut total = 0;
+        for x in 0..wi_img_width(a) as u32 {
+            for y in 0..wi_img_height(a) as u32 {
+                let mut pa = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+                let mut pb = RgbaPixel { r: 0, g: 0, b: 0, a: 0 };
+                wi_img_get_rgba_pixel(a, &mut pa, x, y);
+                wi_img_get_rgba_pixel(b, &mut pb, x, y);
+                for (ca, cb) in [(pa.r, pb.r), (pa.g, pb.g), (pa.b, pb.b)].iter() {
+                    total += (*ca as i32 - *cb as i32).abs();
+                }
+            }
+        }
+        total as f64 / (3 * wi_img_width(a) * wi_img_height(a)) as f64
+    }
+
+    #[test]
+    fn encoded_bytes_round_trip_through_caller_owned_memory() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let img = gradient_img(8, 6);
+            let png = CString::new("png").unwrap();
+            let bytes = wi_img_encode(img, png.as_ptr());
+            assert_eq!(wi_bytes_is_ok(bytes), 1);
+            let mut small = vec![0u8; wi_bytes_len(bytes) - 1];
+            assert_eq!(wi_bytes_copy(bytes, small.as_mut_ptr(), small.len()), -1);
+            assert_eq!(wi_last_error_code(), WiErrorCode::OutOfBounds);
+            let mut owned = vec![0u8; wi_bytes_len(bytes)];
+            assert_eq!(wi_bytes_copy(bytes, owned.as_mut_ptr(), owned.len()), owned.len() as isize);
+            wi_bytes_free(bytes);
+
+            let decoded = wi_img_decode_from_memory(owned.as_ptr(), owned.len());
+            assert_eq!(mean_difference(img, decoded), 0.0);
+            wi_img_free(decoded);
+            wi_img_free(img);
+        }
+    }
+
+    unsafe extern "C" fn cursor_read(user_data: *mut c_void, buf: *mut u8, len: size_t) -> isize {
+        use std::io::Read;
+        let cursor = &mut *(user_data as *mut std::io::Cursor<Vec<u8>>);
+        cursor.read(std::slice::from_raw_parts_mut(buf, len)).map_or(-1, |x| x as isize)
+    }
+
+    unsafe extern "C" fn cursor_seek(user_data: *mut c_void, offset: i64, whence: c_int) -> i64 {
+        use std::io::{Seek, SeekFrom};
+        let cursor = &mut *(user_data as *mut std::io::Cursor<Vec<u8>>);
+        let pos = match whence {
+            0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            _ => SeekFrom::End(offset),
+        };
+        cursor.seek(pos).map_or(-1, |x| x as i64)
+    }
+
+    unsafe extern "C" fn cursor_write(user_data: *mut c_void, buf: *const u8, len: size_t) -> isize {
+        use std::io::Write;
+        let cursor = &mut *(user_data as *mut std::io::Cursor<Vec<u8>>);
+        cursor.write(std::slice::from_raw_parts(buf, len)).map_or(-1, |x| x as isize)
+    }
+
+    #[test]
+    fn callbacks_round_trip_with_and_without_seek() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let img = gradient_img(8, 6);
+            let mut cursor = std::io::Cursor::new(Vec::new());
+            let mut io = WiIoCallbacks {
+                user_data: &mut cursor as *mut std::io::Cursor<Vec<u8>> as *mut c_void,
+                read: Some(cursor_read),
+                seek: Some(cursor_seek),
+                write: Some(cursor_write),
+            };
+            let png = CString::new("png").unwrap();
+            assert_eq!(wi_img_encode_to_callbacks(img, &io, png.as_ptr()), 1);
+            assert!(!cursor.get_ref().is_empty());
+
+            cursor.set_position(0);
+            let decoded = wi_img_decode_from_callbacks(&io, std::ptr::null());
+            assert_eq!(mean_difference(img, decoded), 0.0);
+            wi_img_free(decoded);
+
+            cursor.set_position(0);
+            io.seek = None;
+            let decoded = wi_img_decode_from_callbacks(&io, png.as_ptr());
+            assert_eq!(mean_difference(img, decoded), 0.0);
+            wi_img_free(decoded);
+
+            io.read = None;
+            let decoded = wi_img_decode_from_callbacks(&io, png.as_ptr());
+            assert_eq!(wi_img_is_err(decoded), 1);
+            wi_img_free(decoded);
+            wi_img_free(img);
+        }
+    }
+
+    /// Record the length of every write, without keeping the data.
+    unsafe extern "C" fn count_write(user_data: *mut c_void, _buf: *const u8, len: size_t) -> isize {
+        (&mut *(user_data as *mut Vec<usize>)).push(len);
+        len as isize
+    }
+
+    #[test]
+    fn callbacks_are_encoded_with_options_as_they_are_produced() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let img = gradient_img(128, 128);
+            let mut writes = Vec::<usize>::new();
+            let io = WiIoCallbacks {
+                user_data: &mut writes as *mut Vec<usize> as *mut c_void,
+                read: None,
+                seek: None,
+                write: Some(count_write),
+            };
+            let bmp = CString::new("bmp").unwrap();
+            assert_eq!(wi_img_encode_to_callbacks(img, &io, bmp.as_ptr()), 1);
+            assert!(writes.len() > 1);
+            assert!(writes.iter().sum::<usize>() > 128 * 128 * 3);
+
+            let mut cursor = std::io::Cursor::new(Vec::new());
+            let mut io = WiIoCallbacks {
+                user_data: &mut cursor as *mut std::io::Cursor<Vec<u8>> as *mut c_void,
+                read: None,
+                seek: None,
+                write: Some(cursor_write),
+            };
+            let jpeg = CString::new("jpeg").unwrap();
+            let options = wi_encode_options_new();
+            let mut sizes = Vec::new();
+            for quality in [10, 95].iter() {
+                wi_encode_options_set_jpeg_quality(options, *quality);
+                cursor = std::io::Cursor::new(Vec::new());
+                io.user_data = &mut cursor as *mut std::io::Cursor<Vec<u8>> as *mut c_void;
+                assert_eq!(wi_img_encode_to_callbacks_with_options(img, &io, jpeg.as_ptr(), options), 1);
+                let bytes = wi_img_encode_with_options(img, jpeg.as_ptr(), options);
+                assert_eq!(cursor.get_ref().as_slice(), std::slice::from_raw_parts(wi_bytes_data(bytes), wi_bytes_len(bytes)));
+                wi_bytes_free(bytes);
+                sizes.push(cursor.get_ref().len());
+            }
+            assert!(sizes[0] < sizes[1]);
+            wi_encode_options_free(options);
+
+            // TIFF is encoded in memory first, as its encoder seeks.
+            let tiff = CString::new("tiff").unwrap();
+            cursor = std::io::Cursor::new(Vec::new());
+            io.user_data = &mut cursor as *mut std::io::Cursor<Vec<u8>> as *mut c_void;
+            assert_eq!(wi_img_encode_to_callbacks(img, &io, tiff.as_ptr()), 1);
+            let decoded = wi_img_decode_from_memory(cursor.get_ref().as_ptr(), cursor.get_ref().len());
+            assert_eq!(mean_difference(img, decoded), 0.0);
+            wi_img_free(decoded);
+
+            // So are JPEG files with metadata.
+            let oriented = jpeg_with_orientation(6);
+            let source = wi_img_decode_from_memory(oriented.as_ptr(), oriented.len());
+            cursor = std::io::Cursor::new(Vec::new());
+            io.user_data = &mut cursor as *mut std::io::Cursor<Vec<u8>> as *mut c_void;
+            assert_eq!(wi_img_encode_to_callbacks(source, &io, jpeg.as_ptr()), 1);
+            let decoded = wi_img_decode_from_memory(cursor.get_ref().as_ptr(), cursor.get_ref().len());
+            assert_eq!(wi_img_exif_orientation(decoded), 6);
+            wi_img_free(decoded);
+            wi_img_free(source);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn seekable_callbacks_read_metadata_from_the_header() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let jpeg = jpeg_with_orientation(6);
+            // The image starts partway into the stream, as in an archive.
+            let mut cursor = std::io::Cursor::new([b"archive header".to_vec(), jpeg.clone()].concat());
+            cursor.set_position(14);
+            let io = WiIoCallbacks {
+                user_data: &mut cursor as *mut std::io::Cursor<Vec<u8>> as *mut c_void,
+                read: Some(cursor_read),
+                seek: Some(cursor_seek),
+                write: None,
+            };
+            let decoded = wi_img_decode_from_callbacks(&io, std::ptr::null());
+            assert_eq!(wi_img_exif_orientation(decoded), 6);
+            wi_img_free(decoded);
+
+            cursor.set_position(20);
+            let prefix = metadata_prefix(&mut cursor, 14).unwrap();
+            assert_eq!(cursor.position(), 20);
+            assert!(prefix.len() < jpeg.len());
+            assert_eq!(ImageMetadata::read(&prefix, &WiDecodeLimits::default()).orientation(), Some(6));
+
+            let mut png = Vec::new();
+            DynamicImage::new_rgb8(3, 2)
+                .write_to(&mut std::io::Cursor::new(&mut png), image::ImageOutputFormat::Png)
+                .unwrap();
+            let prefix = metadata_prefix(&mut std::io::Cursor::new(&png), 0).unwrap();
+            assert!(prefix.len() < png.len());
+            assert_eq!(&prefix[prefix.len() - 4..], b"IDAT");
+            assert!(metadata_prefix(&mut std::io::Cursor::new(b"GIF89a"), 0).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn webp_round_trips_lossy_and_lossless() {
+        let _limits = default_limits_guard();
+        unsafe {
+            let img = gradient_img(64, 48);
+            let webp = CString::new("webp").unwrap();
+            let options = wi_encode_options_new();
+            assert_eq!(wi_encode_options_set_webp_quality(options, 101), 0);
+            assert_eq!(wi_encode_options_set_webp_quality(options, 90), 1);
+            let lossy = wi_img_encode_with_options(img, webp.as_ptr(), options);
+            if cfg!(feature = "webp") {
+                let decoded = wi_img_decode_from_memory(wi_bytes_data(lossy), wi_bytes_len(lossy));
+                assert!(mean_difference(img, decoded) < 4.0);
+                wi_img_free(decoded);
+            } else {
+                assert_eq!(wi_bytes_get_err_code(lossy), WiErrorCode::UnsupportedFormat);
+            }
+
+            wi_encode_options_set_webp_lossless(options, 1);
+            let lossless = wi_img_encode_with_options(img, webp.as_ptr(), options);
+            let data = std::slice::from_raw_parts(wi_bytes_data(lossless), wi_bytes_len(lossless));
+            assert_eq!(&data[12..16], b"VP8L");
+            let decoded = wi_img_decode_from_memory(data.as_ptr(), data.len());
+            assert_eq!(mean_difference(img, decoded), 0.0);
+            wi_img_free(decoded);
+            wi_bytes_free(lossless);
+            wi_bytes_free(lossy);
+            wi_encode_options_free(options);
+            wi_img_free(img);
+        }
+    }
+
+    #[test]
+    fn avif_output_follows_quality_and_speed() {
+        unsafe {
+            let img = gradient_img(64, 48);
+            let avif = CString::new("avif").unwrap();
+            let options = wi_encode_options_new();
+            assert_eq!(wi_encode_options_set_avif_speed(options, 0), 0);
+            assert_eq!(wi_encode_options_set_avif_quality(options, 0), 0);
+            assert_eq!(wi_encode_options_set_avif_speed(options, 10), 1);
+            let mut sizes = Vec::new();
+            for quality in [20, 95].iter() {
+                wi_encode_options_set_avif_quality(options, *quality);
+                let bytes = wi_img_encode_with_options(img, avif.as_ptr(), options);
+                let data = std::slice::from_raw_parts(wi_bytes_data(bytes), wi_bytes_len(bytes));
+                assert_eq!(&data[4..12], b"ftypavif");
+                // The image spatial extents property holds the encoded size.
+                let ispe = data.windows(4).position(|x| x == b"ispe").unwrap();
+                let size = |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+                assert_eq!((size(ispe + 8), size(ispe + 12)), (64, 48));
+                sizes.push(data.len());
+                wi_bytes_free(bytes);
+            }
+            assert!(sizes[0] < sizes[1], "{:?}", sizes);
+            wi_encode_options_free(options);
+            wi_img_free(img);
+        }
+    }
+}